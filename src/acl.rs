@@ -0,0 +1,95 @@
+use std::net::IpAddr;
+
+use crate::cidr::Cidr;
+
+/// Whether the clients of a block are served.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AclAction {
+    Allow,
+    Deny,
+}
+
+/// Decides which clients are served, by address block. The most specific block a client belongs
+/// to wins, so that a network can be allowed save for a few of its hosts, or the other way
+/// around. Clients belonging to no block are served only if no block is explicitly allowed.
+#[derive(Clone, Debug, Default)]
+pub struct Acl {
+    rules: Vec<(Cidr, AclAction)>,
+}
+
+impl Acl {
+    pub fn allow(&mut self, cidr: Cidr) {
+        self.rules.push((cidr, AclAction::Allow));
+    }
+
+    pub fn deny(&mut self, cidr: Cidr) {
+        self.rules.push((cidr, AclAction::Deny));
+    }
+
+    pub fn allows(&self, client: IpAddr) -> bool {
+        let rule = self
+            .rules
+            .iter()
+            .filter(|(cidr, _)| cidr.contains(client))
+            .max_by_key(|(cidr, _)| cidr.prefix_len());
+
+        match rule {
+            Some((_, action)) => *action == AclAction::Allow,
+            None => !self
+                .rules
+                .iter()
+                .any(|(_, action)| *action == AclAction::Allow),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn acl(allowed: &[&str], denied: &[&str]) -> Acl {
+        let mut acl = Acl::default();
+        for cidr in allowed {
+            acl.allow(cidr.parse().unwrap());
+        }
+        for cidr in denied {
+            acl.deny(cidr.parse().unwrap());
+        }
+        acl
+    }
+
+    fn allows(acl: &Acl, client: &str) -> bool {
+        acl.allows(client.parse().unwrap())
+    }
+
+    #[test]
+    fn applies_the_most_specific_block_whatever_the_order() {
+        let networks = acl(
+            &["192.168.0.0/16", "10.0.0.5"],
+            &["192.168.1.0/24", "10.0.0.0/8"],
+        );
+        let hosts = acl(&["192.168.1.7"], &["192.168.1.0/24"]);
+
+        assert!(allows(&networks, "192.168.2.1"));
+        assert!(!allows(&networks, "192.168.1.7"));
+        assert!(allows(&networks, "10.0.0.5"));
+        assert!(!allows(&networks, "10.0.0.6"));
+        assert!(allows(&hosts, "192.168.1.7"));
+        assert!(!allows(&hosts, "192.168.1.8"));
+    }
+
+    #[test]
+    fn serves_unlisted_clients_unless_some_are_allowed() {
+        let denying = acl(&[], &["192.168.1.0/24"]);
+        let allowing = acl(&["192.168.0.0/16"], &["192.168.1.0/24"]);
+
+        assert!(allows(&Acl::default(), "203.0.113.1"));
+        assert!(allows(&denying, "203.0.113.1"));
+        assert!(!allows(&denying, "192.168.1.1"));
+        assert!(!allows(&allowing, "203.0.113.1"));
+        assert!(!allows(&allowing, "2001:db8::1"));
+        // IPv4 clients reaching a dual-stack socket are mapped to IPv6 addresses
+        assert!(allows(&allowing, "::ffff:192.168.2.1"));
+        assert!(!allows(&allowing, "::ffff:192.168.1.1"));
+    }
+}
@@ -0,0 +1,222 @@
+use std::net::IpAddr;
+use std::time::Duration;
+
+use http::{Method, StatusCode};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::blocklist::CustomLists;
+use crate::globals::{MAX_PAUSE_DURATION, RECENT_QUERIES, STATS_TOP_COUNT, STATS_WINDOW};
+use crate::server::Server;
+
+/// A response of the API: a status, and a JSON body.
+pub type Reply = (StatusCode, Value);
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DomainBody {
+    domain: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BlockingBody {
+    enabled: bool,
+    /// The client to enable or disable blocking for, rather than every client
+    client: Option<IpAddr>,
+    /// Seconds after which blocking is enabled again, when disabling it
+    duration: Option<u64>,
+}
+
+fn error(status: StatusCode, message: &str) -> Reply {
+    (status, json!({ "error": message }))
+}
+
+/// Returns the value of the `name` parameter of the query string `query`.
+fn param<'a>(query: Option<&'a str>, name: &str) -> Option<&'a str> {
+    query?.split('&').find_map(|param| {
+        let (key, value) = param.split_once('=')?;
+        (key == name).then_some(value)
+    })
+}
+
+/// Parses the `name` parameter, `default` standing in when it's missing.
+fn parse_param<T: std::str::FromStr>(
+    query: Option<&str>,
+    name: &str,
+    default: T,
+) -> Result<T, Reply> {
+    match param(query, name) {
+        Some(value) => value.parse().map_err(|_| {
+            error(
+                StatusCode::BAD_REQUEST,
+                &format!("invalid {name} parameter"),
+            )
+        }),
+        None => Ok(default),
+    }
+}
+
+fn parse_body<'a, T: Deserialize<'a>>(body: &'a [u8]) -> Result<T, Reply> {
+    serde_json::from_slice(body).map_err(|e| error(StatusCode::BAD_REQUEST, &e.to_string()))
+}
+
+/// Whether `domain` looks like a domain name, wildcards aside.
+fn valid_domain(domain: &str) -> bool {
+    let domain = domain.trim_end_matches('.');
+    !domain.is_empty()
+        && domain.len() <= 253
+        && domain.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && label
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+        })
+}
+
+/// Handles a request to the management API, `path` being relative to `/api`.
+///
+/// - `GET /stats?window=<seconds>&top=<n>`: the statistics, with the top `n` domains and clients
+///   over the last `window`
+/// - `GET /queries?limit=<n>&client=<ip>`: the latest queries, the most recent first
+/// - `GET /blocklist`, `POST /blocklist` with `{"domain": ...}` and `DELETE /blocklist/<domain>`:
+///   the domains blocked through the API, on top of the blocklists of the configuration
+/// - `GET /allowlist`, `POST /allowlist` and `DELETE /allowlist/<domain>`: likewise for the
+///   domains never to block
+/// - `GET /blocking`: whether blocking is enabled, for every client and for single clients
+/// - `PUT /blocking` with `{"enabled": ...}`, and optionally `"client"` and, when disabling,
+///   `"duration"` in seconds: enables or disables blocking, for every client or a single one, for
+///   good or until `duration` is over
+/// - `POST /cache/flush`: empties the cache
+/// - `GET /cache/<name>`: the responses cached for `name`
+pub fn handle(
+    server: &Server,
+    method: &Method,
+    path: &str,
+    query: Option<&str>,
+    body: &[u8],
+) -> Reply {
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+
+    let reply = match (method, segments.as_slice()) {
+        (&Method::GET, ["stats"]) => stats(server, query),
+        (&Method::GET, ["queries"]) => queries(server, query),
+        (&Method::GET, [list @ ("blocklist" | "allowlist")]) => {
+            let custom_lists = server.custom_lists();
+            let domains = match *list {
+                "blocklist" => custom_lists.blocked,
+                _ => custom_lists.allowed,
+            };
+            Ok((StatusCode::OK, json!({ "domains": domains })))
+        }
+        (&Method::POST, [list @ ("blocklist" | "allowlist")]) => parse_body::<DomainBody>(body)
+            .and_then(|body| {
+                let add = match *list {
+                    "blocklist" => CustomLists::block,
+                    _ => CustomLists::allow,
+                };
+                let status = match edit_list(server, &body.domain, add)? {
+                    true => StatusCode::CREATED,
+                    false => StatusCode::OK,
+                };
+                Ok((status, json!({ "domain": body.domain })))
+            }),
+        (&Method::DELETE, [list @ ("blocklist" | "allowlist"), domain]) => {
+            let remove = match *list {
+                "blocklist" => CustomLists::unblock,
+                _ => CustomLists::unallow,
+            };
+            edit_list(server, domain, remove).and_then(|removed| match removed {
+                true => Ok((StatusCode::OK, json!({ "domain": domain }))),
+                false => Err(error(StatusCode::NOT_FOUND, "domain not listed")),
+            })
+        }
+        (&Method::GET, ["blocking"]) => Ok((StatusCode::OK, json!(server.blocking_status()))),
+        (&Method::PUT, ["blocking"]) => {
+            parse_body::<BlockingBody>(body).and_then(|body| set_blocking(server, body))
+        }
+        (&Method::POST, ["cache", "flush"]) => {
+            Ok((StatusCode::OK, json!({ "flushed": server.flush_cache() })))
+        }
+        (&Method::GET, ["cache", name]) => Ok((
+            StatusCode::OK,
+            json!({ "name": name, "entries": server.cached(name) }),
+        )),
+        (
+            _,
+            ["stats" | "queries" | "blocklist" | "allowlist" | "blocking" | "cache"]
+            | ["blocklist" | "allowlist" | "cache", _],
+        ) => Err(error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed")),
+        _ => Err(error(StatusCode::NOT_FOUND, "not found")),
+    };
+
+    reply.unwrap_or_else(|reply| reply)
+}
+
+fn stats(server: &Server, query: Option<&str>) -> Result<Reply, Reply> {
+    let window = parse_param(query, "window", STATS_WINDOW.as_secs())?;
+    let top = parse_param(query, "top", STATS_TOP_COUNT)?;
+
+    let report = server.report(Duration::from_secs(window), top);
+    Ok((StatusCode::OK, json!(report)))
+}
+
+fn set_blocking(server: &Server, body: BlockingBody) -> Result<Reply, Reply> {
+    match (body.enabled, body.duration) {
+        (true, Some(_)) => {
+            return Err(error(
+                StatusCode::BAD_REQUEST,
+                "duration only applies to disabling",
+            ))
+        }
+        (true, None) => server.enable_blocking(body.client),
+        (false, Some(duration)) if duration == 0 || duration > MAX_PAUSE_DURATION.as_secs() => {
+            return Err(error(
+                StatusCode::BAD_REQUEST,
+                &format!(
+                    "invalid duration, expected 1 to {} seconds",
+                    MAX_PAUSE_DURATION.as_secs()
+                ),
+            ))
+        }
+        (false, duration) => {
+            server.disable_blocking(body.client, duration.map(Duration::from_secs))
+        }
+    }
+
+    Ok((StatusCode::OK, json!(server.blocking_status())))
+}
+
+fn queries(server: &Server, query: Option<&str>) -> Result<Reply, Reply> {
+    let limit = parse_param(query, "limit", 100)?.min(RECENT_QUERIES);
+    let client = match param(query, "client") {
+        Some(client) => Some(
+            client
+                .parse::<IpAddr>()
+                .map_err(|_| error(StatusCode::BAD_REQUEST, "invalid client parameter"))?,
+        ),
+        None => None,
+    };
+
+    Ok((
+        StatusCode::OK,
+        json!({ "queries": server.recent_queries(limit, client) }),
+    ))
+}
+
+/// Adds `domain` to, or removes it from, a custom list with `edit`, returning whether the list
+/// changed.
+fn edit_list(
+    server: &Server,
+    domain: &str,
+    edit: fn(&mut CustomLists, &str) -> bool,
+) -> Result<bool, Reply> {
+    if !valid_domain(domain) {
+        return Err(error(StatusCode::BAD_REQUEST, "invalid domain"));
+    }
+
+    server
+        .edit_custom_lists(|custom_lists| edit(custom_lists, domain))
+        .map_err(|e| error(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()))
+}
@@ -0,0 +1,324 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::io::ErrorKind;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use chrono::NaiveDateTime;
+use ring::digest::{digest, SHA256};
+use serde::{Deserialize, Serialize};
+
+use crate::globals::BLOCKED_ANSWER_TTL;
+use crate::index::{DomainIndex, Stamp};
+use crate::log;
+use crate::packet::Packet;
+use crate::record::{Record, RecordPreamble, RecordType};
+use crate::result::{Error, Result, ResultCode};
+use crate::schedule::ScheduledBlocklist;
+
+/// How blocked queries are answered.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BlockingMode {
+    /// `A` and `AAAA` queries get the unspecified address (`0.0.0.0` and `::`), which clients
+    /// fail connecting to right away, and other types get an empty response
+    #[default]
+    Null,
+    /// Every query gets `NXDOMAIN`, as if the domain didn't exist
+    NxDomain,
+}
+
+/// Normalizes `domain` the way lists store them: lowercase, without the trailing dot.
+fn normalize(domain: &str) -> String {
+    domain.trim_end_matches('.').to_lowercase()
+}
+
+/// Returns the listed domain `qname` falls under, if any: `qname` itself or one of its parents,
+/// `get` looking a domain up in the list.
+fn listed<'a>(qname: &str, get: impl Fn(&str) -> Option<&'a String>) -> Option<&'a str> {
+    let qname = normalize(qname);
+
+    let mut name = qname.as_str();
+    loop {
+        if let Some(domain) = get(name) {
+            return Some(domain);
+        }
+        name = name.split_once('.')?.1;
+    }
+}
+
+/// Reads the domains listed in the file at `path`, normalized. Both plain lists (one domain per
+/// line) and hosts files (`0.0.0.0 domain`) are understood, `#` starting a comment.
+fn read_domains(path: &str) -> Result<Vec<String>> {
+    let content =
+        fs::read_to_string(path).map_err(|e| Error::InvalidBlocklist(format!("{path}: {e}")))?;
+
+    let mut domains = Vec::new();
+    for line in content.lines() {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut fields = line.split_whitespace();
+        let domain = match (fields.next(), fields.next()) {
+            (Some(_), Some(domain)) => domain,
+            (Some(domain), None) => domain,
+            _ => continue,
+        };
+        // Hosts files map the local host names too, which must keep resolving
+        if matches!(
+            domain,
+            "localhost" | "localhost.localdomain" | "local" | "broadcasthost"
+        ) {
+            continue;
+        }
+
+        domains.push(normalize(domain));
+    }
+
+    Ok(domains)
+}
+
+/// Loads the lists of the blocklists into indices, reading each file once however many
+/// blocklists use it. With an index directory, each list is compiled once and for all, its index
+/// being read instead of it for as long as the file doesn't change.
+pub struct ListLoader<'a> {
+    index_dir: Option<&'a str>,
+    loaded: HashMap<String, Arc<DomainIndex>>,
+}
+
+impl<'a> ListLoader<'a> {
+    pub fn new(index_dir: Option<&'a str>) -> Self {
+        Self {
+            index_dir,
+            loaded: HashMap::new(),
+        }
+    }
+
+    /// Where the index of the list at `path` is kept, if anywhere. Lists of the same name in
+    /// different directories get their own index, told apart by a hash of their path that must
+    /// not change from one build to the next.
+    fn index_path(&self, path: &str) -> Option<PathBuf> {
+        let dir = self.index_dir?;
+        let name = Path::new(path).file_name()?.to_string_lossy();
+        let hash: String = digest(&SHA256, path.as_bytes()).as_ref()[..8]
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+
+        Some(Path::new(dir).join(format!("{name}.{hash}.idx")))
+    }
+
+    /// Returns the index of the domains listed in the file at `path`, see `read_domains`.
+    pub fn load(&mut self, path: &str) -> Result<Arc<DomainIndex>> {
+        if let Some(index) = self.loaded.get(path) {
+            return Ok(Arc::clone(index));
+        }
+
+        let stamp = Stamp::of(path);
+        let index_path = self.index_path(path);
+        let compiled = index_path
+            .as_deref()
+            .zip(stamp.as_ref())
+            .and_then(|(index_path, stamp)| DomainIndex::load(index_path, stamp));
+
+        let index = match compiled {
+            Some(index) => index,
+            None => {
+                let domains = read_domains(path)?;
+                let index = DomainIndex::build(domains.iter().map(String::as_str));
+                if let Some((index_path, stamp)) = index_path.as_deref().zip(stamp.as_ref()) {
+                    // Without its index, the list is read again next time, which is only slower
+                    let saved = fs::create_dir_all(self.index_dir.unwrap_or_default())
+                        .and_then(|_| index.save(index_path, stamp));
+                    if let Err(e) = saved {
+                        log::warning!("Cannot save the index of {}: {}", path, e);
+                    }
+                }
+                index
+            }
+        };
+
+        let index = Arc::new(index);
+        self.loaded.insert(path.to_owned(), Arc::clone(&index));
+        Ok(index)
+    }
+}
+
+/// The domains whose queries are answered by ourselves rather than resolved, to keep clients
+/// away from ads, trackers or malware. Blocking a domain blocks its subdomains as well, unless
+/// they are allowed: allowed domains, and their subdomains, are never blocked.
+///
+/// Lists are kept as the indices `ListLoader` compiles them into, shared by the blocklists
+/// using the same files.
+#[derive(Default)]
+pub struct Blocklist {
+    lists: Vec<Arc<DomainIndex>>,
+    allowlists: Vec<Arc<DomainIndex>>,
+    mode: BlockingMode,
+    /// Blocklists blocking on top of this one while their schedule is active
+    scheduled: Vec<ScheduledBlocklist>,
+}
+
+impl Blocklist {
+    pub fn set_mode(&mut self, mode: BlockingMode) {
+        self.mode = mode;
+    }
+
+    /// Number of domains listed, counting the domains of several lists once per list.
+    pub fn len(&self) -> usize {
+        self.lists.iter().map(|list| list.len()).sum()
+    }
+
+    /// Blocks every domain of `list`.
+    pub fn add_list(&mut self, list: Arc<DomainIndex>) {
+        self.lists.push(list);
+    }
+
+    /// Allows every domain of `list`.
+    pub fn add_allowlist(&mut self, list: Arc<DomainIndex>) {
+        self.allowlists.push(list);
+    }
+
+    /// Blocks the domains of `scheduled` as well, while its schedule is active.
+    pub fn add_scheduled(&mut self, scheduled: ScheduledBlocklist) {
+        self.scheduled.push(scheduled);
+    }
+
+    /// Whether `qname` falls under an allowed domain.
+    pub fn allows(&self, qname: &str) -> bool {
+        let qname = normalize(qname);
+        self.allowlists
+            .iter()
+            .any(|list| list.find(&qname).is_some())
+    }
+
+    /// Returns the blocked domain `qname` falls under at the local time `now`, if any: `qname`
+    /// itself or one of its parents. Allowed domains are never blocked, whatever the schedule.
+    pub fn blocked(&self, qname: &str, now: NaiveDateTime) -> Option<String> {
+        if self.allows(qname) {
+            return None;
+        }
+        let normalized = normalize(qname);
+        self.lists
+            .iter()
+            .find_map(|list| list.find(&normalized))
+            .map(str::to_owned)
+            .or_else(|| {
+                self.scheduled
+                    .iter()
+                    .filter(|scheduled| scheduled.schedule.active(now))
+                    .find_map(|scheduled| scheduled.blocklist.blocked(qname, now))
+            })
+    }
+
+    /// Builds the response to a blocked query for `qname`, according to the blocking mode.
+    pub fn response(&self, qname: &str, qtype: RecordType) -> Packet {
+        let mut packet: Packet = Default::default();
+        packet.header.is_response = true;
+
+        match self.mode {
+            BlockingMode::NxDomain => packet.header.response_code = ResultCode::NXDomain,
+            BlockingMode::Null => {
+                let preamble = RecordPreamble::new(qname, qtype, BLOCKED_ANSWER_TTL);
+                match qtype {
+                    RecordType::A => packet.answers.push(Record::A {
+                        preamble,
+                        addr: Ipv4Addr::UNSPECIFIED,
+                    }),
+                    RecordType::AAAA => packet.answers.push(Record::AAAA {
+                        preamble,
+                        addr: Ipv6Addr::UNSPECIFIED,
+                    }),
+                    _ => {}
+                }
+            }
+        }
+        packet.header.answer_count = packet.answers.len() as u16;
+
+        packet
+    }
+}
+
+/// Domains blocked or allowed through the management API, on top of the blocklist. They take
+/// precedence over it and survive reloads, being saved to a JSON file when there is one.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CustomLists {
+    pub blocked: BTreeSet<String>,
+    pub allowed: BTreeSet<String>,
+}
+
+impl CustomLists {
+    /// Reads the lists saved at `path`, which may not exist yet.
+    pub fn load(path: &str) -> Result<Self> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(Error::InvalidBlocklist(format!("{path}: {e}"))),
+        };
+
+        serde_json::from_str(&content).map_err(|e| Error::InvalidBlocklist(format!("{path}: {e}")))
+    }
+
+    /// Saves the lists at `path`, replacing the previous file at once.
+    pub fn save(&self, path: &str) -> Result<()> {
+        let failed = |e: std::io::Error| Error::InvalidBlocklist(format!("{path}: {e}"));
+
+        let json = serde_json::to_string_pretty(self)
+            .map_err(|e| Error::InvalidBlocklist(format!("{path}: {e}")))?;
+        let temporary = format!("{path}.tmp");
+        fs::write(&temporary, json + "\n").map_err(failed)?;
+        fs::rename(&temporary, path).map_err(failed)
+    }
+
+    /// Blocks `domain`, returning whether it wasn't already.
+    pub fn block(&mut self, domain: &str) -> bool {
+        self.blocked.insert(normalize(domain))
+    }
+
+    pub fn unblock(&mut self, domain: &str) -> bool {
+        self.blocked.remove(&normalize(domain))
+    }
+
+    /// Allows `domain`, returning whether it wasn't already.
+    pub fn allow(&mut self, domain: &str) -> bool {
+        self.allowed.insert(normalize(domain))
+    }
+
+    pub fn unallow(&mut self, domain: &str) -> bool {
+        self.allowed.remove(&normalize(domain))
+    }
+
+    /// Whether `qname` falls under an allowed domain.
+    pub fn allows(&self, qname: &str) -> bool {
+        listed(qname, |name| self.allowed.get(name)).is_some()
+    }
+
+    /// Returns the blocked domain `qname` falls under, if any.
+    pub fn blocked(&self, qname: &str) -> Option<&str> {
+        listed(qname, |name| self.blocked.get(name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_indices_after_the_path_of_their_list() {
+        let loader = ListLoader::new(Some("/var/cache/barthez"));
+        assert_eq!(
+            loader.index_path("/etc/barthez/ads.txt"),
+            Some(PathBuf::from(
+                "/var/cache/barthez/ads.txt.6970fdbb4b74f6e2.idx"
+            ))
+        );
+        assert_ne!(
+            loader.index_path("/etc/barthez/ads.txt"),
+            loader.index_path("/etc/barthez/kids/ads.txt")
+        );
+        assert_eq!(
+            ListLoader::new(None).index_path("/etc/barthez/ads.txt"),
+            None
+        );
+    }
+}
@@ -0,0 +1,337 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::globals::{MAX_CACHE_TTL, PREFETCH_MIN_HITS, PREFETCH_THRESHOLD, STALE_ANSWER_TTL};
+use crate::packet::Packet;
+use crate::record::{Record, RecordType};
+use crate::result::ResultCode;
+
+/// A response cached for a `(name, type)` pair.
+struct CacheEntry {
+    response_code: ResultCode,
+    answers: Vec<Record>,
+    authorities: Vec<Record>,
+    additionals: Vec<Record>,
+    /// When the response was received
+    inserted: Instant,
+    /// How long the response is valid for, i.e. the lowest TTL of its records
+    ttl: Duration,
+    /// When refreshing the entry after it expired last failed
+    refresh_failed: Option<Instant>,
+    /// Number of times the entry was served
+    hits: u32,
+    /// Whether the entry was already handed out for prefetching
+    prefetching: bool,
+}
+
+impl CacheEntry {
+    fn expires(&self) -> Instant {
+        self.inserted + self.ttl
+    }
+
+    /// Rebuilds a response out of the entry, the TTL of every record being set by `ttl`.
+    fn to_packet(&self, ttl: impl Fn(u32) -> u32) -> Packet {
+        let mut packet: Packet = Default::default();
+        packet.header.is_response = true;
+        packet.header.response_code = self.response_code;
+
+        for (section, records) in [
+            (&mut packet.answers, &self.answers),
+            (&mut packet.authorities, &self.authorities),
+            (&mut packet.additionals, &self.additionals),
+        ] {
+            for record in records {
+                let mut record = record.clone();
+                let preamble = record.preamble_mut();
+                preamble.set_ttl(ttl(preamble.ttl()));
+                section.push(record);
+            }
+        }
+        packet.header.answer_count = packet.answers.len() as u16;
+        packet.header.authority_count = packet.authorities.len() as u16;
+        packet.header.additional_count = packet.additionals.len() as u16;
+
+        packet
+    }
+}
+
+/// A cache entry, as described to the management API.
+#[derive(Serialize)]
+pub struct CachedEntry {
+    pub qtype: String,
+    pub rcode: String,
+    /// Seconds left before the entry expires, negative once it did
+    pub expires_in: i64,
+    pub hits: u32,
+    /// The records of every section, with their remaining TTL
+    pub records: Vec<String>,
+}
+
+/// A fresh response found in the cache.
+pub struct CacheHit {
+    pub response: Packet,
+    /// Whether the entry is popular and close to expiring, and should be refreshed in the
+    /// background before it does
+    pub prefetch: bool,
+}
+
+/// Caches the responses of upstream servers for as long as their TTL allows. Expired entries are
+/// kept for an additional `stale_window`, to be served when upstream servers cannot be reached as
+/// described in [RFC8767](https://www.rfc-editor.org/rfc/rfc8767).
+pub struct Cache {
+    entries: Mutex<HashMap<(String, RecordType), CacheEntry>>,
+    max_entries: usize,
+    stale_window: Duration,
+    /// Whether popular entries are refreshed before they expire
+    prefetch: bool,
+}
+
+impl Cache {
+    pub fn new(max_entries: usize, stale_window: Duration) -> Self {
+        Self {
+            entries: Mutex::new(HashMap::new()),
+            max_entries,
+            stale_window,
+            prefetch: true,
+        }
+    }
+
+    pub fn set_max_entries(&mut self, max_entries: usize) {
+        self.max_entries = max_entries;
+    }
+
+    pub fn set_stale_window(&mut self, stale_window: Duration) {
+        self.stale_window = stale_window;
+    }
+
+    pub fn set_prefetch(&mut self, prefetch: bool) {
+        self.prefetch = prefetch;
+    }
+
+    /// Number of entries, stale ones included.
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    /// Removes every entry, returning how many there were.
+    pub fn clear(&self) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        let count = entries.len();
+        entries.clear();
+        count
+    }
+
+    /// Describes the entries of `qname`, whatever their type, expired ones included.
+    pub fn entries(&self, qname: &str) -> Vec<CachedEntry> {
+        let qname = qname.trim_end_matches('.').to_lowercase();
+        let entries = self.entries.lock().unwrap();
+        let now = Instant::now();
+
+        let mut cached: Vec<CachedEntry> = entries
+            .iter()
+            .filter(|((name, _), _)| *name == qname)
+            .map(|((_, qtype), entry)| {
+                let elapsed = (now - entry.inserted).as_secs() as u32;
+                let packet = entry.to_packet(|ttl| ttl.saturating_sub(elapsed));
+                CachedEntry {
+                    qtype: qtype.to_string(),
+                    rcode: entry.response_code.to_string(),
+                    expires_in: if entry.expires() > now {
+                        (entry.expires() - now).as_secs() as i64
+                    } else {
+                        -((now - entry.expires()).as_secs() as i64)
+                    },
+                    hits: entry.hits,
+                    records: packet
+                        .answers
+                        .iter()
+                        .chain(&packet.authorities)
+                        .chain(&packet.additionals)
+                        .map(Record::presentation)
+                        .collect(),
+                }
+            })
+            .collect();
+        cached.sort_by(|a, b| a.qtype.cmp(&b.qtype));
+        cached
+    }
+
+    /// Returns the cached response for `qname`, if it hasn't expired yet. The TTLs of its records
+    /// are decreased by the time spent in the cache.
+    ///
+    /// An entry that was hit at least `PREFETCH_MIN_HITS` times and is in the last
+    /// `PREFETCH_THRESHOLD` of its TTL is flagged for prefetching, once.
+    pub fn get(&self, qname: &str, qtype: RecordType) -> Option<CacheHit> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.get_mut(&(qname.to_lowercase(), qtype))?;
+
+        let now = Instant::now();
+        if entry.expires() <= now {
+            return None;
+        }
+
+        entry.hits = entry.hits.saturating_add(1);
+        let prefetch = self.prefetch
+            && !entry.prefetching
+            && entry.hits >= PREFETCH_MIN_HITS
+            && entry.expires() - now < entry.ttl.mul_f64(PREFETCH_THRESHOLD);
+        if prefetch {
+            entry.prefetching = true;
+        }
+
+        let elapsed = (now - entry.inserted).as_secs() as u32;
+        Some(CacheHit {
+            response: entry.to_packet(|ttl| ttl.saturating_sub(elapsed)),
+            prefetch,
+        })
+    }
+
+    /// Returns the cached response for `qname` if it expired, but less than `stale_window` ago.
+    /// Every record is given a short TTL so that clients come back soon for a fresh answer.
+    pub fn get_stale(&self, qname: &str, qtype: RecordType) -> Option<Packet> {
+        let entries = self.entries.lock().unwrap();
+        let entry = entries.get(&(qname.to_lowercase(), qtype))?;
+
+        let now = Instant::now();
+        if entry.expires() > now || entry.expires() + self.stale_window <= now {
+            return None;
+        }
+
+        Some(entry.to_packet(|_| STALE_ANSWER_TTL))
+    }
+
+    /// Whether refreshing the expired entry of `qname` failed less than `interval` ago, in which
+    /// case upstream servers are likely still unreachable.
+    pub fn refresh_failed_within(
+        &self,
+        qname: &str,
+        qtype: RecordType,
+        interval: Duration,
+    ) -> bool {
+        let entries = self.entries.lock().unwrap();
+
+        entries
+            .get(&(qname.to_lowercase(), qtype))
+            .and_then(|entry| entry.refresh_failed)
+            .is_some_and(|failed| failed.elapsed() < interval)
+    }
+
+    /// Records that refreshing the entry of `qname` failed.
+    pub fn set_refresh_failed(&self, qname: &str, qtype: RecordType) {
+        let mut entries = self.entries.lock().unwrap();
+
+        if let Some(entry) = entries.get_mut(&(qname.to_lowercase(), qtype)) {
+            entry.refresh_failed = Some(Instant::now());
+        }
+    }
+
+    /// Lets the entry of `qname` be flagged for prefetching again, once refreshing it is over. A
+    /// successful refresh replaces the entry, but one that failed, or whose response cannot be
+    /// cached, leaves it as is.
+    pub fn end_prefetch(&self, qname: &str, qtype: RecordType) {
+        let mut entries = self.entries.lock().unwrap();
+
+        if let Some(entry) = entries.get_mut(&(qname.to_lowercase(), qtype)) {
+            entry.prefetching = false;
+        }
+    }
+
+    /// Caches the `response` received for `qname`. Only successful and `NXDOMAIN` responses
+    /// holding at least one record are cached, for the lowest TTL of their records.
+    pub fn insert(&self, qname: &str, qtype: RecordType, response: &Packet) {
+        let response_code = response.header.response_code;
+        if response_code != ResultCode::NoError && response_code != ResultCode::NXDomain {
+            return;
+        }
+
+        let ttl = match response.min_ttl() {
+            Some(ttl) => Duration::from_secs(ttl.into()).min(MAX_CACHE_TTL),
+            None => return,
+        };
+
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.max_entries {
+            self.evict(&mut entries);
+        }
+
+        entries.insert(
+            (qname.to_lowercase(), qtype),
+            CacheEntry {
+                response_code,
+                answers: response.answers.clone(),
+                authorities: response.authorities.clone(),
+                additionals: response.additionals.clone(),
+                inserted: Instant::now(),
+                ttl,
+                refresh_failed: None,
+                hits: 0,
+                prefetching: false,
+            },
+        );
+    }
+
+    /// Makes room for a new entry: entries that cannot even be served stale anymore are dropped,
+    /// and if that's not enough, the entry expiring first is.
+    fn evict(&self, entries: &mut HashMap<(String, RecordType), CacheEntry>) {
+        let now = Instant::now();
+        entries.retain(|_, entry| entry.expires() + self.stale_window > now);
+
+        if entries.len() >= self.max_entries {
+            if let Some(key) = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.expires())
+                .map(|(key, _)| key.clone())
+            {
+                entries.remove(&key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use crate::globals::PREFETCH_MIN_HITS;
+    use crate::record::RecordPreamble;
+
+    use super::*;
+
+    /// A cache holding a popular entry for `example.com`, about to expire.
+    fn expiring_cache() -> Cache {
+        let mut response: Packet = Default::default();
+        response.answers.push(Record::A {
+            preamble: RecordPreamble::new("example.com", RecordType::A, 100),
+            addr: Ipv4Addr::new(192, 0, 2, 1),
+        });
+
+        let cache = Cache::new(10, Duration::ZERO);
+        cache.insert("example.com", RecordType::A, &response);
+        let mut entries = cache.entries.lock().unwrap();
+        let entry = entries.values_mut().next().unwrap();
+        entry.inserted -= Duration::from_secs(95);
+        entry.hits = PREFETCH_MIN_HITS;
+        drop(entries);
+
+        cache
+    }
+
+    #[test]
+    fn prefetches_an_entry_once() {
+        let cache = expiring_cache();
+        assert!(cache.get("example.com", RecordType::A).unwrap().prefetch);
+        assert!(!cache.get("example.com", RecordType::A).unwrap().prefetch);
+    }
+
+    #[test]
+    fn prefetches_again_once_a_refresh_is_over() {
+        let cache = expiring_cache();
+        assert!(cache.get("example.com", RecordType::A).unwrap().prefetch);
+        // The refresh failed, leaving the entry as is
+        cache.end_prefetch("example.com", RecordType::A);
+        assert!(cache.get("example.com", RecordType::A).unwrap().prefetch);
+    }
+}
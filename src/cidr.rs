@@ -0,0 +1,89 @@
+use std::fmt::{self, Formatter};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+use serde::{de, Deserialize, Deserializer};
+
+use crate::result::Error;
+
+/// A block of addresses sharing their first `prefix_len` bits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    /// The block of `prefix_len` bits `addr` belongs to. The length is capped to that of the
+    /// address.
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Self {
+        let (addr, prefix_len) = match addr {
+            IpAddr::V4(addr) => {
+                let prefix_len = prefix_len.min(32);
+                let mask = u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0);
+                (
+                    IpAddr::V4(Ipv4Addr::from(u32::from(addr) & mask)),
+                    prefix_len,
+                )
+            }
+            IpAddr::V6(addr) => {
+                let prefix_len = prefix_len.min(128);
+                let mask = u128::MAX.checked_shl(128 - prefix_len as u32).unwrap_or(0);
+                (
+                    IpAddr::V6(Ipv6Addr::from(u128::from(addr) & mask)),
+                    prefix_len,
+                )
+            }
+        };
+
+        Self { addr, prefix_len }
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    /// Whether `addr` belongs to the block. IPv4 addresses mapped to IPv6 ones are treated as
+    /// the IPv4 addresses they are.
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        let addr = addr.to_canonical();
+
+        addr.is_ipv4() == self.addr.is_ipv4() && Cidr::new(addr, self.prefix_len).addr == self.addr
+    }
+}
+
+/// Parses `<ip>/<prefix length>`, or a single address without a prefix length.
+impl FromStr for Cidr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::InvalidCidr(s.to_owned());
+
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, len)) => (addr, Some(len.parse::<u8>().map_err(|_| invalid())?)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+
+        match prefix_len {
+            Some(len) if len > max_len => Err(invalid()),
+            Some(len) => Ok(Cidr::new(addr, len)),
+            None => Ok(Cidr::new(addr, max_len)),
+        }
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+impl<'de> Deserialize<'de> for Cidr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse()
+            .map_err(|_| de::Error::custom(format!("invalid address block {s}")))
+    }
+}
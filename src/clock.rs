@@ -0,0 +1,15 @@
+use chrono::{DateTime, Utc};
+
+/// Tells the time, so that what depends on it can be run at any time of day.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+/// The clock of the system.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
@@ -0,0 +1,521 @@
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+
+use chrono::{NaiveTime, Weekday};
+use serde::Deserialize;
+use toml::{Table, Value};
+
+use crate::blocklist::BlockingMode;
+use crate::cidr::Cidr;
+use crate::globals::{CACHE_MAX_ENTRIES, SERVE_STALE_WINDOW};
+use crate::groups::ClientMatcher;
+use crate::log::LogLevel;
+use crate::querydb::QueryDbConfig;
+use crate::querylog::QueryLogConfig;
+use crate::ratelimit::RateLimitConfig;
+use crate::result::{Error, Result};
+use crate::server::{IpFamilies, QnameMinimisation};
+use crate::upstream::{self, DohMethod};
+
+/// The whole configuration of the server, read from a TOML file. Every setting has a default,
+/// so that an empty file (or no file at all) gives a working recursive resolver.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listen: ListenConfig,
+    pub resolution: ResolutionConfig,
+    pub upstreams: UpstreamsConfig,
+    pub blocking: BlockingConfig,
+    /// Groups of clients blocking their own way, written `[[groups]]`
+    pub groups: Vec<GroupConfig>,
+    pub cache: CacheConfig,
+    pub access: AccessConfig,
+    pub rate_limit: RateLimitConfig,
+    pub logging: LoggingConfig,
+    pub query_log: QueryLogConfig,
+    pub query_db: QueryDbConfig,
+}
+
+/// Where clients are served.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListenConfig {
+    /// Address of the UDP listener, and of the TCP one clients retry truncated responses over
+    pub udp: SocketAddr,
+    /// `<ip>[:<port>]` of the DNS over TLS listener, on port 853 by default
+    pub dot: Option<String>,
+    /// `<ip>[:<port>]` of the DNS over HTTPS listener, on port 443 by default
+    pub doh: Option<String>,
+    /// `<ip>[:<port>]` of the management HTTP server, serving metrics, on port 8080 by default
+    pub http: Option<String>,
+    /// PEM file holding the certificate chain of the TLS and HTTPS listeners
+    pub cert: Option<String>,
+    /// PEM file holding the private key of the certificate
+    pub key: Option<String>,
+}
+
+impl Default for ListenConfig {
+    fn default() -> Self {
+        Self {
+            udp: SocketAddr::from(([0, 0, 0, 0], 2053)),
+            dot: None,
+            doh: None,
+            http: None,
+            cert: None,
+            key: None,
+        }
+    }
+}
+
+/// How queries are answered when they aren't blocked nor cached.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResolutionMode {
+    /// Iterate from the root servers, save for the zones forwarded to upstreams
+    #[default]
+    Recursive,
+    /// Forward every query to upstreams
+    Forward,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ResolutionConfig {
+    pub mode: ResolutionMode,
+    pub ip_families: IpFamilies,
+    pub qname_minimisation: QnameMinimisation,
+    /// File holding the root hints, the built-in ones being used if it cannot be read
+    pub root_hints: String,
+    /// Local address queries to name servers are sent from
+    pub query_address: IpAddr,
+    /// Local port queries to name servers are sent from, 0 letting the OS pick one per lookup.
+    /// Any other port makes lookups wait for each other.
+    pub query_port: u16,
+}
+
+impl Default for ResolutionConfig {
+    fn default() -> Self {
+        Self {
+            mode: ResolutionMode::default(),
+            ip_families: IpFamilies::default(),
+            qname_minimisation: QnameMinimisation::default(),
+            root_hints: "data/named.root".to_owned(),
+            query_address: IpAddr::from([0, 0, 0, 0]),
+            query_port: 0,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpstreamsConfig {
+    /// `[<zone>=]<upstream>` forwarding the queries for `zone` (or every query) to `upstream`,
+    /// see `upstream::from_spec`
+    pub forward: Vec<String>,
+    /// PEM file holding the CA certificates trusted by upstreams reached over TLS or HTTPS
+    pub tls_ca: Option<String>,
+    /// Base64 SHA-256 hashes of the SubjectPublicKeyInfo upstream certificates must match
+    pub tls_pins: Vec<String>,
+    pub doh_method: DohMethod,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BlockingConfig {
+    /// Files listing the domains to block, as plain lists or hosts files
+    pub lists: Vec<String>,
+    /// Files listing the domains never to block, in the same formats
+    pub allowlists: Vec<String>,
+    pub mode: BlockingMode,
+    /// JSON file saving the domains blocked and allowed through the management API
+    pub custom: Option<String>,
+    /// Blocklists only applying at times, written `[[blocking.scheduled]]`
+    pub scheduled: Vec<ScheduledListConfig>,
+    /// Directory keeping the lists compiled, for them to load faster as long as they don't change
+    pub index_dir: Option<String>,
+}
+
+/// Blocklists only applying during a time window of the local time zone, see
+/// `schedule::Schedule`.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScheduledListConfig {
+    /// Files listing the domains to block
+    pub lists: Vec<String>,
+    /// Days the time window starts on, every day when missing
+    #[serde(default)]
+    pub days: Vec<Weekday>,
+    /// Start of the time window, e.g. `21:00`
+    pub from: NaiveTime,
+    /// End of the time window, the next day when not after `from`
+    pub to: NaiveTime,
+}
+
+/// Clients whose queries are blocked with their own lists and mode, rather than those of
+/// `[blocking]`.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GroupConfig {
+    pub name: String,
+    /// Addresses, address blocks or MAC addresses of the clients of the group
+    pub clients: Vec<ClientMatcher>,
+    /// Files listing the domains to block, replacing `blocking.lists` for the group
+    pub lists: Vec<String>,
+    /// Files listing the domains never to block, replacing `blocking.allowlists`
+    pub allowlists: Vec<String>,
+    /// How blocked queries are answered, `blocking.mode` by default
+    pub mode: Option<BlockingMode>,
+    /// Blocklists only applying at times, replacing `blocking.scheduled`, written
+    /// `[[groups.scheduled]]`
+    pub scheduled: Vec<ScheduledListConfig>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    pub max_entries: usize,
+    /// How long expired responses are served for when upstreams are unreachable, in seconds
+    pub serve_stale: u64,
+    /// Whether popular entries are refreshed before they expire
+    pub prefetch: bool,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            max_entries: CACHE_MAX_ENTRIES,
+            serve_stale: SERVE_STALE_WINDOW.as_secs(),
+            prefetch: true,
+        }
+    }
+}
+
+/// Which clients are served, the most specific block a client belongs to winning.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessConfig {
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    pub level: LogLevel,
+    /// Sample packets to decode and print on startup
+    pub samples: Vec<String>,
+}
+
+impl Config {
+    /// Reads the configuration of `path`, if any, applies the `overrides` on top of it and
+    /// validates the result. Errors name the offending key.
+    pub fn load(path: Option<&str>, overrides: &[Override]) -> Result<Self> {
+        let mut table = match path {
+            Some(path) => {
+                let content = fs::read_to_string(path)
+                    .map_err(|e| Error::InvalidConfig(format!("{path}: {e}")))?;
+                content
+                    .parse::<Table>()
+                    .map_err(|e| Error::InvalidConfig(format!("{path}: {e}")))?
+            }
+            None => Table::new(),
+        };
+
+        for o in overrides {
+            o.apply(&mut table)?;
+        }
+
+        // The path is tracked on our side, as deserializing from a `Value` rather than from the
+        // file loses the location of the errors.
+        let config: Config =
+            serde_path_to_error::deserialize(Value::Table(table)).map_err(|e| {
+                let message = e.inner().to_string();
+                let message = message.lines().next().unwrap_or_default().to_owned();
+                Error::InvalidConfig(format!("{}: {}", e.path(), message))
+            })?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /// Checks what deserializing alone cannot.
+    fn validate(&self) -> Result<()> {
+        let invalid = |key: &str, reason: &str| Error::InvalidConfig(format!("{key}: {reason}"));
+
+        for (key, addr, default_port) in [
+            ("listen.dot", &self.listen.dot, 853),
+            ("listen.doh", &self.listen.doh, 443),
+        ] {
+            let Some(addr) = addr else { continue };
+            if upstream::parse_addr(addr, default_port).is_none() {
+                return Err(invalid(key, &format!("invalid address {addr}")));
+            }
+            if self.listen.cert.is_none() {
+                return Err(invalid("listen.cert", &format!("required by {key}")));
+            }
+            if self.listen.key.is_none() {
+                return Err(invalid("listen.key", &format!("required by {key}")));
+            }
+        }
+
+        if let Some(addr) = &self.listen.http {
+            if upstream::parse_addr(addr, 8080).is_none() {
+                return Err(invalid("listen.http", &format!("invalid address {addr}")));
+            }
+        }
+
+        let mut forwards_everything = false;
+        for (i, forward) in self.upstreams.forward.iter().enumerate() {
+            let (zone, spec) = forward.split_once('=').unwrap_or(("", forward));
+            let Some((scheme, _)) = spec.split_once("://") else {
+                return Err(invalid(
+                    &format!("upstreams.forward[{i}]"),
+                    &format!("invalid upstream {spec}"),
+                ));
+            };
+            if !matches!(scheme, "udp" | "tls" | "https") {
+                return Err(invalid(
+                    &format!("upstreams.forward[{i}]"),
+                    &format!("unknown scheme {scheme}"),
+                ));
+            }
+            forwards_everything |= zone.is_empty() || zone == ".";
+        }
+        match self.resolution.mode {
+            ResolutionMode::Forward if !forwards_everything => {
+                return Err(invalid(
+                    "resolution.mode",
+                    "forward mode requires an upstream for every zone in upstreams.forward",
+                ));
+            }
+            ResolutionMode::Recursive if forwards_everything => {
+                return Err(invalid(
+                    "upstreams.forward",
+                    "forwarding every zone requires resolution.mode = \"forward\"",
+                ));
+            }
+            _ => {}
+        }
+
+        for (i, group) in self.groups.iter().enumerate() {
+            if group.name.is_empty() {
+                return Err(invalid(&format!("groups[{i}].name"), "required"));
+            }
+            if self.groups[..i]
+                .iter()
+                .any(|other| other.name == group.name)
+            {
+                return Err(invalid(
+                    &format!("groups[{i}].name"),
+                    &format!("duplicate group {}", group.name),
+                ));
+            }
+            if group.clients.is_empty() {
+                return Err(invalid(
+                    &format!("groups[{i}].clients"),
+                    "must not be empty",
+                ));
+            }
+        }
+
+        if self.cache.max_entries == 0 {
+            return Err(invalid("cache.max_entries", "must be at least 1"));
+        }
+        if self.rate_limit.ipv4_prefix > 32 {
+            return Err(invalid("rate_limit.ipv4_prefix", "must be at most 32"));
+        }
+        if self.rate_limit.ipv6_prefix > 128 {
+            return Err(invalid("rate_limit.ipv6_prefix", "must be at most 128"));
+        }
+
+        Ok(())
+    }
+}
+
+/// A setting given on the command line, taking precedence over the configuration file.
+#[derive(Clone, Debug)]
+pub struct Override {
+    /// Dotted path of the setting, e.g. `cache.max_entries`
+    key: String,
+    value: Value,
+    /// Whether `value` is appended to the list `key` holds, rather than replacing it
+    append: bool,
+}
+
+impl Override {
+    /// Sets the key of `table` the override is about, creating the tables on the way.
+    fn apply(&self, table: &mut Table) -> Result<()> {
+        let invalid = |reason: &str| Error::InvalidConfig(format!("{}: {reason}", self.key));
+
+        let mut table = table;
+        let mut path = self.key.split('.').peekable();
+        while let Some(part) = path.next() {
+            if path.peek().is_none() {
+                if !self.append {
+                    table.insert(part.to_owned(), self.value.clone());
+                    return Ok(());
+                }
+                return match table
+                    .entry(part)
+                    .or_insert_with(|| Value::Array(Vec::new()))
+                {
+                    Value::Array(values) => {
+                        values.push(self.value.clone());
+                        Ok(())
+                    }
+                    _ => Err(invalid("not a list")),
+                };
+            }
+
+            table = match table
+                .entry(part)
+                .or_insert_with(|| Value::Table(Table::new()))
+            {
+                Value::Table(table) => table,
+                _ => return Err(invalid("not a table")),
+            };
+        }
+
+        Err(invalid("empty key"))
+    }
+}
+
+/// How a command-line flag maps to a setting.
+enum Flag {
+    /// The flag takes a value, which replaces the setting
+    Set(&'static str),
+    /// The flag takes a value, which is appended to the list of the setting
+    Append(&'static str),
+    /// The flag takes no value, and sets the setting to the given TOML value
+    Switch(&'static str, &'static str),
+}
+
+/// The shorthand flags, every other setting being reachable with `--set <key>=<value>`.
+const FLAGS: &[(&str, Flag)] = &[
+    ("--listen", Flag::Set("listen.udp")),
+    ("--dot", Flag::Set("listen.dot")),
+    ("--doh", Flag::Set("listen.doh")),
+    ("--http", Flag::Set("listen.http")),
+    ("--cert", Flag::Set("listen.cert")),
+    ("--key", Flag::Set("listen.key")),
+    ("--mode", Flag::Set("resolution.mode")),
+    ("-4", Flag::Switch("resolution.ip_families", "\"ipv4\"")),
+    ("-6", Flag::Switch("resolution.ip_families", "\"ipv6\"")),
+    (
+        "--qname-minimisation",
+        Flag::Set("resolution.qname_minimisation"),
+    ),
+    ("--root-hints", Flag::Set("resolution.root_hints")),
+    ("--forward", Flag::Append("upstreams.forward")),
+    ("--tls-ca", Flag::Set("upstreams.tls_ca")),
+    ("--tls-pin", Flag::Append("upstreams.tls_pins")),
+    ("--doh-get", Flag::Switch("upstreams.doh_method", "\"get\"")),
+    ("--blocklist", Flag::Append("blocking.lists")),
+    ("--allowlist", Flag::Append("blocking.allowlists")),
+    ("--blocking-mode", Flag::Set("blocking.mode")),
+    ("--blocklist-index", Flag::Set("blocking.index_dir")),
+    ("--cache-size", Flag::Set("cache.max_entries")),
+    ("--serve-stale", Flag::Set("cache.serve_stale")),
+    ("--no-prefetch", Flag::Switch("cache.prefetch", "false")),
+    ("--allow", Flag::Append("access.allow")),
+    ("--deny", Flag::Append("access.deny")),
+    ("--rate-limit", Flag::Set("rate_limit.queries_per_second")),
+    ("--rate-limit-burst", Flag::Set("rate_limit.query_burst")),
+    ("--rrl", Flag::Set("rate_limit.responses_per_second")),
+    ("--rrl-slip", Flag::Set("rate_limit.slip")),
+    ("--log-level", Flag::Set("logging.level")),
+    ("--query-log", Flag::Set("query_log.file")),
+    ("--query-log-format", Flag::Set("query_log.format")),
+    ("--query-db", Flag::Set("query_db.file")),
+];
+
+/// What the command line asks for: a configuration file, and settings overriding it.
+#[derive(Clone, Debug, Default)]
+pub struct CommandLine {
+    /// Given by `--config <file>`
+    pub config_file: Option<String>,
+    pub overrides: Vec<Override>,
+    /// Whether the configuration file and blocklists are watched for changes, given by `--watch`
+    pub watch: bool,
+}
+
+impl CommandLine {
+    pub fn parse(args: &[String]) -> Result<Self> {
+        let mut command_line = CommandLine::default();
+        let mut args = args.iter();
+
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| Error::InvalidArgument(format!("{arg} requires a value")))
+            };
+
+            if arg == "--watch" {
+                command_line.watch = true;
+                continue;
+            }
+            if arg == "--config" {
+                command_line.config_file = Some(value()?.to_owned());
+                continue;
+            }
+            if arg == "--set" {
+                let setting = value()?;
+                let (key, v) = setting.split_once('=').ok_or_else(|| {
+                    Error::InvalidArgument(format!("--set {setting}: expected <key>=<value>"))
+                })?;
+                command_line.overrides.push(Override {
+                    key: key.to_owned(),
+                    value: parse_value(v),
+                    append: false,
+                });
+                continue;
+            }
+
+            let (_, flag) = FLAGS
+                .iter()
+                .find(|(name, _)| name == arg)
+                .ok_or_else(|| Error::InvalidArgument(format!("unknown flag {arg}")))?;
+            let o = match *flag {
+                Flag::Set(key) => Override {
+                    key: key.to_owned(),
+                    value: parse_value(value()?),
+                    append: false,
+                },
+                Flag::Append(key) => Override {
+                    key: key.to_owned(),
+                    value: parse_value(value()?),
+                    append: true,
+                },
+                Flag::Switch(key, v) => Override {
+                    key: key.to_owned(),
+                    value: parse_value(v),
+                    append: false,
+                },
+            };
+            command_line.overrides.push(o);
+        }
+
+        Ok(command_line)
+    }
+}
+
+/// Reads a value given on the command line as a TOML value, so that numbers and booleans get
+/// their type, falling back to a plain string (which saves quoting addresses and paths).
+fn parse_value(value: &str) -> Value {
+    format!("value = {value}")
+        .parse::<Table>()
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| Value::String(value.to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn example_shows_the_defaults() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/data/barthez.toml");
+        assert_eq!(Config::load(Some(path), &[]).unwrap(), Config::default());
+    }
+}
@@ -0,0 +1,122 @@
+use std::io::{Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::time::Duration;
+
+use serde_json::json;
+
+use crate::globals::CONTROL_TIMEOUT;
+use crate::pause::BlockingStatus;
+use crate::result::{Error, Result};
+use crate::upstream;
+
+/// Address of the management HTTP server, unless given by `--api`
+const DEFAULT_API_ADDR: &str = "127.0.0.1:8080";
+
+/// Whether `args` are a command sent to a running server, rather than settings to run one with.
+pub fn is_command(args: &[String]) -> bool {
+    matches!(args.first().map(String::as_str), Some("enable" | "disable"))
+}
+
+/// Sends the command of `args` to a running server, through its management API:
+///
+/// - `disable [<duration>] [--client <ip>] [--api <ip>[:<port>]]` disables blocking, for
+///   `<duration>` (e.g. `300`, `30s`, `5m` or `2h`) or until enabled again
+/// - `enable [--client <ip>] [--api <ip>[:<port>]]` enables it again
+pub fn run(args: &[String]) -> Result<()> {
+    let invalid = |reason: String| Error::InvalidArgument(reason);
+
+    let mut args = args.iter();
+    let enabled = args.next().map(String::as_str) == Some("enable");
+    let mut duration = None;
+    let mut client = None;
+    let mut api = DEFAULT_API_ADDR.to_owned();
+
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| invalid(format!("{arg} requires a value")))
+        };
+
+        match arg.as_str() {
+            "--client" => {
+                let value = value()?;
+                let ip = value
+                    .parse::<IpAddr>()
+                    .map_err(|_| invalid(format!("--client {value}: not an IP address")))?;
+                client = Some(ip);
+            }
+            "--api" => api = value()?.to_owned(),
+            _ if !enabled && duration.is_none() && !arg.starts_with('-') => {
+                let seconds = parse_duration(arg)
+                    .ok_or_else(|| invalid(format!("{arg}: expected a duration like 5m")))?;
+                duration = Some(seconds.as_secs());
+            }
+            _ => return Err(invalid(format!("unknown argument {arg}"))),
+        }
+    }
+
+    let addr = upstream::parse_addr(&api, 8080)
+        .ok_or_else(|| invalid(format!("--api {api}: expected <ip>[:<port>]")))?;
+    let mut body = json!({ "enabled": enabled });
+    if let Some(client) = client {
+        body["client"] = json!(client);
+    }
+    if let Some(duration) = duration {
+        body["duration"] = json!(duration);
+    }
+
+    let response = put(addr, "/api/blocking", &body.to_string())?;
+    let status: BlockingStatus = serde_json::from_str(&response)
+        .map_err(|e| Error::ControlFailed(format!("invalid response: {e}")))?;
+    println!("Blocking {}", status);
+
+    Ok(())
+}
+
+/// Parses a number of seconds, or of minutes, hours or days with a `m`, `h` or `d` suffix.
+fn parse_duration(duration: &str) -> Option<Duration> {
+    let (count, unit) = match duration.char_indices().last()? {
+        (i, 's') => (&duration[..i], 1),
+        (i, 'm') => (&duration[..i], 60),
+        (i, 'h') => (&duration[..i], 60 * 60),
+        (i, 'd') => (&duration[..i], 24 * 60 * 60),
+        _ => (duration, 1),
+    };
+
+    count
+        .parse::<u64>()
+        .ok()
+        .filter(|count| *count > 0)
+        .and_then(|count| count.checked_mul(unit))
+        .map(Duration::from_secs)
+}
+
+/// Sends `body` to `path` with a `PUT` request, returning the body of a successful response.
+fn put(addr: SocketAddr, path: &str, body: &str) -> Result<String> {
+    let failed = |e: std::io::Error| Error::ControlFailed(format!("{addr}: {e}"));
+
+    let mut stream = TcpStream::connect_timeout(&addr, CONTROL_TIMEOUT).map_err(failed)?;
+    stream
+        .set_read_timeout(Some(CONTROL_TIMEOUT))
+        .map_err(failed)?;
+    write!(
+        stream,
+        "PUT {path} HTTP/1.1\r\nHost: {addr}\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )
+    .map_err(failed)?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).map_err(failed)?;
+
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or_else(|| Error::ControlFailed(format!("{addr}: truncated response")))?;
+    let status = head.lines().next().unwrap_or_default();
+    if !status.contains(" 200 ") {
+        return Err(Error::ControlFailed(format!("{addr}: {status} {body}")));
+    }
+
+    Ok(body.to_owned())
+}
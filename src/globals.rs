@@ -1 +1,151 @@
+use std::time::Duration;
+
 pub(crate) const MAX_JUMPS: usize = 5;
+
+/// Largest message sent over UDP, larger responses being truncated for the client to retry over
+/// TCP
+pub(crate) const UDP_MESSAGE_SIZE: usize = 512;
+
+/// Largest message sent over TCP, TLS or HTTPS, whose length is written on 2 bytes
+pub(crate) const MAX_MESSAGE_SIZE: usize = 65535;
+
+/// Maximum number of queries sent to upstream servers while resolving a single client request,
+/// nested name server lookups included.
+pub(crate) const MAX_UPSTREAM_QUERIES: usize = 64;
+
+/// Maximum nesting of lookups started to find the address of a glueless name server.
+pub(crate) const MAX_NS_DEPTH: usize = 4;
+
+/// Maximum number of referrals followed by a single lookup sequence before giving up.
+pub(crate) const MAX_REFERRALS: usize = 16;
+
+/// How long to wait for the response of an upstream name server.
+pub(crate) const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Weight given to a new RTT sample when updating the smoothed RTT of a name server.
+pub(crate) const NS_SRTT_WEIGHT: f64 = 0.3;
+
+/// Highest smoothed RTT of a name server that keeps failing, so that a few responses are enough
+/// for it to be preferred again once it recovers.
+pub(crate) const NS_SRTT_MAX: Duration = Duration::from_secs(8);
+
+/// Upper bound of the random RTT given to name servers that were never queried.
+pub(crate) const NS_UNKNOWN_RTT_MAX: Duration = Duration::from_millis(32);
+
+/// Probability of querying another name server than the fastest one, to keep its RTT fresh.
+pub(crate) const NS_PROBE_PROBABILITY: f64 = 0.05;
+
+/// Backoff applied after the first failure of a name server, doubled on each consecutive one.
+pub(crate) const NS_BACKOFF_BASE: Duration = Duration::from_secs(1);
+
+/// Longest backoff applied to a name server that keeps failing.
+pub(crate) const NS_BACKOFF_MAX: Duration = Duration::from_secs(300);
+
+/// Number of minimised queries revealing a single label, before revealing several at once.
+pub(crate) const MINIMISE_ONE_LAB: usize = 4;
+
+/// Maximum number of minimised queries sent for a single lookup sequence.
+pub(crate) const MAX_MINIMISE_COUNT: usize = 10;
+
+/// Maximum number of responses held in the cache.
+pub(crate) const CACHE_MAX_ENTRIES: usize = 10_000;
+
+/// Longest time a response is cached for, whatever the TTL of its records.
+pub(crate) const MAX_CACHE_TTL: Duration = Duration::from_secs(86_400);
+
+/// How long expired responses are kept to be served when upstream servers are unreachable.
+pub(crate) const SERVE_STALE_WINDOW: Duration = Duration::from_secs(86_400);
+
+/// TTL of the records of stale responses, as recommended by RFC8767.
+pub(crate) const STALE_ANSWER_TTL: u32 = 30;
+
+/// Time between two attempts at refreshing a stale response, during which it is served without
+/// trying to reach upstream servers first.
+pub(crate) const STALE_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Number of hits after which a cache entry is popular enough to be prefetched.
+pub(crate) const PREFETCH_MIN_HITS: u32 = 3;
+
+/// Fraction of its TTL left under which a popular cache entry is prefetched when hit.
+pub(crate) const PREFETCH_THRESHOLD: f64 = 0.1;
+
+/// How long a client connection (TLS or HTTPS) may stay idle before it's closed
+pub(crate) const CLIENT_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Maximum number of client connections open at once on a listener, further ones being refused
+pub(crate) const MAX_CLIENT_CONNECTIONS: usize = 256;
+
+/// Maximum number of queries of a single TCP or TLS connection being answered at once, further
+/// ones being left unread until then
+pub(crate) const MAX_CONNECTION_QUERIES: usize = 32;
+
+/// TTL of the records answering blocked queries
+pub(crate) const BLOCKED_ANSWER_TTL: u32 = 60;
+
+/// Length of the prefix IPv4 clients are grouped by when rate limiting, as they are rarely alone
+/// behind their address
+pub(crate) const RATE_LIMIT_IPV4_PREFIX: u8 = 24;
+
+/// Length of the prefix IPv6 clients are grouped by when rate limiting, a customer being usually
+/// given a whole /56
+pub(crate) const RATE_LIMIT_IPV6_PREFIX: u8 = 56;
+
+/// Number of clients (or responses) tracked by the rate limiter, the least recently seen being
+/// forgotten past that
+pub(crate) const RATE_LIMIT_MAX_ENTRIES: usize = 100_000;
+
+/// How often watched files are checked for changes
+pub(crate) const RELOAD_WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Size in bytes past which the query log file is rotated
+pub(crate) const QUERY_LOG_MAX_SIZE: u64 = 10 * 1024 * 1024;
+
+/// Number of rotated query log files kept
+pub(crate) const QUERY_LOG_KEEP: u32 = 5;
+
+/// Days queries are kept in the query database
+pub(crate) const QUERY_DB_RETENTION_DAYS: u32 = 91;
+
+/// Number of queries waiting to be written to the query database, past which new ones are dropped
+/// rather than holding up clients
+pub(crate) const QUERY_DB_QUEUE_SIZE: usize = 10_000;
+
+/// Number of queries written to the query database in a single transaction, at most
+pub(crate) const QUERY_DB_BATCH_SIZE: usize = 1000;
+
+/// How often queries past the retention period are deleted from the query database
+pub(crate) const QUERY_DB_PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Length of the slots queries are counted by for the top domains and clients
+pub(crate) const STATS_SLOT: Duration = Duration::from_secs(10 * 60);
+
+/// How far back the top domains and clients go
+pub(crate) const STATS_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Number of domains (or clients) tracked per slot, past which new ones go uncounted
+pub(crate) const STATS_MAX_KEYS: usize = 10_000;
+
+/// Number of upstreams (forwarders and name servers) tracked by the metrics, past which new ones
+/// go unrecorded
+pub(crate) const METRICS_MAX_UPSTREAMS: usize = 1000;
+
+/// Number of entries in the top domains and clients of the statistics report
+pub(crate) const STATS_TOP_COUNT: usize = 10;
+
+/// Number of the latest queries kept in memory for the management API
+pub(crate) const RECENT_QUERIES: usize = 1000;
+
+/// Largest request body accepted by the management API
+pub(crate) const API_MAX_BODY_SIZE: usize = 64 * 1024;
+
+/// Longest time blocking can be disabled for, short of disabling it until enabled again
+pub(crate) const MAX_PAUSE_DURATION: Duration = Duration::from_secs(366 * 24 * 60 * 60);
+
+/// Longest time blocking pauses go unchecked, in case the clock jumps
+pub(crate) const PAUSE_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long the ARP table is used for before being read again, to match clients by MAC address
+pub(crate) const ARP_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Longest wait for the management API when sending it a command from the command line
+pub(crate) const CONTROL_TIMEOUT: Duration = Duration::from_secs(5);
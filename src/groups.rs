@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+use std::fs;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use serde::{de, Deserialize, Deserializer};
+
+use crate::blocklist::Blocklist;
+use crate::cidr::Cidr;
+use crate::globals::ARP_REFRESH_INTERVAL;
+
+/// The ARP table of the kernel, mapping the IPv4 addresses of the local network to MAC addresses
+const ARP_TABLE: &str = "/proc/net/arp";
+
+/// A hardware address, written `aa:bb:cc:dd:ee:ff` (or with dashes).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct MacAddr([u8; 6]);
+
+impl FromStr for MacAddr {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut bytes = [0; 6];
+        let mut parts = s.split([':', '-']);
+        for byte in &mut bytes {
+            let part = parts.next().ok_or(())?;
+            if part.len() != 2 {
+                return Err(());
+            }
+            *byte = u8::from_str_radix(part, 16).map_err(|_| ())?;
+        }
+
+        match parts.next() {
+            Some(_) => Err(()),
+            None => Ok(Self(bytes)),
+        }
+    }
+}
+
+/// How the clients of a group are recognized.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClientMatcher {
+    /// By address, or address block
+    Cidr(Cidr),
+    /// By MAC address, for clients of the local network found in the ARP table
+    Mac(MacAddr),
+}
+
+impl<'de> Deserialize<'de> for ClientMatcher {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        if let Ok(mac) = s.parse() {
+            return Ok(ClientMatcher::Mac(mac));
+        }
+        s.parse().map(ClientMatcher::Cidr).map_err(|_| {
+            de::Error::custom(format!(
+                "invalid client {s}, expected an address block or a MAC"
+            ))
+        })
+    }
+}
+
+/// Clients sharing their own blocklists and blocking mode.
+pub struct Group {
+    pub name: String,
+    clients: Vec<ClientMatcher>,
+    pub blocklist: Blocklist,
+}
+
+impl Group {
+    pub fn new(name: String, clients: Vec<ClientMatcher>, blocklist: Blocklist) -> Self {
+        Self {
+            name,
+            clients,
+            blocklist,
+        }
+    }
+
+    /// How specifically the group matches a client of address `client` and MAC address `mac`,
+    /// if it does: a MAC address beats any address block, and longer blocks beat shorter ones.
+    fn specificity(&self, client: IpAddr, mac: Option<MacAddr>) -> Option<u16> {
+        self.clients
+            .iter()
+            .filter_map(|matcher| match matcher {
+                ClientMatcher::Cidr(cidr) if cidr.contains(client) => {
+                    Some(cidr.prefix_len() as u16)
+                }
+                ClientMatcher::Mac(addr) if mac == Some(*addr) => Some(u16::MAX),
+                _ => None,
+            })
+            .max()
+    }
+
+    fn has_macs(&self) -> bool {
+        self.clients
+            .iter()
+            .any(|matcher| matches!(matcher, ClientMatcher::Mac(_)))
+    }
+}
+
+/// The IPv4 neighbours of the host, read from the ARP table at most every
+/// `ARP_REFRESH_INTERVAL`.
+#[derive(Default)]
+struct ArpTable {
+    /// The neighbours, along with when they were read
+    neighbours: Mutex<Option<(Instant, HashMap<IpAddr, MacAddr>)>>,
+}
+
+impl ArpTable {
+    fn lookup(&self, client: IpAddr) -> Option<MacAddr> {
+        let mut neighbours = self.neighbours.lock().unwrap();
+        let now = Instant::now();
+        if neighbours
+            .as_ref()
+            .is_none_or(|(read, _)| now.duration_since(*read) >= ARP_REFRESH_INTERVAL)
+        {
+            *neighbours = Some((now, read_arp_table()));
+        }
+
+        let (_, neighbours) = neighbours.as_ref()?;
+        neighbours.get(&client.to_canonical()).copied()
+    }
+}
+
+/// Reads the complete entries of the ARP table. Without one, no client has a known MAC address.
+fn read_arp_table() -> HashMap<IpAddr, MacAddr> {
+    let Ok(content) = fs::read_to_string(ARP_TABLE) else {
+        return HashMap::new();
+    };
+
+    // IP address, HW type, Flags, HW address, Mask, Device
+    content
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let ip = fields.first()?.parse().ok()?;
+            let mac = fields.get(3)?.parse().ok()?;
+            // Incomplete entries have the 0x2 (`ATF_COM`) flag unset
+            let flags = u32::from_str_radix(fields.get(2)?.trim_start_matches("0x"), 16).ok()?;
+            (flags & 0x2 != 0).then_some((ip, mac))
+        })
+        .collect()
+}
+
+/// The groups of clients, each client belonging to the group matching it most specifically, if
+/// any. Clients outside of every group get the default blocklist.
+#[derive(Default)]
+pub struct Groups {
+    groups: Vec<Group>,
+    arp_table: ArpTable,
+}
+
+impl Groups {
+    pub fn new(groups: Vec<Group>) -> Self {
+        Self {
+            groups,
+            arp_table: ArpTable::default(),
+        }
+    }
+
+    /// The group of `client`, the first one listed winning a tie.
+    pub fn find(&self, client: IpAddr) -> Option<&Group> {
+        // The ARP table is only read when MAC addresses are of use
+        let mac = match self.groups.iter().any(Group::has_macs) {
+            true => self.arp_table.lookup(client),
+            false => None,
+        };
+
+        let mut found: Option<(&Group, u16)> = None;
+        for group in &self.groups {
+            if let Some(specificity) = group.specificity(client, mac) {
+                if found.is_none_or(|(_, best)| specificity > best) {
+                    found = Some((group, specificity));
+                }
+            }
+        }
+
+        found.map(|(group, _)| group)
+    }
+}
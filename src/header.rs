@@ -41,7 +41,7 @@ pub struct Header {
     is_authoritative: bool,
     /// 1 bit. Set to 1 if the message length exceeds 512 bytes. Traditionally a hint that the
     /// query can be reissued using TCP, for which the length limitation doesn't apply.
-    is_truncated: bool,
+    pub is_truncated: bool,
     /// 1 bit. Set by the sender of the request if the server should attempt to resolve the query
     /// recursively if it does not have an answer readily available.
     pub recursion_desired: bool,
@@ -0,0 +1,196 @@
+use std::fmt::{self, Formatter};
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use crate::packet::Packet;
+use crate::record::Record;
+use crate::result::{Error, Result};
+use crate::server::IpFamilies;
+
+/// A root name server along with the addresses it can be reached at.
+pub struct RootServer {
+    pub name: String,
+    pub addrs: Vec<IpAddr>,
+}
+
+/// The set of root name servers iteration starts from.
+pub struct RootHints {
+    pub servers: Vec<RootServer>,
+}
+
+impl RootHints {
+    /// Loads the root hints from a standard `named.root` file, as published by
+    /// [InterNIC](https://www.internic.net/domain/named.root).
+    pub fn from_file(path: &str) -> Result<Self> {
+        let content =
+            fs::read_to_string(path).map_err(|_| Error::InvalidRootHints(path.to_owned()))?;
+
+        Self::parse(&content).map_err(|e| match e {
+            Error::InvalidRootHints(reason) => Error::InvalidRootHints(format!("{path}: {reason}")),
+            e => e,
+        })
+    }
+
+    /// Parses the content of a `named.root` file. Only the `NS` records of the root zone and the
+    /// `A`/`AAAA` records of the servers they point to are taken into account.
+    ///
+    /// Each line follows the master file format of
+    /// [RFC1035#5.1](https://www.rfc-editor.org/rfc/rfc1035#section-5.1):
+    /// ```
+    /// <owner> [<TTL>] [<class>] <type> <RDATA>
+    /// ```
+    pub fn parse(content: &str) -> Result<Self> {
+        let mut servers: Vec<RootServer> = Vec::new();
+        let mut glue: Vec<(String, IpAddr)> = Vec::new();
+
+        for (i, line) in content.lines().enumerate() {
+            // Strip comments and skip empty lines
+            let line = line.split(';').next().unwrap_or_default();
+            let mut fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.is_empty() {
+                continue;
+            }
+
+            let owner = normalize_name(fields.remove(0));
+            // Both the TTL and the class are optional, skip over them
+            while let Some(field) = fields.first() {
+                if field.parse::<u32>().is_ok() || field.eq_ignore_ascii_case("IN") {
+                    fields.remove(0);
+                } else {
+                    break;
+                }
+            }
+
+            let (rtype, rdata) = match fields.as_slice() {
+                [rtype, rdata] => (rtype.to_uppercase(), *rdata),
+                _ => {
+                    return Err(Error::InvalidRootHints(format!(
+                        "line {}: malformed record",
+                        i + 1
+                    )))
+                }
+            };
+
+            let invalid_addr =
+                || Error::InvalidRootHints(format!("line {}: invalid address {rdata}", i + 1));
+            match rtype.as_str() {
+                "NS" if owner.is_empty() => servers.push(RootServer {
+                    name: normalize_name(rdata),
+                    addrs: Vec::new(),
+                }),
+                "A" => glue.push((
+                    owner,
+                    IpAddr::V4(rdata.parse().map_err(|_| invalid_addr())?),
+                )),
+                "AAAA" => glue.push((
+                    owner,
+                    IpAddr::V6(rdata.parse().map_err(|_| invalid_addr())?),
+                )),
+                _ => {}
+            }
+        }
+
+        for server in servers.iter_mut() {
+            server.addrs = glue
+                .iter()
+                .filter(|(name, _)| *name == server.name)
+                .map(|(_, addr)| *addr)
+                .collect();
+        }
+        servers.retain(|server| !server.addrs.is_empty());
+
+        if servers.is_empty() {
+            return Err(Error::InvalidRootHints(
+                "no root server with an address".to_owned(),
+            ));
+        }
+
+        Ok(Self { servers })
+    }
+
+    /// Builds the root hints out of the response to a priming query, that is the `NS` records of
+    /// the root zone in the answer section and their addresses in the additional section.
+    pub fn from_priming_response(response: &Packet) -> Option<Self> {
+        let mut servers: Vec<RootServer> = response
+            .answers
+            .iter()
+            .filter_map(|record| match record {
+                Record::NS { preamble, host } if preamble.name.is_empty() => Some(RootServer {
+                    name: host.to_owned(),
+                    addrs: Vec::new(),
+                }),
+                _ => None,
+            })
+            .collect();
+
+        for server in servers.iter_mut() {
+            server.addrs = response
+                .additionals
+                .iter()
+                .filter_map(|record| match record {
+                    Record::A { preamble, addr } if preamble.name == server.name => {
+                        Some(IpAddr::V4(*addr))
+                    }
+                    Record::AAAA { preamble, addr } if preamble.name == server.name => {
+                        Some(IpAddr::V6(*addr))
+                    }
+                    _ => None,
+                })
+                .collect();
+        }
+        servers.retain(|server| !server.addrs.is_empty());
+
+        if servers.is_empty() {
+            None
+        } else {
+            Some(Self { servers })
+        }
+    }
+
+    /// Every root server address usable with the given address `families`
+    pub fn addrs(&self, families: IpFamilies) -> Vec<IpAddr> {
+        self.servers
+            .iter()
+            .flat_map(|server| server.addrs.iter())
+            .filter(|addr| families.allows(addr))
+            .copied()
+            .collect()
+    }
+}
+
+impl Default for RootHints {
+    /// Falls back to *a.root-servers.net* only.
+    fn default() -> Self {
+        Self {
+            servers: vec![RootServer {
+                name: "a.root-servers.net".to_owned(),
+                addrs: vec![
+                    IpAddr::V4(Ipv4Addr::new(198, 41, 0, 4)),
+                    IpAddr::V6(Ipv6Addr::new(0x2001, 0x503, 0xba3e, 0, 0, 0, 0x2, 0x30)),
+                ],
+            }],
+        }
+    }
+}
+
+impl fmt::Display for RootHints {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "RootHints [")?;
+        for server in &self.servers {
+            write!(f, "\t{}:", server.name)?;
+            for addr in &server.addrs {
+                write!(f, " {}", addr)?;
+            }
+            writeln!(f)?;
+        }
+        writeln!(f, "]")?;
+
+        Ok(())
+    }
+}
+
+/// Lowercases a domain name and removes its trailing dot, to match the names read from packets.
+/// The root zone `.` thus becomes an empty string.
+fn normalize_name(name: &str) -> String {
+    name.trim_end_matches('.').to_lowercase()
+}
@@ -0,0 +1,379 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+/// Starts every compiled index file, the last byte being the version of the format
+const MAGIC: &[u8; 8] = b"BRTZIDX\x01";
+
+/// A node of the trie, whose children are the nodes `first_child..first_child + child_count`,
+/// sorted by label. Nodes without children end a listed domain.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct Node {
+    /// Index of the label of the node in the label table
+    label: u32,
+    first_child: u32,
+    child_count: u32,
+}
+
+/// A set of domains, each one standing for its subdomains as well, compiled into a trie of their
+/// labels read from the right (`com`, then `example`, then `ads`). Labels are stored once
+/// whatever the number of domains they appear in, and nodes are plain indices, so that large
+/// lists take a fraction of the memory of a set of strings. Looking a name up takes a binary
+/// search per label.
+///
+/// A domain listed along with one of its parents is dropped, the parent covering it already.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct DomainIndex {
+    /// Domains in the index
+    len: u32,
+    /// The labels, one after the other
+    label_bytes: Vec<u8>,
+    /// Where each label ends in `label_bytes`, the previous one ending where it starts
+    label_ends: Vec<u32>,
+    /// The nodes, breadth first, the root coming first
+    nodes: Vec<Node>,
+}
+
+impl DomainIndex {
+    /// Compiles `domains`, which must be normalized (lowercase, without the trailing dot).
+    pub fn build<'a>(domains: impl IntoIterator<Item = &'a str>) -> Self {
+        let mut domains: Vec<&str> = domains
+            .into_iter()
+            .filter(|domain| !domain.is_empty())
+            .collect();
+        // Parents come right before their subdomains, and siblings sort by label
+        domains.sort_unstable_by(|a, b| a.rsplit('.').cmp(b.rsplit('.')));
+        domains.dedup();
+
+        let mut index = Self::default();
+        let mut label_ids: HashMap<&str, u32> = HashMap::new();
+        index.nodes.push(Node::default());
+
+        // Sorting keeps the domains under a node next to each other: `(node, domains, depth)`
+        // are the nodes left to expand, along with their domains and the number of labels they
+        // share
+        let mut pending = VecDeque::from([(0, &domains[..], 0)]);
+        while let Some((node, domains, depth)) = pending.pop_front() {
+            let first_child = index.nodes.len();
+            let mut rest = domains;
+            while let Some(first) = rest.first() {
+                // The domain of the node is listed, and covers the ones below it
+                let Some(label) = label_at(first, depth) else {
+                    index.len += 1;
+                    break;
+                };
+                let count = rest
+                    .iter()
+                    .position(|domain| label_at(domain, depth) != Some(label))
+                    .unwrap_or(rest.len());
+
+                let next_id = label_ids.len() as u32;
+                let id = *label_ids.entry(label).or_insert_with(|| {
+                    index.label_bytes.extend_from_slice(label.as_bytes());
+                    index.label_ends.push(index.label_bytes.len() as u32);
+                    next_id
+                });
+                index.nodes.push(Node {
+                    label: id,
+                    ..Node::default()
+                });
+                pending.push_back((index.nodes.len() - 1, &rest[..count], depth + 1));
+                rest = &rest[count..];
+            }
+
+            index.nodes[node].first_child = first_child as u32;
+            index.nodes[node].child_count = (index.nodes.len() - first_child) as u32;
+        }
+
+        index
+    }
+
+    /// Number of domains in the index, those covered by a parent aside.
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    fn label(&self, id: u32) -> &[u8] {
+        let start = match id {
+            0 => 0,
+            _ => self.label_ends[id as usize - 1] as usize,
+        };
+        &self.label_bytes[start..self.label_ends[id as usize] as usize]
+    }
+
+    /// Returns the listed domain `qname` falls under, if any: `qname` itself or one of its
+    /// parents, as a suffix of `qname`, which must be normalized.
+    pub fn find<'q>(&self, qname: &'q str) -> Option<&'q str> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let mut node = self.nodes[0];
+        // `qname[start..]` is the domain of `node`, and `rest` what is left to look up
+        let mut start = qname.len();
+        let mut rest = qname;
+        loop {
+            if node.child_count == 0 {
+                return Some(&qname[start..]);
+            }
+            if rest.is_empty() {
+                return None;
+            }
+
+            let (remaining, label) = match rest.rsplit_once('.') {
+                Some((remaining, label)) => (remaining, label),
+                None => ("", rest),
+            };
+            start -= label.len() + usize::from(start < qname.len());
+            rest = remaining;
+
+            let first = node.first_child as usize;
+            let children = &self.nodes[first..first + node.child_count as usize];
+            let i = children
+                .binary_search_by(|child| self.label(child.label).cmp(label.as_bytes()))
+                .ok()?;
+            node = children[i];
+        }
+    }
+
+    /// Writes the index to `path`, along with `stamp`, which `load` expects back.
+    pub fn save(&self, path: &Path, stamp: &Stamp) -> std::io::Result<()> {
+        let temporary = path.with_extension("tmp");
+        let mut out = BufWriter::new(File::create(&temporary)?);
+
+        out.write_all(MAGIC)?;
+        write_bytes(&mut out, stamp.source.as_bytes())?;
+        out.write_all(&stamp.size.to_le_bytes())?;
+        out.write_all(&stamp.modified.to_le_bytes())?;
+        write_u32(&mut out, self.len)?;
+        write_bytes(&mut out, &self.label_bytes)?;
+        write_u32(&mut out, self.label_ends.len() as u32)?;
+        for end in &self.label_ends {
+            write_u32(&mut out, *end)?;
+        }
+        write_u32(&mut out, self.nodes.len() as u32)?;
+        for node in &self.nodes {
+            write_u32(&mut out, node.label)?;
+            write_u32(&mut out, node.first_child)?;
+            write_u32(&mut out, node.child_count)?;
+        }
+        out.into_inner()?.sync_all()?;
+
+        fs::rename(temporary, path)
+    }
+
+    /// Reads the index written at `path`, unless it was compiled from anything else than
+    /// `stamp` describes, or is damaged.
+    pub fn load(path: &Path, stamp: &Stamp) -> Option<Self> {
+        let mut input = BufReader::new(File::open(path).ok()?);
+
+        let mut magic = [0; MAGIC.len()];
+        input.read_exact(&mut magic).ok()?;
+        if &magic != MAGIC
+            || read_bytes(&mut input)? != stamp.source.as_bytes()
+            || read_u64(&mut input)? != stamp.size
+            || read_u64(&mut input)? != stamp.modified
+        {
+            return None;
+        }
+
+        let len = read_u32(&mut input)?;
+        let label_bytes = read_bytes(&mut input)?;
+        let label_ends = (0..read_u32(&mut input)?)
+            .map(|_| read_u32(&mut input))
+            .collect::<Option<Vec<u32>>>()?;
+        let nodes = (0..read_u32(&mut input)?)
+            .map(|_| {
+                Some(Node {
+                    label: read_u32(&mut input)?,
+                    first_child: read_u32(&mut input)?,
+                    child_count: read_u32(&mut input)?,
+                })
+            })
+            .collect::<Option<Vec<Node>>>()?;
+
+        let index = Self {
+            len,
+            label_bytes,
+            label_ends,
+            nodes,
+        };
+        index.is_valid().then_some(index)
+    }
+
+    /// Whether every index of the nodes and labels is in bounds, so that lookups cannot panic, and
+    /// the root has children unless the index is empty, lest every name be found.
+    fn is_valid(&self) -> bool {
+        let labels_valid = self.label_ends.windows(2).all(|pair| pair[0] <= pair[1])
+            && self
+                .label_ends
+                .last()
+                .is_none_or(|end| *end as usize == self.label_bytes.len());
+        let nodes_valid = self
+            .nodes
+            .first()
+            .is_some_and(|root| self.len == 0 || root.child_count > 0)
+            && self.nodes.iter().enumerate().all(|(i, node)| {
+                (i == 0 || (node.label as usize) < self.label_ends.len())
+                    && node.first_child as usize + node.child_count as usize <= self.nodes.len()
+            });
+
+        labels_valid && nodes_valid
+    }
+}
+
+/// The label of `domain` at `depth`, counting from the right.
+fn label_at(domain: &str, depth: usize) -> Option<&str> {
+    domain.rsplit('.').nth(depth)
+}
+
+fn write_u32(out: &mut impl Write, value: u32) -> std::io::Result<()> {
+    out.write_all(&value.to_le_bytes())
+}
+
+fn write_bytes(out: &mut impl Write, bytes: &[u8]) -> std::io::Result<()> {
+    write_u32(out, bytes.len() as u32)?;
+    out.write_all(bytes)
+}
+
+fn read_u32(input: &mut impl Read) -> Option<u32> {
+    let mut bytes = [0; 4];
+    input.read_exact(&mut bytes).ok()?;
+    Some(u32::from_le_bytes(bytes))
+}
+
+fn read_u64(input: &mut impl Read) -> Option<u64> {
+    let mut bytes = [0; 8];
+    input.read_exact(&mut bytes).ok()?;
+    Some(u64::from_le_bytes(bytes))
+}
+
+fn read_bytes(input: &mut impl Read) -> Option<Vec<u8>> {
+    let len = read_u32(input)? as usize;
+    let mut bytes = Vec::new();
+    input.take(len as u64).read_to_end(&mut bytes).ok()?;
+    (bytes.len() == len).then_some(bytes)
+}
+
+/// Identifies the version of a list an index was compiled from.
+pub struct Stamp {
+    source: String,
+    size: u64,
+    /// Modification time, in nanoseconds since the epoch
+    modified: u64,
+}
+
+impl Stamp {
+    pub fn of(path: &str) -> Option<Self> {
+        let metadata = fs::metadata(path).ok()?;
+        let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+
+        Some(Self {
+            source: path.to_owned(),
+            size: metadata.len(),
+            modified: modified.as_nanos() as u64,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn stamp() -> Stamp {
+        Stamp {
+            source: "/etc/barthez/list.txt".to_owned(),
+            size: 42,
+            modified: 1_700_000_000_000_000_000,
+        }
+    }
+
+    fn index_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("barthez-{}-{name}.idx", std::process::id()))
+    }
+
+    /// Saves `index`, then rewrites the file with `damage`.
+    fn damaged(name: &str, index: &DomainIndex, damage: impl FnOnce(&mut Vec<u8>)) -> PathBuf {
+        let path = index_path(name);
+        index.save(&path, &stamp()).unwrap();
+        let mut bytes = fs::read(&path).unwrap();
+        damage(&mut bytes);
+        fs::write(&path, bytes).unwrap();
+        path
+    }
+
+    fn example() -> DomainIndex {
+        DomainIndex::build(["example.com", "ads.example.org", "x.ads.example.org", "net"])
+    }
+
+    #[test]
+    fn finds_listed_domains_and_subdomains() {
+        let index = example();
+
+        assert_eq!(index.len(), 3);
+        assert_eq!(index.find("example.com"), Some("example.com"));
+        assert_eq!(index.find("a.b.example.com"), Some("example.com"));
+        assert_eq!(index.find("x.ads.example.org"), Some("ads.example.org"));
+        assert_eq!(index.find("example.net"), Some("net"));
+        assert_eq!(index.find("example.org"), None);
+        assert_eq!(index.find("com"), None);
+        assert_eq!(index.find("myexample.com"), None);
+        assert_eq!(index.find(""), None);
+        assert_eq!(DomainIndex::build([]).find("example.com"), None);
+    }
+
+    #[test]
+    fn round_trips_through_a_file() {
+        let index = example();
+        let path = index_path("round-trip");
+        index.save(&path, &stamp()).unwrap();
+
+        let loaded = DomainIndex::load(&path, &stamp());
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded, Some(index));
+    }
+
+    #[test]
+    fn rejects_an_index_of_another_list() {
+        let path = index_path("stale");
+        example().save(&path, &stamp()).unwrap();
+
+        let modified = Stamp {
+            size: 43,
+            ..stamp()
+        };
+        let loaded = DomainIndex::load(&path, &modified);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded, None);
+    }
+
+    #[test]
+    fn rejects_damaged_files() {
+        let index = DomainIndex::build(["com"]);
+        // The header, then `len`, the label bytes and the label table
+        let header = MAGIC.len() + 4 + stamp().source.len() + 8 + 8;
+        let labels = header + 4 + 4 + "com".len();
+        let root = labels + 4 + 4 + 4;
+
+        let truncated = damaged("truncated", &index, |bytes| bytes.truncate(bytes.len() - 1));
+        // No label left for the nodes to point to
+        let no_labels = damaged("no-labels", &index, |bytes| {
+            bytes[header + 4..header + 8].fill(0);
+            bytes.splice(header + 8..labels + 4 + 4, [0; 4]);
+        });
+        // Domains, but none under the root, which would find every name
+        let no_root_children = damaged("no-root-children", &index, |bytes| {
+            bytes[root + 8..root + 12].fill(0);
+        });
+
+        for path in [truncated, no_labels, no_root_children] {
+            let loaded = DomainIndex::load(&path, &stamp());
+            fs::remove_file(&path).unwrap();
+            assert_eq!(loaded, None, "{}", path.display());
+        }
+    }
+}
@@ -0,0 +1,155 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use http::{header, Method, Request, Response, StatusCode};
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::Incoming;
+use hyper::service::service_fn;
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use hyper_util::server::conn::auto;
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::api;
+use crate::globals::{API_MAX_BODY_SIZE, CLIENT_IDLE_TIMEOUT};
+use crate::listener::{accept_loop, bind, status};
+use crate::log;
+use crate::metrics;
+use crate::result::{Error, Result};
+use crate::server::Server;
+
+/// The media type of the Prometheus text exposition format
+const PROMETHEUS_TEXT: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// The media type of the requests and responses of the management API
+const JSON: &str = "application/json";
+
+/// The web dashboard, a single page using the management API
+const DASHBOARD: &str = include_str!("dashboard.html");
+
+pub struct HttpListenerConfig {
+    /// Address to listen on, which had better not be reachable from untrusted networks
+    pub addr: SocketAddr,
+}
+
+/// Serves the management endpoints over plain HTTP: `/metrics` exposes the metrics of the server
+/// to Prometheus, `/api/` the management API (see `api::handle`), and `/` a web dashboard built on
+/// top of it. Nothing is authenticated.
+pub struct HttpListener {
+    listener: TcpListener,
+    server: Arc<Server>,
+}
+
+impl HttpListener {
+    pub fn bind(config: HttpListenerConfig, server: Arc<Server>) -> Result<Self> {
+        Ok(Self {
+            listener: bind(config.addr)?,
+            server,
+        })
+    }
+
+    /// Starts serving clients, see `accept_loop`.
+    pub fn spawn(self) {
+        let server = self.server;
+
+        accept_loop(self.listener, "HTTP", move |stream, _| {
+            serve(stream, Arc::clone(&server))
+        });
+    }
+}
+
+/// Answers the requests of a single client connection until it closes.
+async fn serve(stream: TcpStream, server: Arc<Server>) -> Result<()> {
+    let service = service_fn(move |request| handle(request, Arc::clone(&server)));
+
+    let mut builder = auto::Builder::new(TokioExecutor::new());
+    builder
+        .http1()
+        .timer(TokioTimer::new())
+        .header_read_timeout(CLIENT_IDLE_TIMEOUT);
+
+    builder
+        .serve_connection(TokioIo::new(stream), service)
+        .await
+        .map_err(|e| Error::HttpFailed(e.to_string()))
+}
+
+async fn handle(
+    request: Request<Incoming>,
+    server: Arc<Server>,
+) -> std::result::Result<Response<Full<Bytes>>, Infallible> {
+    let path = request.uri().path().to_owned();
+    if let Some(path) = path.strip_prefix("/api") {
+        if path.is_empty() || path.starts_with('/') {
+            return Ok(handle_api(request, path.to_owned(), server).await);
+        }
+    }
+
+    if request.method() != Method::GET && matches!(path.as_str(), "/" | "/metrics") {
+        return Ok(status(StatusCode::METHOD_NOT_ALLOWED));
+    }
+    let (content_type, body) = match path.as_str() {
+        "/" => (
+            "text/html; charset=utf-8",
+            Bytes::from_static(DASHBOARD.as_bytes()),
+        ),
+        "/metrics" => (PROMETHEUS_TEXT, Bytes::from(metrics::render(&server))),
+        _ => return Ok(status(StatusCode::NOT_FOUND)),
+    };
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, content_type)
+        .body(Full::new(body))
+        .unwrap_or_else(|_| status(StatusCode::INTERNAL_SERVER_ERROR)))
+}
+
+async fn handle_api(
+    request: Request<Incoming>,
+    path: String,
+    server: Arc<Server>,
+) -> Response<Full<Bytes>> {
+    let method = request.method().clone();
+    let query = request.uri().query().map(str::to_owned);
+
+    // Requiring JSON bodies keeps browsers from sending cross-site requests without a preflight
+    let body = if matches!(method, Method::POST | Method::PUT) {
+        let content_type = request.headers().get(header::CONTENT_TYPE);
+        let is_json = content_type
+            .and_then(|content_type| content_type.to_str().ok())
+            .is_some_and(|content_type| content_type.split(';').next() == Some(JSON));
+        if !is_json {
+            return status(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        }
+        match Limited::new(request.into_body(), API_MAX_BODY_SIZE)
+            .collect()
+            .await
+        {
+            Ok(body) => body.to_bytes(),
+            Err(_) => return status(StatusCode::PAYLOAD_TOO_LARGE),
+        }
+    } else {
+        Bytes::new()
+    };
+
+    // Editing lists writes to disk, so it's done out of the runtime's worker threads
+    let reply = tokio::task::spawn_blocking(move || {
+        api::handle(&server, &method, &path, query.as_deref(), &body)
+    })
+    .await;
+
+    let (code, value) = match reply {
+        Ok(reply) => reply,
+        Err(e) => {
+            log::error!("Failed answering API request: {}", e);
+            return status(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    Response::builder()
+        .status(code)
+        .header(header::CONTENT_TYPE, JSON)
+        .body(Full::new(Bytes::from(value.to_string())))
+        .unwrap_or_else(|_| status(StatusCode::INTERNAL_SERVER_ERROR))
+}
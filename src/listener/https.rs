@@ -0,0 +1,261 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as BASE64URL;
+use base64::Engine;
+use bytes::Bytes;
+use http::{header, Method, Request, Response, StatusCode};
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::Incoming;
+use hyper::service::service_fn;
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use hyper_util::server::conn::auto;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::TlsAcceptor;
+
+use crate::globals::CLIENT_IDLE_TIMEOUT;
+use crate::listener::{accept_loop, answer, bind, handshake, server_config, status};
+use crate::log;
+use crate::packet::{Packet, PacketBuffer};
+use crate::result::{Error, Result};
+use crate::server::Server;
+
+/// The media type of DNS messages carried over HTTP
+const DNS_MESSAGE: &str = "application/dns-message";
+
+/// Path of the endpoint queries are sent to
+const DNS_QUERY_PATH: &str = "/dns-query";
+
+/// Largest request body accepted, that of the largest DNS message
+const MAX_BODY_SIZE: usize = 65_535;
+
+pub struct HttpsListenerConfig {
+    /// Address to listen on, usually on port 443
+    pub addr: SocketAddr,
+    /// PEM file holding the certificate chain presented to clients
+    pub cert_file: String,
+    /// PEM file holding the private key of the certificate
+    pub key_file: String,
+}
+
+/// Serves DNS over HTTPS clients, as specified by
+/// [RFC8484](https://www.rfc-editor.org/rfc/rfc8484), on the `/dns-query` endpoint. Both
+/// HTTP/1.1 and HTTP/2 are spoken, and queries go through the same pipeline as the ones received
+/// over UDP, blocking included.
+pub struct HttpsListener {
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+    server: Arc<Server>,
+}
+
+impl HttpsListener {
+    pub fn bind(config: HttpsListenerConfig, server: Arc<Server>) -> Result<Self> {
+        let tls_config = server_config(
+            &config.cert_file,
+            &config.key_file,
+            vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+        )?;
+
+        Ok(Self {
+            listener: bind(config.addr)?,
+            acceptor: TlsAcceptor::from(Arc::new(tls_config)),
+            server,
+        })
+    }
+
+    /// Starts serving clients, see `accept_loop`.
+    pub fn spawn(self) {
+        let acceptor = self.acceptor;
+        let server = self.server;
+
+        accept_loop(self.listener, "HTTPS", move |stream, client| {
+            serve(stream, client, acceptor.clone(), Arc::clone(&server))
+        });
+    }
+}
+
+/// Answers the requests of a single client connection until it closes.
+async fn serve(
+    stream: TcpStream,
+    client: SocketAddr,
+    acceptor: TlsAcceptor,
+    server: Arc<Server>,
+) -> Result<()> {
+    let stream = handshake(&acceptor, stream).await?;
+
+    let service = service_fn(move |request| handle(request, client, Arc::clone(&server)));
+
+    let mut builder = auto::Builder::new(TokioExecutor::new());
+    builder
+        .http1()
+        .timer(TokioTimer::new())
+        .header_read_timeout(CLIENT_IDLE_TIMEOUT);
+    builder
+        .http2()
+        .timer(TokioTimer::new())
+        .keep_alive_interval(CLIENT_IDLE_TIMEOUT)
+        .keep_alive_timeout(CLIENT_IDLE_TIMEOUT);
+
+    builder
+        .serve_connection(TokioIo::new(stream), service)
+        .await
+        .map_err(|e| Error::HttpFailed(e.to_string()))
+}
+
+async fn handle(
+    request: Request<Incoming>,
+    client: SocketAddr,
+    server: Arc<Server>,
+) -> std::result::Result<Response<Full<Bytes>>, Infallible> {
+    if request.uri().path() != DNS_QUERY_PATH {
+        return Ok(status(StatusCode::NOT_FOUND));
+    }
+
+    let message = match *request.method() {
+        // The query is the base64url-encoded `dns` parameter
+        Method::GET => {
+            let encoded = request.uri().query().and_then(|query| {
+                query
+                    .split('&')
+                    .find_map(|param| param.strip_prefix("dns="))
+            });
+            match encoded.and_then(|encoded| BASE64URL.decode(encoded.trim_end_matches('=')).ok()) {
+                Some(message) => message,
+                None => return Ok(status(StatusCode::BAD_REQUEST)),
+            }
+        }
+        // The query is the body
+        Method::POST => {
+            let content_type = request.headers().get(header::CONTENT_TYPE);
+            if content_type.is_none_or(|content_type| content_type != DNS_MESSAGE) {
+                return Ok(status(StatusCode::UNSUPPORTED_MEDIA_TYPE));
+            }
+            match Limited::new(request.into_body(), MAX_BODY_SIZE)
+                .collect()
+                .await
+            {
+                Ok(body) => body.to_bytes().to_vec(),
+                Err(_) => return Ok(status(StatusCode::PAYLOAD_TOO_LARGE)),
+            }
+        }
+        _ => return Ok(status(StatusCode::METHOD_NOT_ALLOWED)),
+    };
+
+    let query = match PacketBuffer::try_from(message.as_slice()).and_then(Packet::try_from) {
+        Ok(query) => query,
+        Err(e) => {
+            log::warning!("Invalid query from {}: {}", client, e);
+            return Ok(status(StatusCode::BAD_REQUEST));
+        }
+    };
+
+    let Some((bytes, min_ttl)) = answer(server, query, client).await else {
+        return Ok(status(StatusCode::INTERNAL_SERVER_ERROR));
+    };
+
+    // HTTP caches must not keep the response longer than its records are valid, see
+    // [RFC8484#5.1](https://www.rfc-editor.org/rfc/rfc8484#section-5.1).
+    let mut builder = Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, DNS_MESSAGE);
+    if let Some(ttl) = min_ttl {
+        builder = builder.header(header::CACHE_CONTROL, format!("max-age={ttl}"));
+    }
+
+    Ok(builder
+        .body(Full::new(Bytes::from(bytes)))
+        .unwrap_or_else(|_| status(StatusCode::INTERNAL_SERVER_ERROR)))
+}
+
+#[cfg(test)]
+mod tests {
+    use rustls::pki_types::ServerName;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::TlsConnector;
+
+    use crate::record::Record;
+    use crate::runtime::runtime;
+    use crate::testing::{blocking_server, client_config, data_file, query, SERVER_NAME};
+    use crate::upstream::{DohMethod, HttpsUpstream, HttpsUpstreamConfig, Upstream};
+
+    use super::*;
+
+    /// Starts a listener answering with `blocking_server`, and returns its address.
+    fn listen() -> SocketAddr {
+        let config = HttpsListenerConfig {
+            addr: SocketAddr::from(([127, 0, 0, 1], 0)),
+            cert_file: data_file("server.pem"),
+            key_file: data_file("server.key"),
+        };
+        let listener = HttpsListener::bind(config, blocking_server()).unwrap();
+        let addr = listener.listener.local_addr().unwrap();
+        listener.spawn();
+        addr
+    }
+
+    /// Sends `request` over HTTP/1.1 to the listener at `addr`, and returns the status code of
+    /// the response.
+    fn status_of(addr: SocketAddr, request: &str) -> u16 {
+        let connector = TlsConnector::from(Arc::new(client_config()));
+        runtime().block_on(async {
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let server_name = ServerName::try_from(SERVER_NAME).unwrap();
+            let mut stream = connector.connect(server_name, stream).await.unwrap();
+            stream.write_all(request.as_bytes()).await.unwrap();
+
+            // The status line starts with `HTTP/1.1 <code>`
+            let mut status_line = [0; 12];
+            stream.read_exact(&mut status_line).await.unwrap();
+            std::str::from_utf8(&status_line[9..])
+                .unwrap()
+                .parse()
+                .unwrap()
+        })
+    }
+
+    #[test]
+    fn answers_queries_with_either_method() {
+        let addr = listen();
+
+        for method in [DohMethod::Post, DohMethod::Get] {
+            let client = HttpsUpstream::new(HttpsUpstreamConfig {
+                url: format!("https://{SERVER_NAME}:{}{DNS_QUERY_PATH}", addr.port()),
+                addr: Some(addr.ip()),
+                method,
+                ca_file: Some(data_file("ca.pem")),
+                spki_pins: Vec::new(),
+            })
+            .unwrap();
+            let response = client.query(&query(7, "blocked.test")).unwrap();
+            assert_eq!(response.header.id, 7);
+            assert!(matches!(
+                response.answers[..],
+                [Record::A { addr, .. }] if addr.is_unspecified()
+            ));
+        }
+    }
+
+    #[test]
+    fn rejects_requests_that_are_not_queries() {
+        let addr = listen();
+        let request = |line: &str, headers: &str| {
+            status_of(
+                addr,
+                &format!("{line}\r\nHost: {SERVER_NAME}\r\n{headers}Connection: close\r\n\r\n"),
+            )
+        };
+
+        assert_eq!(request("GET /resolve?dns=AAAA HTTP/1.1", ""), 404);
+        assert_eq!(request("GET /dns-query HTTP/1.1", ""), 400);
+        assert_eq!(request("GET /dns-query?dns=AAAA HTTP/1.1", ""), 400);
+        assert_eq!(
+            request(
+                "POST /dns-query HTTP/1.1",
+                "Content-Type: text/plain\r\nContent-Length: 0\r\n"
+            ),
+            415
+        );
+        assert_eq!(request("PUT /dns-query HTTP/1.1", ""), 405);
+    }
+}
@@ -0,0 +1,293 @@
+mod http;
+mod https;
+mod tcp;
+mod tls;
+
+pub use http::{HttpListener, HttpListenerConfig};
+pub use https::{HttpsListener, HttpsListenerConfig};
+pub use tcp::{TcpDnsListener, TcpDnsListenerConfig};
+pub use tls::{TlsListener, TlsListenerConfig};
+
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use ::http::{Response, StatusCode};
+use bytes::Bytes;
+use http_body_util::Full;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::ServerConfig;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, Semaphore};
+use tokio::time::timeout;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+use crate::globals::{CLIENT_IDLE_TIMEOUT, MAX_CLIENT_CONNECTIONS, MAX_CONNECTION_QUERIES};
+use crate::log;
+use crate::packet::{Packet, PacketBuffer};
+use crate::result::{Error, Result};
+use crate::runtime::runtime;
+use crate::server::{self, Server};
+
+/// Builds the TLS configuration of a listener, presenting the certificate chain of `cert_file`
+/// signed by the private key of `key_file`, both PEM files. `alpn` lists the application
+/// protocols to negotiate.
+fn server_config(cert_file: &str, key_file: &str, alpn: Vec<Vec<u8>>) -> Result<ServerConfig> {
+    let certs = CertificateDer::pem_file_iter(cert_file)
+        .and_then(|certs| certs.collect::<std::result::Result<Vec<_>, _>>())
+        .map_err(|e| Error::TlsFailed(format!("{cert_file}: {e}")))?;
+    let key = PrivateKeyDer::from_pem_file(key_file)
+        .map_err(|e| Error::TlsFailed(format!("{key_file}: {e}")))?;
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut config = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|e| Error::TlsFailed(e.to_string()))?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| Error::TlsFailed(format!("{cert_file}: {e}")))?;
+    config.alpn_protocols = alpn;
+
+    Ok(config)
+}
+
+/// Binds a TCP listener on `addr`, from outside of the runtime.
+fn bind(addr: SocketAddr) -> Result<TcpListener> {
+    runtime()
+        .block_on(TcpListener::bind(addr))
+        .map_err(|e| Error::ListenFailed(format!("{addr}: {e}")))
+}
+
+/// Performs the TLS handshake of a client connection, which must not take longer than
+/// `CLIENT_IDLE_TIMEOUT`.
+async fn handshake(acceptor: &TlsAcceptor, stream: TcpStream) -> Result<TlsStream<TcpStream>> {
+    let _ = stream.set_nodelay(true);
+
+    timeout(CLIENT_IDLE_TIMEOUT, acceptor.accept(stream))
+        .await
+        .map_err(|_| Error::TlsFailed("handshake timed out".to_owned()))?
+        .map_err(|e| Error::TlsFailed(e.to_string()))
+}
+
+/// Accepts connections on `listener` in the background, for as long as the process runs, each
+/// of them being handed to `serve` in a task of its own. No more than `MAX_CLIENT_CONNECTIONS`
+/// are served at once: connections past the limit are closed right away, rather than left
+/// waiting.
+fn accept_loop<F, Fut>(listener: TcpListener, protocol: &'static str, serve: F)
+where
+    F: Fn(TcpStream, SocketAddr) -> Fut + Send + 'static,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    let connections = Arc::new(Semaphore::new(MAX_CLIENT_CONNECTIONS));
+
+    runtime().spawn(async move {
+        loop {
+            let (stream, client) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    log::warning!("Failed accepting a {} connection: {}", protocol, e);
+                    continue;
+                }
+            };
+
+            let Ok(permit) = Arc::clone(&connections).try_acquire_owned() else {
+                log::warning!(
+                    "Refusing {} connection from {}: too many connections",
+                    protocol,
+                    client
+                );
+                continue;
+            };
+
+            let connection = serve(stream, client);
+            runtime().spawn(async move {
+                if let Err(e) = connection.await {
+                    log::warning!("{} connection from {} failed: {}", protocol, client, e);
+                }
+                drop(permit);
+            });
+        }
+    });
+}
+
+/// Answers the queries of a client connection, plain or TLS, until it closes or goes idle. Every
+/// message is prefixed with its length, as specified by
+/// [RFC7766](https://www.rfc-editor.org/rfc/rfc7766).
+///
+/// Queries are answered concurrently as they arrive, up to `MAX_CONNECTION_QUERIES` at once, and
+/// responses are written back in whatever order they are ready. Connections idle for
+/// `CLIENT_IDLE_TIMEOUT`, or whose client doesn't read its responses for as long, are closed.
+async fn serve_messages<S>(stream: S, client: SocketAddr, server: Arc<Server>)
+where
+    S: AsyncRead + AsyncWrite,
+{
+    let (reader, writer) = tokio::io::split(stream);
+    let (sender, receiver) = mpsc::channel::<Vec<u8>>(MAX_CONNECTION_QUERIES);
+
+    // Once the client is done sending queries, the responses still being resolved are written
+    // before the connection is closed.
+    tokio::join!(
+        read_queries(reader, client, server, sender),
+        write_responses(writer, receiver)
+    );
+}
+
+/// Reads queries until the connection closes or goes idle, and hands their responses to the
+/// writer once they are resolved. A query is only read once there is room for it among the ones
+/// being answered.
+async fn read_queries<R>(
+    mut reader: R,
+    client: SocketAddr,
+    server: Arc<Server>,
+    responses: mpsc::Sender<Vec<u8>>,
+) where
+    R: AsyncRead + Unpin,
+{
+    let in_flight = Arc::new(Semaphore::new(MAX_CONNECTION_QUERIES));
+
+    loop {
+        let Ok(permit) = Arc::clone(&in_flight).acquire_owned().await else {
+            break;
+        };
+        // The writer gave up on the client
+        if responses.is_closed() {
+            break;
+        }
+
+        // Every message is prefixed with its length on 2 bytes
+        let len = match timeout(CLIENT_IDLE_TIMEOUT, reader.read_u16()).await {
+            Ok(Ok(len)) => len as usize,
+            _ => break,
+        };
+        let mut message = vec![0; len];
+        match timeout(CLIENT_IDLE_TIMEOUT, reader.read_exact(&mut message)).await {
+            Ok(Ok(_)) => {}
+            _ => break,
+        }
+
+        let request = match PacketBuffer::try_from(message.as_slice()).and_then(Packet::try_from) {
+            Ok(request) => request,
+            Err(e) => {
+                log::warning!("Invalid query from {}: {}", client, e);
+                break;
+            }
+        };
+
+        let server = Arc::clone(&server);
+        let responses = responses.clone();
+        runtime().spawn(async move {
+            if let Some((response, _)) = answer(server, request, client).await {
+                let mut frame = Vec::with_capacity(response.len() + 2);
+                frame.extend_from_slice(&(response.len() as u16).to_be_bytes());
+                frame.extend_from_slice(&response);
+                let _ = responses.send(frame).await;
+            }
+            drop(permit);
+        });
+    }
+}
+
+/// Writes the responses it is handed, until every query was answered or the client stops
+/// reading them for `CLIENT_IDLE_TIMEOUT`.
+async fn write_responses<W>(mut writer: W, mut responses: mpsc::Receiver<Vec<u8>>)
+where
+    W: AsyncWrite + Unpin,
+{
+    while let Some(frame) = responses.recv().await {
+        let written = timeout(CLIENT_IDLE_TIMEOUT, async {
+            writer.write_all(&frame).await?;
+            writer.flush().await
+        })
+        .await;
+        if !matches!(written, Ok(Ok(()))) {
+            return;
+        }
+    }
+    let _ = writer.shutdown().await;
+}
+
+/// Answers the `request` of `client`, and returns the response encoded along with its lowest
+/// TTL. Resolving blocks, so it's done out of the runtime's worker threads.
+async fn answer(
+    server: Arc<Server>,
+    request: Packet,
+    client: SocketAddr,
+) -> Option<(Vec<u8>, Option<u32>)> {
+    let answered = tokio::task::spawn_blocking(move || {
+        let mut response = server.answer(request, client);
+        (encode(&mut response, client), response.min_ttl())
+    })
+    .await;
+
+    answered
+        .inspect_err(|e| log::error!("Failed answering {}: {}", client, e))
+        .ok()
+}
+
+/// Encodes `response` to a query of `client`. A response that cannot be encoded is replaced with
+/// an empty `SERVFAIL` one, so that the client isn't left without an answer.
+fn encode(response: &mut Packet, client: SocketAddr) -> Vec<u8> {
+    match response.to_bytes() {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            log::error!("Failed encoding response to {}: {}", client, e);
+            *response = server::failed(response);
+            // Without its question, should the question be what cannot be encoded
+            response.to_bytes().unwrap_or_else(|_| {
+                response.questions.clear();
+                response.header.question_count = 0;
+                response.to_bytes().unwrap_or_default()
+            })
+        }
+    }
+}
+
+/// An empty response with the given status.
+fn status(status: StatusCode) -> Response<Full<Bytes>> {
+    let mut response = Response::new(Full::new(Bytes::new()));
+    *response.status_mut() = status;
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::result::ResultCode;
+    use crate::testing::{blocking_server, framed, query};
+
+    use super::*;
+
+    #[test]
+    fn stops_reading_queries_whose_responses_are_not_read() {
+        let (mut client, stream) = tokio::io::duplex(1024);
+        let addr = SocketAddr::from(([127, 0, 0, 1], 5353));
+        runtime().spawn(serve_messages(stream, addr, blocking_server()));
+
+        runtime().block_on(async {
+            let frame = framed(&query(1, "blocked.test").to_bytes().unwrap());
+            let mut sent = 0;
+            while sent < 1000 {
+                match timeout(Duration::from_millis(500), client.write_all(&frame)).await {
+                    Ok(Ok(())) => sent += 1,
+                    _ => break,
+                }
+            }
+            assert!(sent < 1000);
+
+            // The queries read meanwhile are answered once the client reads their responses
+            for _ in 0..sent {
+                let len = client.read_u16().await.unwrap();
+                let mut message = vec![0; len as usize];
+                client.read_exact(&mut message).await.unwrap();
+                let response =
+                    Packet::try_from(PacketBuffer::try_from(message.as_slice()).unwrap()).unwrap();
+                assert_eq!(response.header.response_code, ResultCode::NoError);
+                assert_eq!(response.answers.len(), 1);
+            }
+        });
+    }
+}
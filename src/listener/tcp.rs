@@ -0,0 +1,49 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::listener::{accept_loop, bind, serve_messages};
+use crate::result::Result;
+use crate::server::Server;
+
+pub struct TcpDnsListenerConfig {
+    /// Address to listen on, the same as the UDP listener's
+    pub addr: SocketAddr,
+}
+
+/// Serves DNS over TCP clients, which retry over TCP the queries whose UDP response came back
+/// truncated: too large, or slipped by response rate limiting.
+///
+/// Messages are framed as specified by [RFC7766](https://www.rfc-editor.org/rfc/rfc7766), see
+/// `serve_messages`, and no more than `MAX_CLIENT_CONNECTIONS` connections are served at once.
+pub struct TcpDnsListener {
+    listener: TcpListener,
+    server: Arc<Server>,
+}
+
+impl TcpDnsListener {
+    pub fn bind(config: TcpDnsListenerConfig, server: Arc<Server>) -> Result<Self> {
+        Ok(Self {
+            listener: bind(config.addr)?,
+            server,
+        })
+    }
+
+    /// Starts serving clients, see `accept_loop`.
+    pub fn spawn(self) {
+        let server = self.server;
+
+        accept_loop(self.listener, "TCP", move |stream, client| {
+            serve(stream, client, Arc::clone(&server))
+        });
+    }
+}
+
+/// Answers the queries of a single client connection until it closes or goes idle.
+async fn serve(stream: TcpStream, client: SocketAddr, server: Arc<Server>) -> Result<()> {
+    let _ = stream.set_nodelay(true);
+    serve_messages(stream, client, server).await;
+
+    Ok(())
+}
@@ -0,0 +1,101 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::TlsAcceptor;
+
+use crate::listener::{accept_loop, bind, handshake, serve_messages, server_config};
+use crate::result::Result;
+use crate::server::Server;
+
+pub struct TlsListenerConfig {
+    /// Address to listen on, usually on port 853
+    pub addr: SocketAddr,
+    /// PEM file holding the certificate chain presented to clients
+    pub cert_file: String,
+    /// PEM file holding the private key of the certificate
+    pub key_file: String,
+}
+
+/// Serves DNS over TLS clients, as specified by [RFC7858](https://www.rfc-editor.org/rfc/rfc7858).
+///
+/// Messages are framed as over plain TCP, see `serve_messages`, and no more than
+/// `MAX_CLIENT_CONNECTIONS` connections are served at once.
+pub struct TlsListener {
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+    server: Arc<Server>,
+}
+
+impl TlsListener {
+    pub fn bind(config: TlsListenerConfig, server: Arc<Server>) -> Result<Self> {
+        let tls_config = server_config(&config.cert_file, &config.key_file, Vec::new())?;
+
+        Ok(Self {
+            listener: bind(config.addr)?,
+            acceptor: TlsAcceptor::from(Arc::new(tls_config)),
+            server,
+        })
+    }
+
+    /// Starts serving clients, see `accept_loop`.
+    pub fn spawn(self) {
+        let acceptor = self.acceptor;
+        let server = self.server;
+
+        accept_loop(self.listener, "TLS", move |stream, client| {
+            serve(stream, client, acceptor.clone(), Arc::clone(&server))
+        });
+    }
+}
+
+/// Answers the queries of a single client connection until it closes or goes idle.
+async fn serve(
+    stream: TcpStream,
+    client: SocketAddr,
+    acceptor: TlsAcceptor,
+    server: Arc<Server>,
+) -> Result<()> {
+    let stream = handshake(&acceptor, stream).await?;
+    serve_messages(stream, client, server).await;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::record::Record;
+    use crate::testing::{blocking_server, data_file, query, SERVER_NAME};
+    use crate::upstream::{TlsUpstream, TlsUpstreamConfig, Upstream};
+
+    use super::*;
+
+    #[test]
+    fn answers_queries_over_tls() {
+        let config = TlsListenerConfig {
+            addr: SocketAddr::from(([127, 0, 0, 1], 0)),
+            cert_file: data_file("server.pem"),
+            key_file: data_file("server.key"),
+        };
+        let listener = TlsListener::bind(config, blocking_server()).unwrap();
+        let addr = listener.listener.local_addr().unwrap();
+        listener.spawn();
+
+        let client = TlsUpstream::new(TlsUpstreamConfig {
+            addr,
+            server_name: SERVER_NAME.to_owned(),
+            ca_file: Some(data_file("ca.pem")),
+            spki_pins: Vec::new(),
+        })
+        .unwrap();
+        // Both queries go over the same connection
+        for id in [1, 2] {
+            let response = client.query(&query(id, "blocked.test")).unwrap();
+            assert_eq!(response.header.id, id);
+            assert!(matches!(
+                response.answers[..],
+                [Record::A { addr, .. }] if addr.is_unspecified()
+            ));
+        }
+    }
+}
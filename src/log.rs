@@ -0,0 +1,69 @@
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use serde::Deserialize;
+
+/// How much is logged, each level including the ones before it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    /// Failures needing attention, e.g. the query log cannot be written
+    Error,
+    /// Also failures the server gets over, e.g. an upstream not answering
+    Warn,
+    /// Also what the server is up to, e.g. reloading its configuration
+    #[default]
+    Info,
+    /// Also dumps every packet received and sent
+    Debug,
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+
+pub fn set_level(level: LogLevel) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// Whether messages of `level` should be logged.
+pub fn enabled(level: LogLevel) -> bool {
+    level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+// Every message goes to stderr, the query log going to stdout by default.
+
+/// Prints an error, errors being always logged.
+macro_rules! error {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::LogLevel::Error) {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+/// Prints a warning, unless only errors are logged.
+macro_rules! warning {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::LogLevel::Warn) {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+/// Prints what the server is up to, unless only errors and warnings are logged.
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::LogLevel::Info) {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+/// Prints the details of every query, when debugging.
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::LogLevel::Debug) {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+pub(crate) use {debug, error, info, warning};
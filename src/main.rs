@@ -1,62 +1,145 @@
+mod acl;
+mod api;
+mod blocklist;
+mod cache;
+mod cidr;
+mod clock;
+mod config;
+mod control;
 mod globals;
+mod groups;
 mod header;
+mod hints;
+mod index;
+mod listener;
+mod log;
+mod metrics;
+mod nameservers;
 mod packet;
+mod pause;
+mod querydb;
+mod querylog;
 mod question;
+mod ratelimit;
 mod record;
+mod reload;
 mod result;
+mod runtime;
+mod schedule;
 mod server;
+mod stats;
+#[cfg(test)]
+mod testing;
+mod upstream;
 
+use crate::clock::SystemClock;
+use crate::config::{CommandLine, Config};
 use crate::header::Header;
+use crate::listener::{
+    HttpListener, HttpListenerConfig, HttpsListener, HttpsListenerConfig, TcpDnsListener,
+    TcpDnsListenerConfig, TlsListener, TlsListenerConfig,
+};
 use crate::packet::{Packet, PacketBuffer};
 use crate::question::Question;
 use crate::record::Record;
-use crate::record::RecordType;
 use crate::result::{Error, Result};
 use crate::server::Server;
 
 use std::fs::File;
 use std::io::Read;
 use std::net::UdpSocket;
+use std::sync::Arc;
 
-fn main() -> Result<()> {
-    let mut fd = File::open("data/dns_question.bin").map_err(|_| Error::InvalidInputPath)?;
-    let mut buffer = PacketBuffer::new();
-    fd.read(&mut buffer.bytes)
-        .map_err(|_| Error::FailedReadingFile)?;
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run() -> Result<()> {
+    // Settings come from the file given by `--config <file>`, overridden by the other flags, see
+    // `config::CommandLine`.
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if control::is_command(&args) {
+        return control::run(&args);
+    }
+    let command_line = CommandLine::parse(&args)?;
+    let config = Config::load(command_line.config_file.as_deref(), &command_line.overrides)?;
+    log::set_level(config.logging.level);
+
+    for path in &config.logging.samples {
+        let mut fd = File::open(path).map_err(|_| Error::InvalidInputPath)?;
+        let mut buffer = PacketBuffer::new();
+        fd.read(&mut buffer.bytes)
+            .map_err(|_| Error::FailedReadingFile)?;
+
+        let packet = Packet::try_from(buffer)?;
+        println!("{}", packet);
 
-    let packet = Packet::try_from(buffer)?;
-    println!("{}", packet);
+        println!("------------------------------------");
+    }
+
+    let mut server = Server::from_config(&config, Arc::new(SystemClock))?;
 
-    println!("------------------------------------");
+    // Refresh the root hints with a priming query
+    if let Err(e) = server.prime_root_hints() {
+        log::warning!("Failed priming root hints: {}", e);
+    }
 
-    let mut fd = File::open("data/dns_answer.bin").map_err(|_| Error::InvalidInputPath)?;
-    let mut buffer = PacketBuffer::new();
-    fd.read(&mut buffer.bytes)
-        .map_err(|_| Error::FailedReadingFile)?;
+    // Shared with the thread refreshing stale cache entries, the one enabling blocking again
+    // once paused, and the one reloading the configuration on `SIGHUP` or, with `--watch`, when
+    // its files change
+    let server = Arc::new(server);
+    server.start_refresher();
+    server.start_pause_timer();
+    reload::spawn(Arc::clone(&server), command_line)?;
 
-    // println!("RAW: {:#?}", &buffer.bytes[0..50]);
-    let packet = Packet::try_from(buffer)?;
-    println!("{}", packet);
+    // Statistics are printed on `SIGUSR1`
+    stats::report_on_signal(Arc::clone(&server))?;
 
-    println!("------------------------------------");
+    let listen = &config.listen;
+    if let Some(addr) = &listen.dot {
+        let config = TlsListenerConfig {
+            addr: upstream::parse_addr(addr, 853)
+                .ok_or_else(|| Error::ListenFailed(addr.to_owned()))?,
+            cert_file: listen.cert.clone().unwrap_or_default(),
+            key_file: listen.key.clone().unwrap_or_default(),
+        };
+        TlsListener::bind(config, Arc::clone(&server))?.spawn();
+    }
+    if let Some(addr) = &listen.doh {
+        let config = HttpsListenerConfig {
+            addr: upstream::parse_addr(addr, 443)
+                .ok_or_else(|| Error::ListenFailed(addr.to_owned()))?,
+            cert_file: listen.cert.clone().unwrap_or_default(),
+            key_file: listen.key.clone().unwrap_or_default(),
+        };
+        HttpsListener::bind(config, Arc::clone(&server))?.spawn();
+    }
 
-    let server = Server::new("0.0.0.0".to_string(), 43210);
-    let p = server.recursive_lookup("yahoo.com", RecordType::MX)?;
-    println!("{}", p);
+    if let Some(addr) = &listen.http {
+        let config = HttpListenerConfig {
+            addr: upstream::parse_addr(addr, 8080)
+                .ok_or_else(|| Error::ListenFailed(addr.to_owned()))?,
+        };
+        HttpListener::bind(config, Arc::clone(&server))?.spawn();
+    }
 
-    println!("------------------------------------");
+    // Truncated UDP responses are retried over TCP, on the same address
+    let config = TcpDnsListenerConfig { addr: listen.udp };
+    TcpDnsListener::bind(config, Arc::clone(&server))?.spawn();
 
-    // Bind an UDP socket on port 2053
-    let socket = UdpSocket::bind(("0.0.0.0", 2053)).map_err(|_| Error::UDPBindFailed)?;
+    let socket = UdpSocket::bind(listen.udp).map_err(|_| Error::UDPBindFailed)?;
 
-    println!("Running server [{:?}]", socket);
+    log::info!("Running server [{:?}]", socket);
 
     // For now, queries are handled sequentially, so an infinite loop for servicing
     // requests is initiated.
     loop {
         match server.handle_query(&socket) {
             Ok(_) => {}
-            Err(e) => eprintln!("An error occurred: {}", e),
+            Err(e) => log::error!("An error occurred: {}", e),
         }
     }
 }
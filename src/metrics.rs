@@ -0,0 +1,302 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::globals::METRICS_MAX_UPSTREAMS;
+use crate::querylog::{Outcome, QueryLogEntry};
+use crate::record::RecordType;
+use crate::result::ResultCode;
+use crate::server::Server;
+
+/// Upper bounds, in seconds, of the buckets of the latency histograms
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+];
+
+/// Counts durations by bucket, as Prometheus histograms do.
+#[derive(Clone, Default)]
+struct Histogram {
+    /// Durations falling in each bucket of `LATENCY_BUCKETS`, the last one counting the longer
+    /// ones
+    buckets: [u64; LATENCY_BUCKETS.len() + 1],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[bucket] += 1;
+        self.sum += seconds;
+        self.count += 1;
+    }
+
+    /// Writes the series of the histogram `name`, whose other labels are `labels`.
+    fn write(&self, out: &mut String, name: &str, labels: &str) {
+        let mut cumulative = 0;
+        for (i, count) in self.buckets.iter().enumerate() {
+            cumulative += count;
+            let bound = match LATENCY_BUCKETS.get(i) {
+                Some(bound) => bound.to_string(),
+                None => "+Inf".to_owned(),
+            };
+            let _ = writeln!(out, "{name}_bucket{{{labels},le=\"{bound}\"}} {cumulative}");
+        }
+        let _ = writeln!(out, "{name}_sum{{{labels}}} {}", self.sum);
+        let _ = writeln!(out, "{name}_count{{{labels}}} {}", self.count);
+    }
+}
+
+/// How an upstream fared.
+#[derive(Default)]
+struct UpstreamMetrics {
+    rtt: Histogram,
+    failures: u64,
+}
+
+/// Metrics of the server, exposed to Prometheus by `render`.
+#[derive(Default)]
+pub struct Metrics {
+    queries: Mutex<HashMap<(RecordType, ResultCode, Outcome), u64>>,
+    /// Time taken to answer queries, by outcome
+    latencies: Mutex<HashMap<Outcome, Histogram>>,
+    /// Forwarding upstreams by name, and name servers by address
+    upstreams: Mutex<HashMap<String, UpstreamMetrics>>,
+}
+
+impl Metrics {
+    pub fn record(&self, entry: &QueryLogEntry) {
+        *self
+            .queries
+            .lock()
+            .unwrap()
+            .entry((entry.qtype, entry.rcode, entry.outcome))
+            .or_insert(0) += 1;
+        self.latencies
+            .lock()
+            .unwrap()
+            .entry(entry.outcome)
+            .or_default()
+            .observe(entry.latency);
+    }
+
+    pub fn record_upstream_success(&self, upstream: &str, rtt: Duration) {
+        self.update_upstream(upstream, |metrics| metrics.rtt.observe(rtt));
+    }
+
+    pub fn record_upstream_failure(&self, upstream: &str) {
+        self.update_upstream(upstream, |metrics| metrics.failures += 1);
+    }
+
+    /// Updates the metrics of `upstream`, unless it's a new one and `METRICS_MAX_UPSTREAMS` are
+    /// already tracked: recursing may reach any number of name servers.
+    fn update_upstream(&self, upstream: &str, update: impl FnOnce(&mut UpstreamMetrics)) {
+        let mut upstreams = self.upstreams.lock().unwrap();
+        if let Some(metrics) = upstreams.get_mut(upstream) {
+            update(metrics);
+        } else if upstreams.len() < METRICS_MAX_UPSTREAMS {
+            update(upstreams.entry(upstream.to_owned()).or_default());
+        }
+    }
+}
+
+/// Escapes `value` to be used as a label value.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+/// Renders the metrics of `server` in the Prometheus text exposition format.
+pub fn render(server: &Server) -> String {
+    let metrics = server.metrics();
+    let policy = server.policy();
+    let mut out = String::new();
+
+    let mut queries: Vec<_> = metrics
+        .queries
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|((qtype, rcode, outcome), count)| {
+            ((qtype.to_string(), rcode.to_string(), *outcome), *count)
+        })
+        .collect();
+    queries.sort();
+    header(
+        &mut out,
+        "barthez_queries_total",
+        "counter",
+        "Queries answered, by record type, response code and outcome.",
+    );
+    for ((qtype, rcode, outcome), count) in &queries {
+        let _ = writeln!(
+            out,
+            "barthez_queries_total{{qtype=\"{}\",rcode=\"{rcode}\",outcome=\"{outcome}\"}} {count}",
+            escape(qtype)
+        );
+    }
+
+    let mut latencies: Vec<_> = metrics
+        .latencies
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(outcome, histogram)| (*outcome, histogram.clone()))
+        .collect();
+    latencies.sort_by_key(|(outcome, _)| *outcome);
+    header(
+        &mut out,
+        "barthez_query_duration_seconds",
+        "histogram",
+        "Time taken to answer queries, by outcome.",
+    );
+    for (outcome, histogram) in &latencies {
+        histogram.write(
+            &mut out,
+            "barthez_query_duration_seconds",
+            &format!("outcome=\"{outcome}\""),
+        );
+    }
+
+    // Blocked and refused queries never reach the cache
+    let count = |outcomes: &[Outcome]| -> u64 {
+        queries
+            .iter()
+            .filter(|((_, _, outcome), _)| outcomes.contains(outcome))
+            .map(|(_, count)| count)
+            .sum()
+    };
+    let hits = count(&[Outcome::Cached, Outcome::Stale]);
+    let misses = count(&[Outcome::Forwarded, Outcome::Recursed, Outcome::Failed]);
+    header(
+        &mut out,
+        "barthez_cache_entries",
+        "gauge",
+        "Responses held in the cache.",
+    );
+    let _ = writeln!(out, "barthez_cache_entries {}", server.cache_len());
+    header(
+        &mut out,
+        "barthez_cache_hits_total",
+        "counter",
+        "Queries answered from the cache, stale responses included.",
+    );
+    let _ = writeln!(out, "barthez_cache_hits_total {hits}");
+    header(
+        &mut out,
+        "barthez_cache_misses_total",
+        "counter",
+        "Queries not found in the cache.",
+    );
+    let _ = writeln!(out, "barthez_cache_misses_total {misses}");
+    header(
+        &mut out,
+        "barthez_cache_hit_ratio",
+        "gauge",
+        "Share of the queries looked up in the cache that were answered from it.",
+    );
+    let ratio = match hits + misses {
+        0 => 0.0,
+        lookups => hits as f64 / lookups as f64,
+    };
+    let _ = writeln!(out, "barthez_cache_hit_ratio {ratio}");
+
+    {
+        let upstreams = metrics.upstreams.lock().unwrap();
+        let mut names: Vec<&String> = upstreams.keys().collect();
+        names.sort();
+
+        header(
+            &mut out,
+            "barthez_upstream_rtt_seconds",
+            "histogram",
+            "Round-trip time of the queries sent to each upstream or name server.",
+        );
+        for name in &names {
+            upstreams[*name].rtt.write(
+                &mut out,
+                "barthez_upstream_rtt_seconds",
+                &format!("upstream=\"{}\"", escape(name)),
+            );
+        }
+        header(
+            &mut out,
+            "barthez_upstream_failures_total",
+            "counter",
+            "Queries sent to each upstream or name server that failed.",
+        );
+        for name in &names {
+            let _ = writeln!(
+                out,
+                "barthez_upstream_failures_total{{upstream=\"{}\"}} {}",
+                escape(name),
+                upstreams[*name].failures
+            );
+        }
+    }
+
+    header(
+        &mut out,
+        "barthez_blocklist_domains",
+        "gauge",
+        "Domains in the blocklist.",
+    );
+    let _ = writeln!(
+        out,
+        "barthez_blocklist_domains {}",
+        policy.blocklist().len()
+    );
+
+    let counters = policy.rate_limiter().counters();
+    header(
+        &mut out,
+        "barthez_rate_limited_total",
+        "counter",
+        "Queries and responses held back by rate limiting, by action.",
+    );
+    for (action, count) in [
+        ("query_dropped", counters.queries_dropped),
+        ("response_dropped", counters.responses_dropped),
+        ("response_slipped", counters.responses_slipped),
+    ] {
+        let _ = writeln!(
+            out,
+            "barthez_rate_limited_total{{action=\"{action}\"}} {count}"
+        );
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tracks_a_bounded_number_of_upstreams() {
+        let metrics = Metrics::default();
+        for i in 0..=METRICS_MAX_UPSTREAMS {
+            metrics.record_upstream_failure(&format!("192.0.2.{i}"));
+        }
+        metrics.record_upstream_success("192.0.2.0", Duration::from_millis(20));
+
+        let upstreams = metrics.upstreams.lock().unwrap();
+        assert_eq!(upstreams.len(), METRICS_MAX_UPSTREAMS);
+        assert_eq!(upstreams["192.0.2.0"].failures, 1);
+        assert_eq!(upstreams["192.0.2.0"].rtt.count, 1);
+        let last = format!("192.0.2.{METRICS_MAX_UPSTREAMS}");
+        assert!(!upstreams.contains_key(&last));
+    }
+}
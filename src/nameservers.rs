@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use rand::seq::IndexedRandom;
+use rand::Rng;
+
+use crate::globals::{
+    NS_BACKOFF_BASE, NS_BACKOFF_MAX, NS_PROBE_PROBABILITY, NS_SRTT_MAX, NS_SRTT_WEIGHT,
+    NS_UNKNOWN_RTT_MAX,
+};
+
+/// What we know about a single name server.
+#[derive(Clone, Debug)]
+pub struct NameserverStats {
+    /// Smoothed round-trip time
+    pub srtt: Duration,
+    /// Number of consecutive failures (timeouts or unreachable)
+    pub failures: u32,
+    /// The server is not queried again until then, unless there is nothing else to try
+    pub backoff_until: Option<Instant>,
+}
+
+/// Picks the name server to query among the candidates of a zone, the way BIND and Unbound do:
+/// the server with the lowest smoothed RTT is preferred, others are occasionally probed so that
+/// their RTT stays up-to-date, and servers that keep timing out are backed off from.
+///
+/// Servers are identified by their address when iterating, but forwarders can be identified by
+/// anything else.
+pub struct NameserverSelector<K = IpAddr> {
+    stats: Mutex<HashMap<K, NameserverStats>>,
+}
+
+impl<K: Copy + Eq + Hash> NameserverSelector<K> {
+    pub fn new() -> Self {
+        Self {
+            stats: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Chooses which of the `candidates` should be queried next.
+    pub fn select(&self, candidates: &[K]) -> Option<K> {
+        let stats = self.stats.lock().unwrap();
+        let now = Instant::now();
+        let mut rng = rand::rng();
+
+        let backed_off = |addr: &K| {
+            stats
+                .get(addr)
+                .and_then(|s| s.backoff_until)
+                .is_some_and(|until| until > now)
+        };
+
+        let available: Vec<K> = candidates
+            .iter()
+            .filter(|addr| !backed_off(addr))
+            .copied()
+            .collect();
+
+        // If every candidate is being backed off from, retry the one that will be available first.
+        if available.is_empty() {
+            return candidates
+                .iter()
+                .min_by_key(|addr| stats.get(addr).and_then(|s| s.backoff_until))
+                .copied();
+        }
+
+        // Every now and then, probe another server to give it a chance to prove faster.
+        if rng.random_bool(NS_PROBE_PROBABILITY) {
+            return available.choose(&mut rng).copied();
+        }
+
+        // Servers never queried get a small random RTT, so that they are all tried at least once
+        // before settling on the fastest one.
+        available
+            .into_iter()
+            .map(|addr| {
+                let srtt = match stats.get(&addr) {
+                    Some(s) => s.srtt,
+                    None => rng.random_range(Duration::ZERO..NS_UNKNOWN_RTT_MAX),
+                };
+                (addr, srtt)
+            })
+            .min_by_key(|(_, srtt)| *srtt)
+            .map(|(addr, _)| addr)
+    }
+
+    /// Records a response from `addr`, received after `rtt`.
+    pub fn record_success(&self, addr: K, rtt: Duration) {
+        let mut stats = self.stats.lock().unwrap();
+
+        stats
+            .entry(addr)
+            .and_modify(|s| {
+                s.srtt = s.srtt.mul_f64(1.0 - NS_SRTT_WEIGHT) + rtt.mul_f64(NS_SRTT_WEIGHT);
+                s.failures = 0;
+                s.backoff_until = None;
+            })
+            .or_insert(NameserverStats {
+                srtt: rtt,
+                failures: 0,
+                backoff_until: None,
+            });
+    }
+
+    /// Records a failure to get a response from `addr` after `timeout`. The server is penalized,
+    /// up to `NS_SRTT_MAX`, and backed off from, for exponentially longer on each consecutive
+    /// failure.
+    pub fn record_failure(&self, addr: K, timeout: Duration) {
+        let mut stats = self.stats.lock().unwrap();
+
+        let s = stats.entry(addr).or_insert(NameserverStats {
+            srtt: timeout,
+            failures: 0,
+            backoff_until: None,
+        });
+        s.srtt = s.srtt.saturating_mul(2).max(timeout).min(NS_SRTT_MAX);
+        s.failures = s.failures.saturating_add(1);
+
+        let backoff = NS_BACKOFF_BASE
+            .saturating_mul(1 << (s.failures - 1).min(16))
+            .min(NS_BACKOFF_MAX);
+        s.backoff_until = Some(Instant::now() + backoff);
+    }
+}
+
+impl<K: Copy + Eq + Hash> Default for NameserverSelector<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use crate::globals::UPSTREAM_TIMEOUT;
+
+    use super::*;
+
+    #[test]
+    fn recovers_from_any_number_of_failures() {
+        let selector = NameserverSelector::new();
+        let failing = IpAddr::from(Ipv4Addr::new(192, 0, 2, 1));
+        let other = IpAddr::from(Ipv4Addr::new(192, 0, 2, 2));
+
+        for _ in 0..500 {
+            selector.record_failure(failing, UPSTREAM_TIMEOUT);
+        }
+        // Backed off from, but still the only one to try
+        assert_eq!(selector.select(&[failing]), Some(failing));
+        assert_eq!(selector.stats.lock().unwrap()[&failing].srtt, NS_SRTT_MAX);
+
+        selector.record_success(other, Duration::from_millis(20));
+        for _ in 0..20 {
+            selector.record_success(failing, Duration::from_millis(10));
+        }
+        let stats = selector.stats.lock().unwrap()[&failing].clone();
+        assert_eq!(stats.failures, 0);
+        assert_eq!(stats.backoff_until, None);
+        assert!(stats.srtt < Duration::from_millis(20));
+    }
+}
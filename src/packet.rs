@@ -1,9 +1,10 @@
 use std::fmt::{self, Formatter};
 use std::io::Write;
-use std::net::Ipv4Addr;
+use std::net::IpAddr;
 
-use crate::globals::MAX_JUMPS;
+use crate::globals::{MAX_JUMPS, MAX_MESSAGE_SIZE, UDP_MESSAGE_SIZE};
 use crate::record::RecordType;
+use crate::server::IpFamilies;
 use crate::Header;
 use crate::Question;
 use crate::Record;
@@ -13,8 +14,8 @@ use crate::{Error, Result};
 // for `Question`, `Record` and `Header`.
 #[derive(Debug)]
 pub struct PacketBuffer {
-    /// Bytes array containing a RAW DNS packet
-    pub bytes: [u8; 512],
+    /// Bytes array containing a RAW DNS packet, as large as the largest message it may hold
+    pub bytes: Vec<u8>,
     /// Current position in the bytes array
     pos: usize,
     /// Shadowed actual bit position
@@ -22,9 +23,15 @@ pub struct PacketBuffer {
 }
 
 impl PacketBuffer {
+    /// A buffer for a message sent over UDP, which holds no more than `UDP_MESSAGE_SIZE` bytes.
     pub fn new() -> Self {
+        Self::with_size(UDP_MESSAGE_SIZE)
+    }
+
+    /// A buffer for a message of up to `size` bytes.
+    pub fn with_size(size: usize) -> Self {
         Self {
-            bytes: [0; 512],
+            bytes: vec![0; size],
             pos: 0,
             _bit_pos: 0,
         }
@@ -36,8 +43,8 @@ impl PacketBuffer {
 
     /// Instead of writting this code everywhere...
     fn check_pos(&self) -> Result<()> {
-        if self.pos >= 512 {
-            return Err(Error::PacketBufferOver512(format!(
+        if self.pos >= self.bytes.len() {
+            return Err(Error::PacketBufferOverflow(format!(
                 "check_pos(): self.pos = {}",
                 self.pos
             )));
@@ -48,8 +55,8 @@ impl PacketBuffer {
 
     /// Gets byte `n` without consuming it
     fn get(&self, n: usize) -> Result<u8> {
-        if n >= 512 {
-            return Err(Error::PacketBufferOver512(format!("get(): n = {}", n)));
+        if n >= self.bytes.len() {
+            return Err(Error::PacketBufferOverflow(format!("get(): n = {}", n)));
         }
 
         Ok(self.bytes[n])
@@ -62,8 +69,8 @@ impl PacketBuffer {
 
     /// Changes the buffer position
     fn seek(&mut self, n: usize) -> Result<()> {
-        if n >= 512 {
-            return Err(Error::PacketBufferOver512(format!("seek(): n = {}", n)));
+        if n >= self.bytes.len() {
+            return Err(Error::PacketBufferOverflow(format!("seek(): n = {}", n)));
         }
 
         self.pos = n;
@@ -71,8 +78,8 @@ impl PacketBuffer {
     }
 
     pub fn get_range(&mut self, start: usize, len: usize) -> Result<&[u8]> {
-        if start + len >= 512 {
-            return Err(Error::PacketBufferOver512(format!(
+        if start + len > self.bytes.len() {
+            return Err(Error::PacketBufferOverflow(format!(
                 "get_range(): start = {}, len = {}",
                 start, len
             )));
@@ -229,8 +236,9 @@ impl PacketBuffer {
     }
 
     pub fn write_qname(&mut self, qname: &str) -> Result<()> {
-        // Write each part of the domain
-        for label in qname.split('.') {
+        // Write each part of the domain. Empty labels are skipped so that the root zone (either
+        // `""` or `"."`) and fully qualified names are written properly.
+        for label in qname.split('.').filter(|label| !label.is_empty()) {
             // Double check the label length isn't over 63
             let len = label.len();
             if len >= 63 {
@@ -252,8 +260,8 @@ impl PacketBuffer {
 
     /* SET */
     fn set_u8(&mut self, pos: usize, value: u8) -> Result<()> {
-        if pos >= 512 {
-            return Err(Error::PacketBufferOver512(format!(
+        if pos >= self.bytes.len() {
+            return Err(Error::PacketBufferOverflow(format!(
                 "Cannot set value at {}",
                 pos
             )));
@@ -265,7 +273,7 @@ impl PacketBuffer {
 
     pub fn set_u16(&mut self, pos: usize, value: u16) -> Result<()> {
         self.set_u8(pos, ((value >> 8) & 0x00FF) as u8)?;
-        self.set_u8(pos, (value & 0x00FF) as u8)?;
+        self.set_u8(pos + 1, (value & 0x00FF) as u8)?;
         Ok(())
     }
 
@@ -290,7 +298,7 @@ impl Write for PacketBuffer {
     fn write(&mut self, buf: &[u8]) -> std::result::Result<usize, std::io::Error> {
         for b in buf {
             self.write_u8(*b)
-                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
+                .map_err(|e| std::io::Error::other(e.to_string()))?;
         }
 
         Ok(buf.len())
@@ -301,13 +309,24 @@ impl Write for PacketBuffer {
     }
 }
 
-impl From<[u8; 512]> for PacketBuffer {
-    fn from(bytes: [u8; 512]) -> Self {
-        Self {
-            bytes,
+impl TryFrom<&[u8]> for PacketBuffer {
+    type Error = Error;
+
+    /// Copies a raw DNS message received over a stream or HTTP transport into a buffer sized to
+    /// it.
+    fn try_from(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() > MAX_MESSAGE_SIZE {
+            return Err(Error::PacketBufferOverflow(format!(
+                "try_from(): len = {}",
+                bytes.len()
+            )));
+        }
+
+        Ok(Self {
+            bytes: bytes.to_vec(),
             pos: 0,
             _bit_pos: 0,
-        }
+        })
     }
 }
 
@@ -330,6 +349,14 @@ impl TryFrom<Packet> for PacketBuffer {
 }
 */
 
+/// Whether `name` is `zone` itself or one of its subdomains. Every name is a subdomain of the root
+/// zone `""`.
+pub fn is_subdomain(name: &str, zone: &str) -> bool {
+    zone.is_empty()
+        || name == zone
+        || (name.ends_with(zone) && name[..name.len() - zone.len()].ends_with('.'))
+}
+
 /// From [RFCxxx]():
 /// ```
 /// +---------------------+
@@ -362,17 +389,34 @@ impl Packet {
         Ok(())
     }
 
-    pub fn get_random_a(&self) -> Option<Ipv4Addr> {
-        self.answers.iter().find_map(|record| match record {
-            Record::A { addr, .. } => Some(*addr),
-            _ => None,
-        })
+    /// The lowest TTL of the records of every section, `None` when there are no records.
+    pub fn min_ttl(&self) -> Option<u32> {
+        self.answers
+            .iter()
+            .chain(self.authorities.iter())
+            .chain(self.additionals.iter())
+            .map(|record| record.preamble().ttl())
+            .min()
+    }
+
+    /// Every address of the `A` and `AAAA` records of the answer section, restricted to the given
+    /// address `families`.
+    pub fn get_addrs(&self, families: IpFamilies) -> Vec<IpAddr> {
+        self.answers
+            .iter()
+            .filter_map(|record| match record {
+                Record::A { addr, .. } => Some(IpAddr::V4(*addr)),
+                Record::AAAA { addr, .. } => Some(IpAddr::V6(*addr)),
+                _ => None,
+            })
+            .filter(|addr| families.allows(addr))
+            .collect()
     }
 
     fn match_ns<'a>(&'a self, qname: &'a str) -> impl Iterator<Item = (&'a str, &'a str)> {
         self.authorities.iter().filter_map(|record| match record {
             Record::NS { preamble, host } => {
-                if qname.ends_with(&preamble.name) {
+                if is_subdomain(qname, &preamble.name) {
                     Some((preamble.name.as_str(), host.as_str()))
                 } else {
                     None
@@ -382,23 +426,46 @@ impl Packet {
         })
     }
 
-    pub fn get_resolved_ns(&self, qname: &str) -> Option<Ipv4Addr> {
+    /// Finds the addresses of the name servers for `qname` in the glue records (`A` or `AAAA`) of
+    /// the additional section, restricted to the given address `families`.
+    pub fn get_resolved_ns(&self, qname: &str, families: IpFamilies) -> Vec<IpAddr> {
         self.match_ns(qname)
             .flat_map(|(_, host)| {
                 self.additionals
                     .iter()
                     .filter_map(move |record| match record {
-                        Record::A { preamble, addr, .. } if preamble.name == host => Some(*addr),
+                        Record::A { preamble, addr, .. } if preamble.name == host => {
+                            Some(IpAddr::V4(*addr))
+                        }
+                        Record::AAAA { preamble, addr, .. } if preamble.name == host => {
+                            Some(IpAddr::V6(*addr))
+                        }
                         _ => None,
                     })
             })
-            .next()
+            .filter(|addr| families.allows(addr))
+            .collect()
     }
 
-    pub fn get_unresolved_ns<'a>(&'a self, qname: &'a str) -> Option<&str> {
+    /// The zone the `NS` records of the authority section delegate to, if they apply to `qname`.
+    pub fn get_ns_zone<'a>(&'a self, qname: &'a str) -> Option<&'a str> {
+        self.match_ns(qname).map(|(zone, _)| zone).next()
+    }
+
+    pub fn get_unresolved_ns<'a>(&'a self, qname: &'a str) -> Option<&'a str> {
         self.match_ns(qname).map(|(_, host)| host).next()
     }
 
+    /// Writes the packet into a new vector holding exactly its raw bytes, for transports
+    /// carrying messages of up to `MAX_MESSAGE_SIZE` bytes.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut buffer = PacketBuffer::with_size(MAX_MESSAGE_SIZE);
+        self.write(&mut buffer)?;
+
+        buffer.bytes.truncate(buffer.pos());
+        Ok(buffer.bytes)
+    }
+
     pub fn write(&self, buffer: &mut PacketBuffer) -> Result<()> {
         self.header.write(buffer)?;
         for question in &self.questions {
@@ -0,0 +1,142 @@
+use std::collections::BTreeMap;
+use std::fmt::{self, Formatter};
+use std::net::IpAddr;
+
+use chrono::{DateTime, Local, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Blocking disabled for a client, or for every client.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Pause {
+    /// When blocking is enabled again, if ever
+    pub until: Option<DateTime<Utc>>,
+}
+
+impl Pause {
+    fn active(&self, now: DateTime<Utc>) -> bool {
+        self.until.is_none_or(|until| until > now)
+    }
+}
+
+impl fmt::Display for Pause {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.until {
+            Some(until) => write!(
+                f,
+                "until {}",
+                until.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S")
+            ),
+            None => write!(f, "until enabled again"),
+        }
+    }
+}
+
+/// Whether blocking applies, for every client and for the ones it is disabled for.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockingStatus {
+    /// Whether blocking is enabled for every client
+    pub enabled: bool,
+    /// When blocking is enabled again, when it is disabled for every client until then
+    pub until: Option<DateTime<Utc>>,
+    /// The clients blocking is disabled for
+    pub clients: BTreeMap<IpAddr, Pause>,
+}
+
+impl fmt::Display for BlockingStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.enabled {
+            true => write!(f, "enabled")?,
+            false => write!(f, "disabled {}", Pause { until: self.until })?,
+        }
+        for (client, pause) in &self.clients {
+            write!(f, ", disabled for {client} {pause}")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Where blocking is disabled: for every client, or some of them, each time for good or until a
+/// deadline past which it is enabled again.
+#[derive(Debug, Default)]
+pub struct Pauses {
+    global: Option<Pause>,
+    clients: BTreeMap<IpAddr, Pause>,
+}
+
+impl Pauses {
+    /// Disables blocking for `client`, or every client, replacing any previous pause.
+    pub fn disable(&mut self, client: Option<IpAddr>, pause: Pause) {
+        match client {
+            Some(client) => {
+                self.clients.insert(client, pause);
+            }
+            None => self.global = Some(pause),
+        }
+    }
+
+    /// Enables blocking again for `client`, or every client, returning whether it was disabled.
+    /// Enabling it for every client also ends the pauses of single clients.
+    pub fn enable(&mut self, client: Option<IpAddr>) -> bool {
+        match client {
+            Some(client) => self.clients.remove(&client).is_some(),
+            None => {
+                let disabled = self.global.is_some() || !self.clients.is_empty();
+                self.global = None;
+                self.clients.clear();
+                disabled
+            }
+        }
+    }
+
+    /// Whether blocking is disabled for `client` at `now`.
+    pub fn paused(&self, client: IpAddr, now: DateTime<Utc>) -> bool {
+        self.global.is_some_and(|pause| pause.active(now))
+            || self
+                .clients
+                .get(&client)
+                .is_some_and(|pause| pause.active(now))
+    }
+
+    /// Ends the pauses over at `now`, returning the clients they were for, `None` standing for
+    /// every client.
+    pub fn expire(&mut self, now: DateTime<Utc>) -> Vec<Option<IpAddr>> {
+        let mut expired = Vec::new();
+        if self.global.is_some_and(|pause| !pause.active(now)) {
+            self.global = None;
+            expired.push(None);
+        }
+        self.clients.retain(|client, pause| {
+            let active = pause.active(now);
+            if !active {
+                expired.push(Some(*client));
+            }
+            active
+        });
+
+        expired
+    }
+
+    /// When the next pause is over.
+    pub fn next_deadline(&self) -> Option<DateTime<Utc>> {
+        self.global
+            .iter()
+            .chain(self.clients.values())
+            .filter_map(|pause| pause.until)
+            .min()
+    }
+
+    pub fn status(&self, now: DateTime<Utc>) -> BlockingStatus {
+        let global = self.global.filter(|pause| pause.active(now));
+        BlockingStatus {
+            enabled: global.is_none(),
+            until: global.and_then(|pause| pause.until),
+            clients: self
+                .clients
+                .iter()
+                .filter(|(_, pause)| pause.active(now))
+                .map(|(client, pause)| (*client, *pause))
+                .collect(),
+        }
+    }
+}
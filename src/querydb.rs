@@ -0,0 +1,223 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use rusqlite::{params, Connection};
+use serde::Deserialize;
+
+use crate::globals::{
+    QUERY_DB_BATCH_SIZE, QUERY_DB_PRUNE_INTERVAL, QUERY_DB_QUEUE_SIZE, QUERY_DB_RETENTION_DAYS,
+};
+use crate::log;
+use crate::querylog::QueryLogEntry;
+use crate::result::{Error, Result};
+
+const SCHEMA: &str = "
+    PRAGMA journal_mode = WAL;
+    CREATE TABLE IF NOT EXISTS queries (
+        id INTEGER PRIMARY KEY,
+        timestamp INTEGER NOT NULL,
+        client TEXT NOT NULL,
+        qname TEXT NOT NULL,
+        qtype TEXT NOT NULL,
+        outcome TEXT NOT NULL,
+        rcode TEXT NOT NULL,
+        upstream TEXT,
+        latency_ms REAL NOT NULL,
+        cname TEXT
+    );
+    CREATE INDEX IF NOT EXISTS queries_timestamp ON queries (timestamp);
+    CREATE INDEX IF NOT EXISTS queries_client ON queries (client, timestamp);
+    CREATE INDEX IF NOT EXISTS queries_qname ON queries (qname, timestamp);
+";
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueryDbConfig {
+    /// SQLite database every query is stored in, none when empty
+    pub file: String,
+    /// Days queries are kept, 0 keeping them forever
+    pub retention: u32,
+}
+
+impl Default for QueryDbConfig {
+    fn default() -> Self {
+        Self {
+            file: String::new(),
+            retention: QUERY_DB_RETENTION_DAYS,
+        }
+    }
+}
+
+/// Stores every query for the long term in a SQLite database, in the `queries` table, the
+/// timestamp being in seconds since the Unix epoch.
+///
+/// Queries are handed over to a writer thread which inserts them by batches, so that clients
+/// never wait on the disk. Should the writer fall too far behind, queries are dropped.
+pub struct QueryDb {
+    /// Queue of the writer thread, none when there is no database
+    sender: Option<SyncSender<QueryLogEntry>>,
+    dropped: AtomicU64,
+}
+
+impl QueryDb {
+    /// Opens the database of `config`, creating it if needed, and starts its writer thread.
+    pub fn open(config: &QueryDbConfig) -> Result<Self> {
+        if config.file.is_empty() {
+            return Ok(Self::default());
+        }
+
+        let failed = |e: rusqlite::Error| Error::QueryDbFailed(format!("{}: {e}", config.file));
+        let connection = Connection::open(&config.file).map_err(failed)?;
+        connection
+            .busy_timeout(Duration::from_secs(5))
+            .map_err(failed)?;
+        connection.execute_batch(SCHEMA).map_err(failed)?;
+        migrate(&connection).map_err(failed)?;
+
+        let retention = (config.retention > 0)
+            .then(|| Duration::from_secs(u64::from(config.retention) * 24 * 60 * 60));
+        let (sender, receiver) = mpsc::sync_channel(QUERY_DB_QUEUE_SIZE);
+        thread::spawn(move || run_writer(connection, receiver, retention));
+
+        Ok(Self {
+            sender: Some(sender),
+            dropped: AtomicU64::new(0),
+        })
+    }
+
+    /// Queues `entry` for writing, without ever blocking.
+    pub fn insert(&self, entry: &QueryLogEntry) {
+        let Some(sender) = &self.sender else {
+            return;
+        };
+
+        match sender.try_send(entry.clone()) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                if self.dropped.fetch_add(1, Ordering::Relaxed) == 0 {
+                    log::warning!("Query database is falling behind, dropping queries");
+                }
+            }
+            Err(TrySendError::Disconnected(_)) => {}
+        }
+    }
+}
+
+impl Default for QueryDb {
+    fn default() -> Self {
+        Self {
+            sender: None,
+            dropped: AtomicU64::new(0),
+        }
+    }
+}
+
+/// Writes the queries of `receiver` until the `QueryDb` is dropped, deleting the ones older than
+/// `retention` every now and then.
+fn run_writer(
+    mut connection: Connection,
+    receiver: Receiver<QueryLogEntry>,
+    retention: Option<Duration>,
+) {
+    let mut pruned: Option<Instant> = None;
+
+    loop {
+        let mut batch = Vec::new();
+        match receiver.recv_timeout(QUERY_DB_PRUNE_INTERVAL) {
+            Ok(entry) => batch.push(entry),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+        batch.extend(receiver.try_iter().take(QUERY_DB_BATCH_SIZE - 1));
+
+        if !batch.is_empty() {
+            if let Err(e) = insert(&mut connection, &batch) {
+                log::error!(
+                    "Cannot write {} queries to the query database: {}",
+                    batch.len(),
+                    e
+                );
+            }
+        }
+
+        let Some(retention) = retention else {
+            continue;
+        };
+        if pruned.is_none_or(|pruned| pruned.elapsed() >= QUERY_DB_PRUNE_INTERVAL) {
+            let oldest = Utc::now().timestamp() - retention.as_secs() as i64;
+            let deleted = connection.execute("DELETE FROM queries WHERE timestamp < ?1", [oldest]);
+            if let Err(e) = deleted {
+                log::error!("Cannot delete old queries from the query database: {}", e);
+            }
+            pruned = Some(Instant::now());
+        }
+    }
+}
+
+/// Adds the columns missing from a database created by an older version.
+fn migrate(connection: &Connection) -> rusqlite::Result<()> {
+    let mut statement = connection.prepare("SELECT name FROM pragma_table_info('queries')")?;
+    let columns = statement
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    if !columns.iter().any(|column| column == "cname") {
+        connection.execute_batch("ALTER TABLE queries ADD COLUMN cname TEXT")?;
+    }
+    Ok(())
+}
+
+fn insert(connection: &mut Connection, batch: &[QueryLogEntry]) -> rusqlite::Result<()> {
+    let transaction = connection.transaction()?;
+    {
+        let mut statement = transaction.prepare_cached(
+            "INSERT INTO queries (timestamp, client, qname, qtype, outcome, rcode, upstream, \
+             latency_ms, cname) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        )?;
+        for entry in batch {
+            statement.execute(params![
+                entry.timestamp.timestamp(),
+                entry.client.to_string(),
+                entry.qname.to_lowercase(),
+                entry.qtype.to_string(),
+                entry.outcome.to_string(),
+                entry.rcode.to_string(),
+                entry.upstream,
+                entry.latency.as_secs_f64() * 1000.0,
+                entry.cname,
+            ])?;
+        }
+    }
+    transaction.commit()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn adds_the_cname_column_to_older_databases() {
+        let connection = Connection::open_in_memory().unwrap();
+        connection
+            .execute_batch(&SCHEMA.replace(",\n        cname TEXT", ""))
+            .unwrap();
+        connection
+            .execute_batch(
+                "INSERT INTO queries (timestamp, client, qname, qtype, outcome, rcode, \
+                 latency_ms) VALUES (0, '127.0.0.1', 'example.com', 'A', 'cached', 'NOERROR', 0)",
+            )
+            .unwrap();
+
+        migrate(&connection).unwrap();
+        // Migrating twice is harmless
+        migrate(&connection).unwrap();
+
+        let cname: Option<String> = connection
+            .query_row("SELECT cname FROM queries", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(cname, None);
+    }
+}
@@ -0,0 +1,343 @@
+use std::collections::VecDeque;
+use std::fmt::{self, Display, Formatter};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize, Serializer};
+
+use crate::globals::{QUERY_LOG_KEEP, QUERY_LOG_MAX_SIZE, RECENT_QUERIES};
+use crate::log;
+use crate::record::RecordType;
+use crate::result::{Error, Result, ResultCode};
+
+/// How a query was answered.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    /// Answered by ourselves, the domain being blocked
+    Blocked,
+    /// Answered from the cache
+    Cached,
+    /// Answered from an expired cache entry, upstream servers failing to answer
+    Stale,
+    /// Answered by an upstream of a forward zone
+    Forwarded,
+    /// Resolved by iterating from the root servers
+    Recursed,
+    /// Refused, the client not being allowed to use us
+    Refused,
+    /// Resolution failed, and the client was sent `SERVFAIL`
+    Failed,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Blocked => write!(f, "blocked"),
+            Outcome::Cached => write!(f, "cached"),
+            Outcome::Stale => write!(f, "stale"),
+            Outcome::Forwarded => write!(f, "forwarded"),
+            Outcome::Recursed => write!(f, "recursed"),
+            Outcome::Refused => write!(f, "refused"),
+            Outcome::Failed => write!(f, "failed"),
+        }
+    }
+}
+
+/// How entries are written, one per line.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QueryLogFormat {
+    /// Space-separated fields, the upstream being `-` for queries answered locally
+    #[default]
+    Text,
+    /// A JSON object per line
+    Json,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueryLogConfig {
+    /// Where queries are logged: `-` for the standard output, a file path, or nowhere when empty
+    pub file: String,
+    pub format: QueryLogFormat,
+    /// Size in bytes past which the file is rotated, 0 never rotating it
+    pub max_size: u64,
+    /// Rotated files kept, as `<file>.1` (the most recent one) up to `<file>.<keep>`
+    pub keep: u32,
+}
+
+impl Default for QueryLogConfig {
+    fn default() -> Self {
+        Self {
+            file: "-".to_owned(),
+            format: QueryLogFormat::default(),
+            max_size: QUERY_LOG_MAX_SIZE,
+            keep: QUERY_LOG_KEEP,
+        }
+    }
+}
+
+/// A query, and how it was answered.
+#[derive(Clone, Debug, Serialize)]
+pub struct QueryLogEntry {
+    /// When the query was received
+    #[serde(serialize_with = "rfc3339")]
+    pub timestamp: DateTime<Utc>,
+    pub client: IpAddr,
+    pub qname: String,
+    #[serde(serialize_with = "display")]
+    pub qtype: RecordType,
+    pub outcome: Outcome,
+    #[serde(serialize_with = "display")]
+    pub rcode: ResultCode,
+    /// The upstream, or authoritative name server, which sent the response
+    pub upstream: Option<String>,
+    /// The name of the `CNAME` chain of the answer the query was blocked for, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cname: Option<String>,
+    /// How long answering took
+    #[serde(rename = "latency_ms", serialize_with = "milliseconds")]
+    pub latency: Duration,
+}
+
+impl fmt::Display for QueryLogEntry {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {} {} {} {} {} {:.1}ms",
+            self.timestamp.to_rfc3339_opts(SecondsFormat::Millis, true),
+            self.client,
+            self.qname,
+            self.qtype,
+            self.outcome,
+            self.rcode,
+            self.upstream.as_deref().unwrap_or("-"),
+            self.latency.as_secs_f64() * 1000.0
+        )?;
+        if let Some(cname) = &self.cname {
+            write!(f, " cname={cname}")?;
+        }
+
+        Ok(())
+    }
+}
+
+fn rfc3339<S: Serializer>(
+    timestamp: &DateTime<Utc>,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    serializer.serialize_str(&timestamp.to_rfc3339_opts(SecondsFormat::Millis, true))
+}
+
+fn display<T: Display, S: Serializer>(
+    value: &T,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    serializer.collect_str(value)
+}
+
+fn milliseconds<S: Serializer>(
+    latency: &Duration,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    serializer.serialize_f64((latency.as_secs_f64() * 1_000_000.0).round() / 1000.0)
+}
+
+/// Where entries end up.
+enum Sink {
+    Discard,
+    Stdout,
+    /// A file, along with its current size
+    File(File, u64),
+}
+
+/// Logs every query answered, to the standard output or to a file rotated once it grows too
+/// large.
+pub struct QueryLog {
+    config: QueryLogConfig,
+    sink: Mutex<Sink>,
+}
+
+impl QueryLog {
+    pub fn new(config: QueryLogConfig) -> Result<Self> {
+        let sink = match config.file.as_str() {
+            "" => Sink::Discard,
+            "-" => Sink::Stdout,
+            path => {
+                let file = open(path)?;
+                let size = file.metadata().map(|m| m.len()).unwrap_or(0);
+                Sink::File(file, size)
+            }
+        };
+
+        Ok(Self {
+            config,
+            sink: Mutex::new(sink),
+        })
+    }
+
+    pub fn log(&self, entry: &QueryLogEntry) {
+        let mut sink = self.sink.lock().unwrap();
+
+        let line = match self.config.format {
+            QueryLogFormat::Text => format!("{entry}\n"),
+            QueryLogFormat::Json => match serde_json::to_string(entry) {
+                Ok(json) => json + "\n",
+                Err(e) => {
+                    log::error!("Cannot serialize query log entry: {}", e);
+                    return;
+                }
+            },
+        };
+
+        match &mut *sink {
+            Sink::Discard => {}
+            Sink::Stdout => print!("{line}"),
+            Sink::File(file, size) => {
+                let max_size = self.config.max_size;
+                if max_size > 0 && *size > 0 && *size + line.len() as u64 > max_size {
+                    match self.rotate() {
+                        Ok(rotated) => {
+                            *file = rotated;
+                            *size = 0;
+                        }
+                        Err(e) => log::error!("{}", e),
+                    }
+                }
+
+                match file.write_all(line.as_bytes()) {
+                    Ok(()) => *size += line.len() as u64,
+                    Err(e) => log::error!("Cannot write the query log: {}", e),
+                }
+            }
+        }
+    }
+
+    /// Shifts the rotated files by one, the oldest one being dropped, moves the current file to
+    /// `<file>.1` and returns a new, empty, file.
+    fn rotate(&self) -> Result<File> {
+        let path = &self.config.file;
+        let failed = |e: io::Error| Error::QueryLogFailed(format!("{path}: {e}"));
+
+        if self.config.keep == 0 {
+            fs::remove_file(path).map_err(failed)?;
+        } else {
+            for i in (1..self.config.keep).rev() {
+                let from = format!("{path}.{i}");
+                if fs::exists(&from).unwrap_or(false) {
+                    fs::rename(&from, format!("{path}.{}", i + 1)).map_err(failed)?;
+                }
+            }
+            fs::rename(path, format!("{path}.1")).map_err(failed)?;
+        }
+
+        open(path)
+    }
+}
+
+impl Default for QueryLog {
+    fn default() -> Self {
+        Self {
+            config: QueryLogConfig::default(),
+            sink: Mutex::new(Sink::Stdout),
+        }
+    }
+}
+
+/// The latest queries, kept in memory to be looked at through the management API.
+#[derive(Default)]
+pub struct RecentQueries {
+    entries: Mutex<VecDeque<QueryLogEntry>>,
+}
+
+impl RecentQueries {
+    pub fn push(&self, entry: QueryLogEntry) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= RECENT_QUERIES {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    /// Returns the latest `limit` entries `filter` accepts, the most recent first.
+    pub fn latest(
+        &self,
+        limit: usize,
+        filter: impl Fn(&QueryLogEntry) -> bool,
+    ) -> Vec<QueryLogEntry> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .iter()
+            .rev()
+            .filter(|entry| filter(entry))
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+}
+
+fn open(path: &str) -> Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| Error::QueryLogFailed(format!("{path}: {e}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(qname: &str) -> QueryLogEntry {
+        QueryLogEntry {
+            timestamp: Utc::now(),
+            client: IpAddr::from([192, 168, 1, 10]),
+            qname: qname.to_owned(),
+            qtype: RecordType::A,
+            outcome: Outcome::Cached,
+            rcode: ResultCode::NoError,
+            upstream: None,
+            cname: None,
+            latency: Duration::ZERO,
+        }
+    }
+
+    #[test]
+    fn rotates_the_file_and_keeps_the_latest_ones() {
+        let dir = std::env::temp_dir().join(format!("barthez-{}-querylog", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("queries.log").to_string_lossy().into_owned();
+        let file = |suffix: &str| fs::read_to_string(format!("{path}{suffix}")).ok();
+
+        // Every file holds two entries, of the same length
+        let line_len = format!("{}\n", entry("q0.example")).len() as u64;
+        let query_log = QueryLog::new(QueryLogConfig {
+            file: path.clone(),
+            format: QueryLogFormat::Text,
+            max_size: 2 * line_len,
+            keep: 2,
+        })
+        .unwrap();
+        for i in 0..7 {
+            query_log.log(&entry(&format!("q{i}.example")));
+        }
+        let qnames = |content: Option<String>| -> Vec<String> {
+            content
+                .unwrap()
+                .lines()
+                .map(|line| line.split(' ').nth(2).unwrap().to_owned())
+                .collect()
+        };
+
+        assert_eq!(qnames(file("")), ["q6.example"]);
+        assert_eq!(qnames(file(".1")), ["q4.example", "q5.example"]);
+        assert_eq!(qnames(file(".2")), ["q2.example", "q3.example"]);
+        assert_eq!(file(".3"), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
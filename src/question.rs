@@ -1,9 +1,11 @@
 use std::fmt::{self, Formatter};
 
+use crate::log;
 use crate::packet::PacketBuffer;
 use crate::record::RecordType;
 use crate::result::{Error, Result};
 
+#[derive(Clone)]
 pub struct Question {
     pub name: String,
     /// 2 bytes. The record type.
@@ -63,7 +65,7 @@ impl TryFrom<&mut PacketBuffer> for Question {
         let _class = buffer.read_u16()?;
 
         if _class != 1 {
-            eprintln!("Strange, class of question {} != 1.", name);
+            log::warning!("Strange, class of question {} != 1.", name);
         }
 
         Ok(Self {
@@ -0,0 +1,319 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{self, Formatter};
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use serde::Deserialize;
+
+use crate::cidr::Cidr;
+use crate::globals::{RATE_LIMIT_IPV4_PREFIX, RATE_LIMIT_IPV6_PREFIX, RATE_LIMIT_MAX_ENTRIES};
+use crate::log;
+use crate::packet::Packet;
+use crate::record::RecordType;
+use crate::result::ResultCode;
+
+/// Limits applying to clients, grouped by address prefix. A rate of 0 disables the limit.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// Queries accepted per second from a client
+    pub queries_per_second: u32,
+    /// Queries a client can send at once after being quiet for a while
+    pub query_burst: u32,
+    /// Identical responses sent per second to a client
+    pub responses_per_second: u32,
+    /// One in `slip` of the responses over the limit is sent truncated rather than dropped, 0
+    /// dropping them all
+    pub slip: u32,
+    pub ipv4_prefix: u8,
+    pub ipv6_prefix: u8,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            queries_per_second: 0,
+            query_burst: 0,
+            responses_per_second: 0,
+            slip: 2,
+            ipv4_prefix: RATE_LIMIT_IPV4_PREFIX,
+            ipv6_prefix: RATE_LIMIT_IPV6_PREFIX,
+        }
+    }
+}
+
+/// What to do with a response, once response rate limiting is through with it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RrlAction {
+    Send,
+    /// Send it truncated (`TC=1`) and without records, so that a legitimate client retries over
+    /// TCP while the victim of a spoofed query gets nothing worth amplifying
+    Slip,
+    Drop,
+}
+
+/// How many queries and responses were held back, since the start.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RateLimitCounters {
+    pub queries_dropped: u64,
+    pub responses_dropped: u64,
+    pub responses_slipped: u64,
+}
+
+impl fmt::Display for RateLimitCounters {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} queries dropped, {} responses dropped, {} responses slipped",
+            self.queries_dropped, self.responses_dropped, self.responses_slipped
+        )
+    }
+}
+
+/// A token bucket, filled at a given rate up to its capacity and emptied by one on every
+/// message.
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// Number of messages over the limit in a row
+    exceeded: u32,
+    /// When the bucket was last used, in number of messages
+    used: u64,
+}
+
+impl Bucket {
+    fn new(capacity: f64, now: Instant) -> Self {
+        Self {
+            tokens: capacity,
+            updated: now,
+            exceeded: 0,
+            used: 0,
+        }
+    }
+
+    fn refill(&mut self, rate: f64, capacity: f64, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(capacity);
+        self.updated = now;
+    }
+
+    /// Takes a token if there is one left.
+    fn take(&mut self, rate: f64, capacity: f64, now: Instant) -> bool {
+        self.refill(rate, capacity, now);
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            self.exceeded = 0;
+            true
+        } else {
+            self.exceeded = self.exceeded.saturating_add(1);
+            false
+        }
+    }
+}
+
+/// Buckets keyed by client prefix (and response), no more than `RATE_LIMIT_MAX_ENTRIES` of them:
+/// past that, the least recently used one is forgotten to make room for a new one, and its client
+/// starts over with a full bucket.
+struct Buckets<K> {
+    state: Mutex<BucketsState<K>>,
+    rate: f64,
+    capacity: f64,
+}
+
+struct BucketsState<K> {
+    buckets: HashMap<K, Bucket>,
+    /// The keys of the buckets, by when they were last used, the least recently used first
+    usage: BTreeMap<u64, K>,
+    /// Number of messages so far
+    messages: u64,
+}
+
+impl<K: Clone + Eq + Hash> Buckets<K> {
+    fn new(rate: u32, burst: u32) -> Self {
+        Self {
+            state: Mutex::new(BucketsState {
+                buckets: HashMap::new(),
+                usage: BTreeMap::new(),
+                messages: 0,
+            }),
+            rate: rate as f64,
+            capacity: burst.max(rate).max(1) as f64,
+        }
+    }
+
+    /// Takes a token from the bucket of `key`, returning `None` when there was one, or the
+    /// number of messages over the limit in a row otherwise.
+    fn take(&self, key: K) -> Option<u32> {
+        let mut state = self.state.lock().unwrap();
+        let BucketsState {
+            buckets,
+            usage,
+            messages,
+        } = &mut *state;
+        let now = Instant::now();
+        *messages += 1;
+
+        match buckets.get(&key) {
+            Some(bucket) => {
+                usage.remove(&bucket.used);
+            }
+            None if buckets.len() >= RATE_LIMIT_MAX_ENTRIES => {
+                if let Some((_, oldest)) = usage.pop_first() {
+                    buckets.remove(&oldest);
+                }
+            }
+            None => {}
+        }
+
+        let bucket = buckets
+            .entry(key.clone())
+            .or_insert_with(|| Bucket::new(self.capacity, now));
+        bucket.used = *messages;
+        usage.insert(*messages, key);
+
+        match bucket.take(self.rate, self.capacity, now) {
+            true => None,
+            false => Some(bucket.exceeded),
+        }
+    }
+}
+
+/// Protects upstream servers and third parties from abusive clients: the queries of each client
+/// go through a token bucket, and so do identical responses sent to the same client, as
+/// described by [Response Rate Limiting](https://kb.isc.org/docs/aa-00994). The latter keeps
+/// us from being used to amplify attacks with spoofed queries.
+///
+/// Clients are grouped by address prefix, so that a single host can't escape its limits by
+/// hopping between addresses.
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    queries: Option<Buckets<Cidr>>,
+    responses: Option<Buckets<(Cidr, String, RecordType, ResultCode)>>,
+    queries_dropped: AtomicU64,
+    responses_dropped: AtomicU64,
+    responses_slipped: AtomicU64,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        let queries = (config.queries_per_second > 0)
+            .then(|| Buckets::new(config.queries_per_second, config.query_burst));
+        let responses = (config.responses_per_second > 0)
+            .then(|| Buckets::new(config.responses_per_second, config.responses_per_second));
+
+        Self {
+            config,
+            queries,
+            responses,
+            queries_dropped: AtomicU64::new(0),
+            responses_dropped: AtomicU64::new(0),
+            responses_slipped: AtomicU64::new(0),
+        }
+    }
+
+    fn prefix(&self, client: IpAddr) -> Cidr {
+        match client {
+            IpAddr::V4(_) => Cidr::new(client, self.config.ipv4_prefix),
+            IpAddr::V6(_) => Cidr::new(client, self.config.ipv6_prefix),
+        }
+    }
+
+    /// Whether a query from `client` should be answered at all.
+    pub fn allow_query(&self, client: IpAddr) -> bool {
+        let Some(queries) = &self.queries else {
+            return true;
+        };
+
+        let prefix = self.prefix(client);
+        match queries.take(prefix) {
+            None => true,
+            Some(exceeded) => {
+                self.queries_dropped.fetch_add(1, Ordering::Relaxed);
+                if exceeded == 1 {
+                    log::warning!("Rate limiting queries from {}: {}", prefix, self.counters());
+                }
+                false
+            }
+        }
+    }
+
+    /// Decides whether `response` should be sent to `client`, given the identical responses it
+    /// was sent lately.
+    pub fn limit_response(&self, client: IpAddr, response: &Packet) -> RrlAction {
+        let Some(responses) = &self.responses else {
+            return RrlAction::Send;
+        };
+        let Some(question) = response.questions.first() else {
+            return RrlAction::Send;
+        };
+
+        let prefix = self.prefix(client);
+        let key = (
+            prefix,
+            question.name.to_lowercase(),
+            question.question_type,
+            response.header.response_code,
+        );
+        match responses.take(key) {
+            None => RrlAction::Send,
+            Some(exceeded) => {
+                let action = if self.config.slip > 0 && exceeded % self.config.slip == 0 {
+                    self.responses_slipped.fetch_add(1, Ordering::Relaxed);
+                    RrlAction::Slip
+                } else {
+                    self.responses_dropped.fetch_add(1, Ordering::Relaxed);
+                    RrlAction::Drop
+                };
+                if exceeded == 1 {
+                    log::warning!(
+                        "Rate limiting responses to {} for {}: {}",
+                        prefix,
+                        question.name,
+                        self.counters()
+                    );
+                }
+                action
+            }
+        }
+    }
+
+    pub fn counters(&self) -> RateLimitCounters {
+        RateLimitCounters {
+            queries_dropped: self.queries_dropped.load(Ordering::Relaxed),
+            responses_dropped: self.responses_dropped.load(Ordering::Relaxed),
+            responses_slipped: self.responses_slipped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new(RateLimitConfig::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn forgets_the_least_recently_used_bucket() {
+        let buckets = Buckets::new(1, 1);
+        for key in 0..RATE_LIMIT_MAX_ENTRIES {
+            assert_eq!(buckets.take(key), None);
+        }
+        // The first bucket is used again, so the second one is the oldest
+        assert_eq!(buckets.take(0), Some(1));
+        assert_eq!(buckets.take(RATE_LIMIT_MAX_ENTRIES), None);
+
+        let state = buckets.state.lock().unwrap();
+        assert_eq!(state.buckets.len(), RATE_LIMIT_MAX_ENTRIES);
+        assert_eq!(state.usage.len(), RATE_LIMIT_MAX_ENTRIES);
+        assert!(state.buckets.contains_key(&0));
+        assert!(!state.buckets.contains_key(&1));
+    }
+}
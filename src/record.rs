@@ -1,23 +1,21 @@
 use core::fmt::{self, Formatter};
 use std::net::{Ipv4Addr, Ipv6Addr};
 
+use crate::log;
 use crate::result::{Error, Result};
 use crate::PacketBuffer;
 
 // #![allow(non_camel_case_types)]
 
-#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum RecordType {
     Unknown(u16),
-    A,  // 1
-    NS, // 2
-    //#[allow(non_camel_case_types)]
-    #[allow(non_camel_case_types)]
+    A,     // 1
+    NS,    // 2
     CNAME, // 5
-    //#[allow(non_camel_case_types)]
-    MX, // 15
-    #[allow(non_camel_case_types)]
-    AAAA, // 28
+    MX,    // 15
+    AAAA,  // 28
 }
 
 impl From<RecordType> for u16 {
@@ -49,7 +47,7 @@ impl From<u16> for RecordType {
 impl fmt::Display for RecordType {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
-            RecordType::Unknown(_) => write!(f, "Unknown")?,
+            RecordType::Unknown(x) => write!(f, "TYPE{x}")?,
             RecordType::A => write!(f, "A")?,
             RecordType::NS => write!(f, "NS")?,
             RecordType::CNAME => write!(f, "CNAME")?,
@@ -61,6 +59,7 @@ impl fmt::Display for RecordType {
     }
 }
 
+#[derive(Clone)]
 pub struct RecordPreamble {
     pub name: String,
     /// 2 bytes
@@ -71,6 +70,28 @@ pub struct RecordPreamble {
     len: u16,
 }
 
+impl RecordPreamble {
+    /// A preamble for a record of class `IN` we build ourselves, its length being computed when
+    /// it's written.
+    pub fn new(name: &str, record_type: RecordType, ttl: u32) -> Self {
+        Self {
+            name: name.to_owned(),
+            record_type,
+            _class: 1,
+            ttl,
+            len: 0,
+        }
+    }
+
+    pub fn ttl(&self) -> u32 {
+        self.ttl
+    }
+
+    pub fn set_ttl(&mut self, ttl: u32) {
+        self.ttl = ttl;
+    }
+}
+
 impl fmt::Display for RecordPreamble {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         writeln!(f, "\tName: {}", self.name)?;
@@ -83,6 +104,8 @@ impl fmt::Display for RecordPreamble {
     }
 }
 
+#[derive(Clone)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum Record {
     Unknown {
         preamble: RecordPreamble,
@@ -95,7 +118,6 @@ pub enum Record {
         preamble: RecordPreamble,
         host: String,
     },
-    #[allow(non_camel_case_types)]
     CNAME {
         preamble: RecordPreamble,
         host: String,
@@ -105,7 +127,6 @@ pub enum Record {
         preference: u16,
         exchange: String,
     },
-    #[allow(non_camel_case_types)]
     AAAA {
         preamble: RecordPreamble,
         addr: Ipv6Addr,
@@ -113,6 +134,51 @@ pub enum Record {
 }
 
 impl Record {
+    pub fn preamble(&self) -> &RecordPreamble {
+        match self {
+            Record::Unknown { preamble }
+            | Record::A { preamble, .. }
+            | Record::NS { preamble, .. }
+            | Record::CNAME { preamble, .. }
+            | Record::MX { preamble, .. }
+            | Record::AAAA { preamble, .. } => preamble,
+        }
+    }
+
+    pub fn preamble_mut(&mut self) -> &mut RecordPreamble {
+        match self {
+            Record::Unknown { preamble }
+            | Record::A { preamble, .. }
+            | Record::NS { preamble, .. }
+            | Record::CNAME { preamble, .. }
+            | Record::MX { preamble, .. }
+            | Record::AAAA { preamble, .. } => preamble,
+        }
+    }
+
+    /// The record on a single line, as in zone files, e.g. `example.com 300 A 93.184.216.34`.
+    pub fn presentation(&self) -> String {
+        let preamble = self.preamble();
+        let data = match self {
+            Record::Unknown { .. } => String::new(),
+            Record::A { addr, .. } => addr.to_string(),
+            Record::AAAA { addr, .. } => addr.to_string(),
+            Record::NS { host, .. } | Record::CNAME { host, .. } => host.clone(),
+            Record::MX {
+                preference,
+                exchange,
+                ..
+            } => format!("{preference} {exchange}"),
+        };
+
+        format!(
+            "{} {} {} {}",
+            preamble.name, preamble.ttl, preamble.record_type, data
+        )
+        .trim_end()
+        .to_owned()
+    }
+
     /// From [RFC1035#4.1.3](https://www.rfc-editor.org/rfc/rfc1035#section-4.1.3):
     /// ```
     ///                                     1  1  1  1  1  1
@@ -165,7 +231,7 @@ impl Record {
                 let pos = buffer.pos();
                 buffer.write_u16(0)?;
                 buffer.write_qname(host)?;
-                let size = buffer.pos() - pos + 2;
+                let size = buffer.pos() - (pos + 2);
                 buffer.set_u16(pos, size as u16)?;
             }
             Record::CNAME { preamble, host } => {
@@ -179,7 +245,7 @@ impl Record {
                 let pos = buffer.pos();
                 buffer.write_u16(0)?;
                 buffer.write_qname(host)?;
-                let size = buffer.pos() - pos + 2;
+                let size = buffer.pos() - (pos + 2);
                 buffer.set_u16(pos, size as u16)?;
             }
             Record::MX {
@@ -200,7 +266,7 @@ impl Record {
                 buffer.write_u16(*preference)?;
                 buffer.write_qname(exchange)?;
                 // Calculate and set the length of the data we just wrote
-                let size = buffer.pos() - pos + 2;
+                let size = buffer.pos() - (pos + 2);
                 buffer.set_u16(pos, size as u16)?;
             }
             Record::AAAA { preamble, addr } => {
@@ -216,7 +282,7 @@ impl Record {
                 }
             }
             _ => {
-                println!("Skipping writing record: {}", self);
+                log::warning!("Skipping writing record: {}", self);
             }
         }
 
@@ -338,7 +404,7 @@ impl TryFrom<&mut PacketBuffer> for Record {
             _ => {
                 // Jumps over the non-parsed records length
                 buffer.step(preamble.len.into());
-                return Ok(Record::Unknown { preamble });
+                Ok(Record::Unknown { preamble })
             }
         }
     }
@@ -0,0 +1,104 @@
+use std::collections::HashMap;
+use std::fs;
+use std::sync::mpsc::{self, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::SystemTime;
+
+use signal_hook::consts::SIGHUP;
+use signal_hook::iterator::Signals;
+
+use crate::config::{CommandLine, Config};
+use crate::globals::RELOAD_WATCH_INTERVAL;
+use crate::log;
+use crate::result::{Error, Result};
+use crate::server::Server;
+
+/// Reloads the configuration and blocklists of `server` in the background on `SIGHUP`, and also
+/// whenever one of their files changes if `command_line` asks for it. The command line
+/// overrides keep applying on top of the reloaded configuration.
+pub fn spawn(server: Arc<Server>, command_line: CommandLine) -> Result<()> {
+    let (sender, receiver) = mpsc::channel::<&'static str>();
+
+    let mut signals =
+        Signals::new([SIGHUP]).map_err(|e| Error::ReloadFailed(format!("SIGHUP: {e}")))?;
+    let signal_sender = sender.clone();
+    thread::spawn(move || {
+        for _ in signals.forever() {
+            if signal_sender.send("SIGHUP").is_err() {
+                break;
+            }
+        }
+    });
+
+    if command_line.watch {
+        let server = Arc::clone(&server);
+        let config_file = command_line.config_file.clone();
+        thread::spawn(move || watch(&server, config_file, sender));
+    }
+
+    thread::spawn(move || {
+        while let Ok(reason) = receiver.recv() {
+            // Triggers piling up while reloading only need a single reload
+            while receiver.try_recv().is_ok() {}
+
+            log::info!("Reloading configuration ({})", reason);
+            let reloaded =
+                Config::load(command_line.config_file.as_deref(), &command_line.overrides)
+                    .and_then(|config| server.reload(&config));
+            match reloaded {
+                Ok(_) => log::info!("Configuration reloaded"),
+                Err(e) => log::error!("Keeping the current configuration: {}", e),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Polls the modification time of the configuration file and of the blocklists in use, asking
+/// for a reload when one of them changes.
+fn watch(server: &Server, config_file: Option<String>, reload: Sender<&'static str>) {
+    let mut modified: HashMap<String, Option<SystemTime>> = HashMap::new();
+
+    loop {
+        // The blocklists and allowlists in use may change with the configuration
+        let config = server.config();
+        let files: Vec<String> = config_file
+            .iter()
+            .cloned()
+            .chain(config.blocking.lists)
+            .chain(config.blocking.allowlists)
+            .chain(
+                config
+                    .blocking
+                    .scheduled
+                    .into_iter()
+                    .flat_map(|scheduled| scheduled.lists),
+            )
+            .chain(config.groups.into_iter().flat_map(|group| {
+                group.lists.into_iter().chain(group.allowlists).chain(
+                    group
+                        .scheduled
+                        .into_iter()
+                        .flat_map(|scheduled| scheduled.lists),
+                )
+            }))
+            .collect();
+
+        let mut changed = false;
+        for file in files {
+            let time = fs::metadata(&file).and_then(|m| m.modified()).ok();
+            // Files seen for the first time are new to the watch, not changed
+            if let Some(previous) = modified.insert(file, time) {
+                changed |= previous != time;
+            }
+        }
+
+        if changed && reload.send("file changed").is_err() {
+            return;
+        }
+
+        thread::sleep(RELOAD_WATCH_INTERVAL);
+    }
+}
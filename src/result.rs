@@ -1,12 +1,15 @@
 use core::fmt;
 use std::fmt::Formatter;
+use std::net::SocketAddr;
+
+use crate::globals::{MAX_NS_DEPTH, MAX_REFERRALS, MAX_UPSTREAM_QUERIES};
 
 pub type Result<T> = std::result::Result<T, Error>;
 
 #[derive(Debug)]
 pub enum Error {
     PacketBufferInvalidPosition,
-    PacketBufferOver512(String),
+    PacketBufferOverflow(String),
 
     /// When reading labels performs too many jumps
     MaxJumpsAttained,
@@ -23,20 +26,101 @@ pub enum Error {
     UDPBindFailed,
     UDPSendFailed,
     UDPRecvFailed,
+
+    /// When resolving a single request sent too many queries upstream
+    MaxUpstreamQueries(String),
+    /// When resolving glueless name servers nested too many lookups
+    MaxNsDepth(String),
+    /// When a lookup sequence followed too many referrals
+    MaxReferrals(String),
+
+    /// When the root hints file cannot be read or parsed
+    InvalidRootHints(String),
+    /// When no root server can be used to start iterating from
+    NoRootHints,
+    /// When every name server of a zone failed to answer
+    NoNameserver(String),
+    /// When an upstream server didn't answer in time
+    UpstreamTimeout(SocketAddr),
+    /// When an upstream specification cannot be understood
+    InvalidUpstream(String),
+    /// When connecting to an upstream server fails
+    UpstreamConnectFailed(String),
+    /// When the connection to an upstream server closed before its response arrived
+    UpstreamClosed,
+    /// When setting up TLS, or a TLS handshake, fails
+    TlsFailed(String),
+    /// When an HTTP exchange with an upstream server fails
+    HttpFailed(String),
+    /// When a listener cannot be set up
+    ListenFailed(String),
+    /// When a blocklist cannot be read
+    InvalidBlocklist(String),
+    /// When an address block cannot be understood
+    InvalidCidr(String),
+    /// When the configuration is invalid, naming the offending key
+    InvalidConfig(String),
+    /// When a command-line argument cannot be understood
+    InvalidArgument(String),
+    /// When reloading cannot be set up
+    ReloadFailed(String),
+    /// When the query log cannot be opened or rotated
+    QueryLogFailed(String),
+    /// When the query database cannot be opened
+    QueryDbFailed(String),
+    /// When a signal cannot be handled
+    SignalFailed(String),
+    /// When a command cannot be sent to a running server
+    ControlFailed(String),
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
-            Error::PacketBufferOver512(s) => writeln!(f, "Buffer overflow: {s}")?,
-            _ => writeln!(f, "Error")?,
+            Error::PacketBufferOverflow(s) => write!(f, "Buffer overflow: {s}")?,
+            Error::MaxUpstreamQueries(qname) => write!(
+                f,
+                "Too many upstream queries (> {MAX_UPSTREAM_QUERIES}) while resolving {qname}"
+            )?,
+            Error::MaxNsDepth(qname) => write!(
+                f,
+                "Too many nested name server lookups (> {MAX_NS_DEPTH}) while resolving {qname}"
+            )?,
+            Error::MaxReferrals(qname) => write!(
+                f,
+                "Too many referrals (> {MAX_REFERRALS}) while resolving {qname}"
+            )?,
+            Error::InvalidRootHints(reason) => write!(f, "Invalid root hints: {reason}")?,
+            Error::NoRootHints => write!(f, "No usable root server")?,
+            Error::NoNameserver(qname) => {
+                write!(f, "No name server answered while resolving {qname}")?
+            }
+            Error::UpstreamTimeout(addr) => write!(f, "Timed out waiting for {addr}")?,
+            Error::InvalidUpstream(spec) => write!(f, "Invalid upstream: {spec}")?,
+            Error::UpstreamConnectFailed(reason) => {
+                write!(f, "Failed connecting to upstream: {reason}")?
+            }
+            Error::UpstreamClosed => write!(f, "Upstream connection closed")?,
+            Error::TlsFailed(reason) => write!(f, "TLS error: {reason}")?,
+            Error::HttpFailed(reason) => write!(f, "HTTP error: {reason}")?,
+            Error::ListenFailed(reason) => write!(f, "Cannot listen: {reason}")?,
+            Error::InvalidBlocklist(reason) => write!(f, "Invalid blocklist: {reason}")?,
+            Error::InvalidCidr(cidr) => write!(f, "Invalid address block: {cidr}")?,
+            Error::InvalidConfig(reason) => write!(f, "Invalid configuration: {reason}")?,
+            Error::InvalidArgument(reason) => write!(f, "Invalid argument: {reason}")?,
+            Error::ReloadFailed(reason) => write!(f, "Cannot reload: {reason}")?,
+            Error::QueryLogFailed(reason) => write!(f, "Cannot write the query log: {reason}")?,
+            Error::QueryDbFailed(reason) => write!(f, "Cannot open the query database: {reason}")?,
+            Error::SignalFailed(reason) => write!(f, "Cannot handle signal: {reason}")?,
+            Error::ControlFailed(reason) => write!(f, "Cannot send the command: {reason}")?,
+            _ => write!(f, "Error")?,
         }
 
         Ok(())
     }
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Default)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Default)]
 pub enum ResultCode {
     #[default]
     NoError = 0,
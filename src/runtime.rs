@@ -0,0 +1,17 @@
+use std::sync::OnceLock;
+
+use tokio::runtime::{Builder, Runtime};
+
+/// The Tokio runtime driving every connection-oriented transport (TLS, HTTPS). The resolution
+/// pipeline itself stays synchronous and blocks on it when needed.
+pub fn runtime() -> &'static Runtime {
+    static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+
+    RUNTIME.get_or_init(|| {
+        Builder::new_multi_thread()
+            .enable_all()
+            .thread_name("barthez-io")
+            .build()
+            .expect("failed to start the Tokio runtime")
+    })
+}
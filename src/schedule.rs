@@ -0,0 +1,116 @@
+use chrono::{Datelike, Days, NaiveDateTime, NaiveTime, Weekday};
+
+use crate::blocklist::Blocklist;
+
+/// When blocklists assigned with a schedule apply, in local time: from `from` to `to` on `days`
+/// (every day when empty). A window whose end isn't after its start ends the next day, so that
+/// `21:00` to `07:00` spans the night and `00:00` to `00:00` the whole day.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Schedule {
+    pub days: Vec<Weekday>,
+    pub from: NaiveTime,
+    pub to: NaiveTime,
+}
+
+impl Schedule {
+    fn starts_on(&self, day: Weekday) -> bool {
+        self.days.is_empty() || self.days.contains(&day)
+    }
+
+    /// Whether the local time `now` falls in the time window of a scheduled day.
+    pub fn active(&self, now: NaiveDateTime) -> bool {
+        let time = now.time();
+        if self.from < self.to {
+            return self.starts_on(now.weekday()) && self.from <= time && time < self.to;
+        }
+
+        // The window spans midnight: the evening belongs to today's window, and the morning to
+        // yesterday's
+        let yesterday = now.date() - Days::new(1);
+        (self.starts_on(now.weekday()) && time >= self.from)
+            || (self.starts_on(yesterday.weekday()) && time < self.to)
+    }
+}
+
+/// A blocklist only applying while its schedule is active.
+pub struct ScheduledBlocklist {
+    pub schedule: Schedule,
+    pub blocklist: Blocklist,
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use super::*;
+
+    fn schedule(days: &[Weekday], from: &str, to: &str) -> Schedule {
+        Schedule {
+            days: days.to_vec(),
+            from: from.parse().unwrap(),
+            to: to.parse().unwrap(),
+        }
+    }
+
+    /// 2026-10-19 is a Monday.
+    fn at(day: u32, time: &str) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2026, 10, day)
+            .unwrap()
+            .and_time(time.parse().unwrap())
+    }
+
+    #[test]
+    fn applies_within_a_daytime_window() {
+        let schedule = schedule(&[], "09:00", "17:00");
+        assert!(!schedule.active(at(19, "08:59:59")));
+        assert!(schedule.active(at(19, "09:00")));
+        assert!(schedule.active(at(19, "16:59:59")));
+        assert!(!schedule.active(at(19, "17:00")));
+    }
+
+    #[test]
+    fn spans_the_night() {
+        let schedule = schedule(&[], "21:00", "07:00");
+        assert!(!schedule.active(at(19, "20:59:59")));
+        assert!(schedule.active(at(19, "21:00")));
+        assert!(schedule.active(at(19, "23:59:59")));
+        assert!(schedule.active(at(20, "00:00")));
+        assert!(schedule.active(at(20, "06:59:59")));
+        assert!(!schedule.active(at(20, "07:00")));
+        assert!(!schedule.active(at(20, "12:00")));
+    }
+
+    #[test]
+    fn spans_the_whole_day_when_from_and_to_are_equal() {
+        let midnight = schedule(&[], "00:00", "00:00");
+        assert!(midnight.active(at(19, "00:00")));
+        assert!(midnight.active(at(19, "12:00")));
+        assert!(midnight.active(at(19, "23:59:59")));
+
+        let morning = schedule(&[], "08:00", "08:00");
+        assert!(morning.active(at(19, "07:59:59")));
+        assert!(morning.active(at(19, "08:00")));
+    }
+
+    #[test]
+    fn applies_on_the_days_it_starts_on() {
+        let schedule = schedule(&[Weekday::Fri], "21:00", "07:00");
+        // Thursday evening, then the Friday morning ending Thursday's window
+        assert!(!schedule.active(at(22, "22:00")));
+        assert!(!schedule.active(at(23, "06:00")));
+        // Friday evening, then the Saturday morning ending it
+        assert!(schedule.active(at(23, "22:00")));
+        assert!(schedule.active(at(24, "06:00")));
+        assert!(!schedule.active(at(24, "07:00")));
+        assert!(!schedule.active(at(24, "22:00")));
+    }
+
+    #[test]
+    fn spans_the_whole_of_the_days_it_starts_on() {
+        let schedule = schedule(&[Weekday::Mon], "00:00", "00:00");
+        assert!(!schedule.active(at(18, "23:59:59")));
+        assert!(schedule.active(at(19, "00:00")));
+        assert!(schedule.active(at(19, "23:59:59")));
+        assert!(!schedule.active(at(20, "00:00")));
+    }
+}
@@ -1,56 +1,627 @@
-use crate::packet::{Packet, PacketBuffer};
-use crate::record::RecordType;
+use crate::acl::Acl;
+use crate::blocklist::{BlockingMode, Blocklist, CustomLists, ListLoader};
+use crate::cache::Cache;
+use crate::cache::CachedEntry;
+use crate::clock::{Clock, SystemClock};
+use crate::config::{Config, ScheduledListConfig};
+use crate::globals::{
+    CACHE_MAX_ENTRIES, MAX_MINIMISE_COUNT, MAX_NS_DEPTH, MAX_REFERRALS, MAX_UPSTREAM_QUERIES,
+    MINIMISE_ONE_LAB, PAUSE_CHECK_INTERVAL, SERVE_STALE_WINDOW, STALE_REFRESH_INTERVAL,
+    UPSTREAM_TIMEOUT,
+};
+use crate::groups::{Group, Groups};
+use crate::hints::RootHints;
+use crate::log;
+use crate::metrics::Metrics;
+use crate::nameservers::NameserverSelector;
+use crate::packet::{is_subdomain, Packet, PacketBuffer};
+use crate::pause::{BlockingStatus, Pause, Pauses};
+use crate::querydb::QueryDb;
+use crate::querylog::{Outcome, QueryLog, QueryLogEntry, RecentQueries};
+use crate::question::Question;
+use crate::ratelimit::{RateLimiter, RrlAction};
+use crate::record::{Record, RecordType};
 use crate::result::{Error, Result, ResultCode};
+use crate::schedule::{Schedule, ScheduledBlocklist};
+use crate::stats::{Stats, StatsReport};
+use crate::upstream::{self, Upstream, UpstreamOptions};
 
+use std::collections::HashMap;
 use std::fmt::{self, Formatter};
-use std::net::Ipv4Addr;
-use std::net::UdpSocket;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Local, TimeDelta, Utc};
+use serde::Deserialize;
+
+/// The address families that can be used to reach upstream name servers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IpFamilies {
+    Ipv4,
+    Ipv6,
+    #[default]
+    Both,
+}
+
+impl IpFamilies {
+    pub fn allows(&self, addr: &IpAddr) -> bool {
+        match self {
+            IpFamilies::Ipv4 => addr.is_ipv4(),
+            IpFamilies::Ipv6 => addr.is_ipv6(),
+            IpFamilies::Both => true,
+        }
+    }
+}
+
+/// The QNAME minimisation modes of [RFC9156](https://www.rfc-editor.org/rfc/rfc9156).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QnameMinimisation {
+    /// The full query name is sent to every server
+    Off,
+    /// Minimise, but fall back to the full query name when a server answers a minimised query
+    /// with an error
+    #[default]
+    Relaxed,
+    /// Minimise, and take errors to minimised queries as the final answer
+    Strict,
+}
+
+/// A response, along with how it was obtained.
+pub struct Resolution {
+    pub response: Packet,
+    pub outcome: Outcome,
+    /// The upstream, or authoritative name server, which sent the response
+    pub upstream: Option<String>,
+    /// The blocked name further down the `CNAME` chain of the answer, when the query was blocked
+    /// for it
+    pub cname: Option<String>,
+}
+
+impl Resolution {
+    /// A response obtained without asking any upstream server.
+    fn local(response: Packet, outcome: Outcome) -> Self {
+        Self {
+            response,
+            outcome,
+            upstream: None,
+            cname: None,
+        }
+    }
+}
+
+/// A zone whose queries are forwarded to upstream servers instead of being resolved recursively.
+/// Forwarding every query is done with the root zone `""`.
+pub struct ForwardZone {
+    pub zone: String,
+    pub upstreams: Vec<Box<dyn Upstream>>,
+    /// Picks the upstream to use, by index in `upstreams`
+    selector: NameserverSelector<usize>,
+}
+
+impl ForwardZone {
+    pub fn new(zone: &str, upstreams: Vec<Box<dyn Upstream>>) -> Self {
+        Self {
+            zone: zone.trim_end_matches('.').to_lowercase(),
+            upstreams,
+            selector: NameserverSelector::new(),
+        }
+    }
+}
+
+/// The settings of the server that can be replaced while it runs. They are swapped as a whole on
+/// reload, queries being answered with either the old or the new settings but never a mix.
+pub struct Policy {
+    /// Zones forwarded to upstream servers
+    forward_zones: Arc<Vec<ForwardZone>>,
+    /// Domains answered by ourselves rather than resolved
+    blocklist: Blocklist,
+    /// Clients with blocklists of their own, replacing `blocklist`
+    groups: Groups,
+    /// Clients allowed to use us, the others being refused
+    acl: Acl,
+    /// Keeps clients of the UDP listener from flooding us, or having us flood others
+    rate_limiter: Arc<RateLimiter>,
+    /// Where answered queries are logged
+    query_log: Arc<QueryLog>,
+    /// Where answered queries are stored for the long term
+    query_db: Arc<QueryDb>,
+}
+
+impl Policy {
+    /// Builds the policy of `config`, loading the files it references. Upstreams, rate limits, the
+    /// query log and the query database are taken over from the `current` policy (built from
+    /// `current_config`) when their settings didn't change, so that upstream connections and
+    /// statistics, rate limiting state and the files of the query log survive reloads.
+    fn from_config(config: &Config, current: Option<(&Config, &Policy)>) -> Result<Self> {
+        let forward_zones = match current {
+            Some((current_config, current)) if current_config.upstreams == config.upstreams => {
+                Arc::clone(&current.forward_zones)
+            }
+            _ => Arc::new(forward_zones(config)?),
+        };
+        let rate_limiter = match current {
+            Some((current_config, current)) if current_config.rate_limit == config.rate_limit => {
+                Arc::clone(&current.rate_limiter)
+            }
+            _ => Arc::new(RateLimiter::new(config.rate_limit.clone())),
+        };
+        let query_log = match current {
+            Some((current_config, current)) if current_config.query_log == config.query_log => {
+                Arc::clone(&current.query_log)
+            }
+            _ => Arc::new(QueryLog::new(config.query_log.clone())?),
+        };
+        let query_db = match current {
+            Some((current_config, current)) if current_config.query_db == config.query_db => {
+                Arc::clone(&current.query_db)
+            }
+            _ => Arc::new(QueryDb::open(&config.query_db)?),
+        };
+
+        let mut acl = Acl::default();
+        for cidr in &config.access.allow {
+            acl.allow(*cidr);
+        }
+        for cidr in &config.access.deny {
+            acl.deny(*cidr);
+        }
+
+        let blocking = &config.blocking;
+        let mut loader = ListLoader::new(blocking.index_dir.as_deref());
+        let blocklist = load_blocklist(
+            &mut loader,
+            "blocking",
+            &blocking.lists,
+            &blocking.allowlists,
+            &blocking.scheduled,
+            blocking.mode,
+        )?;
+        let mut groups = Vec::new();
+        for (i, group) in config.groups.iter().enumerate() {
+            let blocklist = load_blocklist(
+                &mut loader,
+                &format!("groups[{i}]"),
+                &group.lists,
+                &group.allowlists,
+                &group.scheduled,
+                group.mode.unwrap_or(blocking.mode),
+            )?;
+            groups.push(Group::new(
+                group.name.clone(),
+                group.clients.clone(),
+                blocklist,
+            ));
+        }
+
+        Ok(Self {
+            forward_zones,
+            blocklist,
+            groups: Groups::new(groups),
+            acl,
+            rate_limiter,
+            query_log,
+            query_db,
+        })
+    }
+}
+
+impl Policy {
+    pub fn blocklist(&self) -> &Blocklist {
+        &self.blocklist
+    }
+
+    /// The group `client` belongs to, if any.
+    pub fn group(&self, client: IpAddr) -> Option<&Group> {
+        self.groups.find(client)
+    }
+
+    pub fn rate_limiter(&self) -> &RateLimiter {
+        &self.rate_limiter
+    }
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            forward_zones: Arc::new(Vec::new()),
+            blocklist: Blocklist::default(),
+            groups: Groups::default(),
+            acl: Acl::default(),
+            rate_limiter: Arc::new(RateLimiter::default()),
+            query_log: Arc::new(QueryLog::default()),
+            query_db: Arc::new(QueryDb::default()),
+        }
+    }
+}
 
 pub struct Server {
     local_addr: String,
+    /// Local address used for queries sent to IPv6 name servers
+    local_addr_v6: String,
+    /// Local port queries to name servers are sent from, a new one for every lookup when 0
     local_port: u16,
+    /// Taken by lookups while they use `local_port`, when it's fixed
+    local_port_lock: Mutex<()>,
+    /// Address families used to reach upstream name servers
+    ip_families: IpFamilies,
+    /// Whether queries sent to upstream name servers only reveal the labels they need
+    qname_minimisation: QnameMinimisation,
+    /// Root name servers every iteration starts from
+    root_hints: RootHints,
+    /// Port name servers are queried on, only other than 53 in tests
+    ns_port: u16,
+    /// Keeps track of the responsiveness of upstream name servers
+    nameservers: NameserverSelector,
+    /// Responses of upstream servers, served until they expire (or later when they are stale)
+    cache: Cache,
+    /// Sends the names to refresh to the refresher thread, once started
+    refresher: OnceLock<Sender<(String, RecordType)>>,
+    /// The settings that can be reloaded
+    policy: RwLock<Arc<Policy>>,
+    /// The configuration the server was built from, or last reloaded
+    config: Mutex<Config>,
+    /// Statistics of the queries answered since the start
+    stats: Stats,
+    /// Metrics exposed to Prometheus
+    metrics: Metrics,
+    /// The latest queries answered
+    recent_queries: RecentQueries,
+    /// Domains blocked and allowed through the management API
+    custom_lists: RwLock<CustomLists>,
+    /// Tells the time blocking pauses and schedules are checked against
+    clock: Arc<dyn Clock>,
+    /// Where blocking is disabled, for every client or some of them
+    pauses: Mutex<Pauses>,
+    /// Wakes the thread enabling blocking again once pauses are over, once started
+    pause_timer: OnceLock<Sender<()>>,
 }
 
 impl Server {
     pub fn new(addr: String, port: u16) -> Self {
         Self {
             local_addr: addr,
+            local_addr_v6: "::".to_string(),
             local_port: port,
+            local_port_lock: Mutex::new(()),
+            ip_families: IpFamilies::default(),
+            qname_minimisation: QnameMinimisation::default(),
+            root_hints: RootHints::default(),
+            ns_port: 53,
+            nameservers: NameserverSelector::new(),
+            cache: Cache::new(CACHE_MAX_ENTRIES, SERVE_STALE_WINDOW),
+            refresher: OnceLock::new(),
+            policy: RwLock::new(Arc::new(Policy::default())),
+            config: Mutex::new(Config::default()),
+            stats: Stats::new(),
+            metrics: Metrics::default(),
+            recent_queries: RecentQueries::default(),
+            custom_lists: RwLock::new(CustomLists::default()),
+            clock: Arc::new(SystemClock),
+            pauses: Mutex::new(Pauses::default()),
+            pause_timer: OnceLock::new(),
+        }
+    }
+
+    /// Builds a server out of `config`, loading every file it references, telling the time with
+    /// `clock`. Root hints that cannot be read are replaced by the built-in ones, but any other
+    /// file failing is an error naming the setting it comes from.
+    pub fn from_config(config: &Config, clock: Arc<dyn Clock>) -> Result<Self> {
+        let resolution = &config.resolution;
+        let mut server = Server::new("0.0.0.0".to_string(), resolution.query_port);
+        match resolution.query_address {
+            IpAddr::V4(addr) => server.local_addr = addr.to_string(),
+            IpAddr::V6(addr) => server.local_addr_v6 = addr.to_string(),
+        }
+        server.set_ip_families(resolution.ip_families);
+        server.set_qname_minimisation(resolution.qname_minimisation);
+        match RootHints::from_file(&resolution.root_hints) {
+            Ok(hints) => server.set_root_hints(hints),
+            Err(e) => log::warning!("{}, using built-in root hints", e),
+        }
+
+        server.cache.set_max_entries(config.cache.max_entries);
+        server.set_serve_stale(Duration::from_secs(config.cache.serve_stale));
+        server.set_prefetch(config.cache.prefetch);
+
+        server.policy = RwLock::new(Arc::new(Policy::from_config(config, None)?));
+        if let Some(path) = &config.blocking.custom {
+            let custom_lists = CustomLists::load(path)
+                .map_err(|e| Error::InvalidConfig(format!("blocking.custom: {e}")))?;
+            server.custom_lists = RwLock::new(custom_lists);
+        }
+        server.config = Mutex::new(config.clone());
+        server.clock = clock;
+
+        Ok(server)
+    }
+
+    /// The settings currently in use. Holding on to them for the whole handling of a query keeps
+    /// it consistent, even if a reload happens meanwhile.
+    pub fn policy(&self) -> Arc<Policy> {
+        Arc::clone(&self.policy.read().unwrap())
+    }
+
+    /// Reports the statistics, with the `n` top domains and clients over the last `window`, and
+    /// whether blocking is enabled.
+    pub fn report(&self, window: Duration, n: usize) -> StatsReport {
+        StatsReport {
+            blocking: Some(self.blocking_status()),
+            ..self.stats.report(window, n)
+        }
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Number of responses in the cache.
+    pub fn cache_len(&self) -> usize {
+        self.cache.len()
+    }
+
+    /// Empties the cache, returning how many responses it held.
+    pub fn flush_cache(&self) -> usize {
+        let count = self.cache.clear();
+        log::info!("Flushed {} responses from the cache", count);
+        count
+    }
+
+    /// Describes the responses cached for `qname`.
+    pub fn cached(&self, qname: &str) -> Vec<CachedEntry> {
+        self.cache.entries(qname)
+    }
+
+    /// Returns the latest `limit` queries answered, the most recent first, only keeping those
+    /// of `client` if given.
+    pub fn recent_queries(&self, limit: usize, client: Option<IpAddr>) -> Vec<QueryLogEntry> {
+        self.recent_queries.latest(limit, |entry| {
+            client.is_none_or(|client| entry.client == client)
+        })
+    }
+
+    pub fn blocking_status(&self) -> BlockingStatus {
+        self.pauses.lock().unwrap().status(self.clock.now())
+    }
+
+    /// Disables blocking for `client`, or every client, for `duration` or until it is enabled
+    /// again. Durations too long to tell when they end are as good as the latter.
+    pub fn disable_blocking(&self, client: Option<IpAddr>, duration: Option<Duration>) {
+        let until = duration
+            .and_then(|duration| TimeDelta::from_std(duration).ok())
+            .and_then(|duration| self.clock.now().checked_add_signed(duration));
+        let pause = Pause { until };
+        self.pauses.lock().unwrap().disable(client, pause);
+        log::info!("Blocking disabled{} {}", for_client(client), pause);
+
+        if let Some(sender) = self.pause_timer.get() {
+            let _ = sender.send(());
+        }
+    }
+
+    /// Enables blocking again for `client`, or every client.
+    pub fn enable_blocking(&self, client: Option<IpAddr>) {
+        if self.pauses.lock().unwrap().enable(client) {
+            log::info!("Blocking enabled{}", for_client(client));
+        }
+    }
+
+    /// Starts the thread enabling blocking again as pauses end, so that it gets logged. Until
+    /// then, pauses over are merely ignored.
+    pub fn start_pause_timer(self: &Arc<Self>) {
+        let (sender, receiver) = mpsc::channel();
+        if self.pause_timer.set(sender).is_err() {
+            return;
+        }
+
+        let server = Arc::clone(self);
+        thread::spawn(move || server.run_pause_timer(receiver));
+    }
+
+    fn run_pause_timer(&self, receiver: Receiver<()>) {
+        loop {
+            // Sleep until a pause is disabled or enabled, or the next one ends
+            let next = self.pauses.lock().unwrap().next_deadline();
+            let timeout = next
+                .and_then(|next| (next - self.clock.now()).to_std().ok())
+                .unwrap_or(PAUSE_CHECK_INTERVAL)
+                .min(PAUSE_CHECK_INTERVAL);
+            match receiver.recv_timeout(timeout) {
+                Ok(()) | Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+
+            for client in self.pauses.lock().unwrap().expire(self.clock.now()) {
+                log::info!("Blocking enabled{}", for_client(client));
+            }
+        }
+    }
+
+    pub fn custom_lists(&self) -> CustomLists {
+        self.custom_lists.read().unwrap().clone()
+    }
+
+    /// Applies `edit` to the domains blocked and allowed through the management API, saving
+    /// them to the `blocking.custom` file, if any, when `edit` reports a change. Returns the
+    /// result of `edit`.
+    pub fn edit_custom_lists(&self, edit: impl FnOnce(&mut CustomLists) -> bool) -> Result<bool> {
+        let mut custom_lists = self.custom_lists.write().unwrap();
+        let changed = edit(&mut custom_lists);
+        if changed {
+            if let Some(path) = &self.config().blocking.custom {
+                custom_lists.save(path)?;
+            }
         }
+
+        Ok(changed)
     }
 
-    pub fn lookup(
+    /// Returns the domain `qname` is blocked as, if blocking is enabled for `client` and it is
+    /// blocked. The domains blocked or allowed through the management API take precedence over
+    /// `blocklist`.
+    fn blocked(&self, blocklist: &Blocklist, client: IpAddr, qname: &str) -> Option<String> {
+        let now = self.clock.now();
+        if self.pauses.lock().unwrap().paused(client, now) {
+            return None;
+        }
+
+        let custom_lists = self.custom_lists.read().unwrap();
+        if custom_lists.allows(qname) {
+            return None;
+        }
+        custom_lists
+            .blocked(qname)
+            .map(str::to_owned)
+            .or_else(|| blocklist.blocked(qname, now.with_timezone(&Local).naive_local()))
+    }
+
+    /// Blocks the response of `resolution` when a name of its `CNAME` chain is blocked for
+    /// `client`, as trackers hide behind first-party names aliased to their own ("CNAME
+    /// cloaking"). Names allowed for `client` are never blocked this way.
+    fn uncloak(
         &self,
-        qname: &str,
-        qtype: RecordType,
-        server: (Ipv4Addr, u16),
-    ) -> Result<Packet> {
-        // Forge a query packet
-        let mut send_packet: Packet = Default::default();
-        send_packet.header.recursion_desired = true;
-        send_packet.add_question(qname, qtype)?;
+        blocklist: &Blocklist,
+        client: IpAddr,
+        question: &Question,
+        resolution: Resolution,
+    ) -> Resolution {
+        if blocklist.allows(&question.name)
+            || self.custom_lists.read().unwrap().allows(&question.name)
+        {
+            return resolution;
+        }
 
-        // Write that packet to a buffer to send
-        let mut send_buffer = PacketBuffer::new();
-        send_packet.write(&mut send_buffer)?;
+        let blocked = resolution
+            .response
+            .answers
+            .iter()
+            .find_map(|record| match record {
+                Record::CNAME { host, .. } => self
+                    .blocked(blocklist, client, host)
+                    .map(|domain| (host, domain)),
+                _ => None,
+            });
+        let Some((cname, domain)) = blocked else {
+            return resolution;
+        };
 
-        let socket = UdpSocket::bind((self.local_addr.to_owned(), self.local_port))
-            .map_err(|_| Error::UDPBindFailed)?;
-        socket
-            .send_to(&send_buffer.bytes[0..send_buffer.pos()], server)
-            .map_err(|e| {
-                eprintln!("{e}");
-                Error::UDPSendFailed
-            })?;
+        log::debug!(
+            "Blocked {} for its CNAME {} (listed as {})",
+            question.name,
+            cname,
+            domain
+        );
+        Resolution {
+            response: blocklist.response(&question.name, question.question_type),
+            outcome: Outcome::Blocked,
+            cname: Some(cname.clone()),
+            ..resolution
+        }
+    }
 
-        let mut recv_buffer = PacketBuffer::new();
-        socket
-            .recv_from(&mut recv_buffer.bytes)
-            .map_err(|_| Error::UDPRecvFailed)?;
+    /// The configuration currently in use.
+    pub fn config(&self) -> Config {
+        self.config.lock().unwrap().clone()
+    }
+
+    /// Applies `config` to the running server: blocklists are reloaded, and so are access lists,
+    /// rate limits, upstreams and the log level. The new settings are all built before being
+    /// swapped in at once, and if anything fails the current ones are kept. Settings that can only
+    /// be applied on restart are reported as such.
+    pub fn reload(&self, config: &Config) -> Result<()> {
+        let mut current_config = self.config.lock().unwrap();
+
+        let policy = Policy::from_config(config, Some((&current_config, &self.policy())))?;
+        *self.policy.write().unwrap() = Arc::new(policy);
+        log::set_level(config.logging.level);
+
+        for (section, changed) in [
+            ("listen", current_config.listen != config.listen),
+            ("resolution", current_config.resolution != config.resolution),
+            ("cache", current_config.cache != config.cache),
+        ] {
+            if changed {
+                log::warning!("Changes to [{}] will only apply on restart", section);
+            }
+        }
+        *current_config = config.clone();
+
+        Ok(())
+    }
+
+    pub fn set_ip_families(&mut self, families: IpFamilies) {
+        self.ip_families = families;
+    }
+
+    pub fn set_qname_minimisation(&mut self, mode: QnameMinimisation) {
+        self.qname_minimisation = mode;
+    }
+
+    /// Sets how long expired responses can be served for when upstream servers are unreachable.
+    /// A zero `window` disables serving stale responses.
+    pub fn set_serve_stale(&mut self, window: Duration) {
+        self.cache.set_stale_window(window);
+    }
+
+    /// Enables or disables the prefetching of popular cache entries about to expire.
+    pub fn set_prefetch(&mut self, prefetch: bool) {
+        self.cache.set_prefetch(prefetch);
+    }
+
+    pub fn set_root_hints(&mut self, hints: RootHints) {
+        self.root_hints = hints;
+    }
+
+    #[cfg(test)]
+    pub(crate) fn set_ns_port(&mut self, port: u16) {
+        self.ns_port = port;
+    }
+
+    /// Primes the root hints as described in [RFC8109](https://www.rfc-editor.org/rfc/rfc8109):
+    /// the `NS` records of the root zone are queried from the configured hints, and the answer
+    /// replaces them. Each hint is tried in turn until one of them answers.
+    pub fn prime_root_hints(&mut self) -> Result<()> {
+        let mut last_error = Error::NoRootHints;
+
+        for addr in self.root_hints.addrs(self.ip_families) {
+            log::info!("priming root hints with ns {}", addr);
+
+            match self.lookup("", RecordType::NS, SocketAddr::new(addr, self.ns_port)) {
+                Ok(response) => match RootHints::from_priming_response(&response) {
+                    Some(hints) => {
+                        self.root_hints = hints;
+                        return Ok(());
+                    }
+                    None => log::warning!("Empty priming response from {}", addr),
+                },
+                Err(e) => {
+                    log::warning!("Priming with {} failed: {}", addr, e);
+                    last_error = e;
+                }
+            }
+        }
+
+        Err(last_error)
+    }
 
-        let recv_packet = Packet::try_from(recv_buffer)?;
+    pub fn lookup(&self, qname: &str, qtype: RecordType, server: SocketAddr) -> Result<Packet> {
+        // Forge a query packet
+        let send_packet = query_packet(qname, qtype)?;
+
+        // The local socket must be of the same family as the name server
+        let local_addr = if server.is_ipv4() {
+            &self.local_addr
+        } else {
+            &self.local_addr_v6
+        };
 
-        Ok(recv_packet)
+        // A fixed port can only be bound by a single lookup at a time, the others waiting for it
+        let _port = (self.local_port != 0).then(|| self.local_port_lock.lock().unwrap());
+        upstream::exchange(&send_packet, server, (local_addr, self.local_port))
     }
 
     pub fn handle_query(&self, socket: &UdpSocket) -> Result<()> {
@@ -68,7 +639,54 @@ impl Server {
 
         // Next, `DnsPacket::from_buffer` is used to parse the raw bytes into
         // a `DnsPacket`.
-        let mut request = Packet::try_from(req_buffer)?;
+        let request = Packet::try_from(req_buffer)?;
+
+        // Rate limits only apply to UDP, whose source addresses can be spoofed and which answers
+        // without any handshake. Queries over the limit are silently dropped.
+        let policy = self.policy();
+        if !policy.rate_limiter.allow_query(src.ip()) {
+            return Ok(());
+        }
+
+        let mut packet = self.answer_with(&policy, request, src);
+        match policy.rate_limiter.limit_response(src.ip(), &packet) {
+            RrlAction::Send => {}
+            RrlAction::Slip => packet = truncated(&packet),
+            RrlAction::Drop => return Ok(()),
+        }
+
+        // The only thing remaining is to encode our response and send it off! Responses too large
+        // for UDP are sent truncated, for the client to retry over TCP.
+        let mut res_buffer = PacketBuffer::new();
+        match packet.write(&mut res_buffer) {
+            Ok(()) => {}
+            Err(Error::PacketBufferOverflow(_)) => {
+                res_buffer = PacketBuffer::new();
+                truncated(&packet).write(&mut res_buffer)?;
+            }
+            Err(e) => return Err(e),
+        }
+
+        let len = res_buffer.pos();
+        let data = res_buffer.get_range(0, len)?;
+
+        socket
+            .send_to(data, src)
+            .map_err(|_| Error::UDPSendFailed)?;
+
+        Ok(())
+    }
+
+    /// Builds the response to the `request` of `client`, whatever transport it came through.
+    /// Queries with a question are logged to the query log, along with how they were answered.
+    pub fn answer(&self, request: Packet, client: SocketAddr) -> Packet {
+        self.answer_with(&self.policy(), request, client)
+    }
+
+    /// Builds the response to the `request` of `client` with the settings of `policy`, which
+    /// are used from start to finish even if a reload happens meanwhile.
+    fn answer_with(&self, policy: &Policy, mut request: Packet, client: SocketAddr) -> Packet {
+        let received = (self.clock.now(), Instant::now());
 
         // Create and initialize the response packet
         let mut packet: Packet = Default::default();
@@ -77,36 +695,106 @@ impl Server {
         packet.header.recursion_available = true;
         packet.header.is_response = true;
 
+        // Clients we don't serve are told so, with their question echoed back
+        if !policy.acl.allows(client.ip()) {
+            packet.header.recursion_available = false;
+            packet.header.response_code = ResultCode::Refused;
+            packet.header.question_count = request.questions.len() as u16;
+            packet.questions = request.questions;
+            self.log_query(
+                policy,
+                client.ip(),
+                received,
+                &packet,
+                Outcome::Refused,
+                None,
+                None,
+            );
+            return packet;
+        }
+
         // In the normal case, exactly one question is present
         if let Some(question) = request.questions.pop() {
-            println!("Received query: {}", question);
+            log::debug!("Received query from {}: {}", client, question);
 
             // Since all is set up and as expected, the query can be forwarded to the
             // target server. There's always the possibility that the query will
             // fail, in which case the `SERVFAIL` response code is set to indicate
             // as much to the client. If rather everything goes as planned, the
             // question and response records as copied into our response packet.
-            if let Ok(result) = self.recursive_lookup(&question.name, question.question_type) {
-                println!("Result: {}", result);
+            // Blocked domains never reach upstream servers, whatever transport the query came
+            // through. Clients of a group are blocked with the lists and mode of the group.
+            let group = policy.group(client.ip());
+            let blocklist = group.map_or(&policy.blocklist, |group| &group.blocklist);
+            let result = match self.blocked(blocklist, client.ip(), &question.name) {
+                Some(domain) => {
+                    match group {
+                        Some(group) => log::debug!(
+                            "Blocked {} for group {} (listed as {})",
+                            question.name,
+                            group.name,
+                            domain
+                        ),
+                        None => log::debug!("Blocked {} (listed as {})", question.name, domain),
+                    }
+                    let response = blocklist.response(&question.name, question.question_type);
+                    Ok(Resolution::local(response, Outcome::Blocked))
+                }
+                None => self
+                    .resolve(policy, &question.name, question.question_type)
+                    .map(|resolution| self.uncloak(blocklist, client.ip(), &question, resolution)),
+            };
 
-                packet.questions.push(question);
-                packet.header.question_count += 1;
-                packet.header.response_code = result.header.response_code;
+            packet.questions.push(question);
+            packet.header.question_count += 1;
 
-                for rec in result.answers {
-                    packet.answers.push(rec);
-                    packet.header.answer_count += 1;
-                }
-                for rec in result.authorities {
-                    packet.authorities.push(rec);
-                    packet.header.authority_count += 1;
+            match result {
+                Ok(Resolution {
+                    response: result,
+                    outcome,
+                    upstream,
+                    cname,
+                }) => {
+                    log::debug!("Result: {}", result);
+
+                    packet.header.response_code = result.header.response_code;
+
+                    for rec in result.answers {
+                        packet.answers.push(rec);
+                        packet.header.answer_count += 1;
+                    }
+                    for rec in result.authorities {
+                        packet.authorities.push(rec);
+                        packet.header.authority_count += 1;
+                    }
+                    for rec in result.additionals {
+                        packet.additionals.push(rec);
+                        packet.header.additional_count += 1;
+                    }
+
+                    self.log_query(
+                        policy,
+                        client.ip(),
+                        received,
+                        &packet,
+                        outcome,
+                        upstream,
+                        cname,
+                    );
                 }
-                for rec in result.additionals {
-                    packet.additionals.push(rec);
-                    packet.header.additional_count += 1;
+                Err(e) => {
+                    log::warning!("Failed resolving {}: {}", packet.questions[0].name, e);
+                    packet.header.response_code = ResultCode::ServFail;
+                    self.log_query(
+                        policy,
+                        client.ip(),
+                        received,
+                        &packet,
+                        Outcome::Failed,
+                        None,
+                        None,
+                    );
                 }
-            } else {
-                packet.header.response_code = ResultCode::ServFail;
             }
         }
         // Being mindful of how unreliable input data from arbitrary senders can be, we
@@ -116,33 +804,316 @@ impl Server {
             packet.header.response_code = ResultCode::FormErr;
         }
 
-        // The only thing remaining is to encode our response and send it off!
-        let mut res_buffer = PacketBuffer::new();
-        packet.write(&mut res_buffer)?;
+        packet
+    }
 
-        let len = res_buffer.pos();
-        let data = res_buffer.get_range(0, len)?;
+    /// Logs the `response` sent to `client` for a query received at `received`, if it has a
+    /// question, to the query log and the query database, and counts it in the statistics.
+    #[allow(clippy::too_many_arguments)]
+    fn log_query(
+        &self,
+        policy: &Policy,
+        client: IpAddr,
+        received: (DateTime<Utc>, Instant),
+        response: &Packet,
+        outcome: Outcome,
+        upstream: Option<String>,
+        cname: Option<String>,
+    ) {
+        let Some(question) = response.questions.first() else {
+            return;
+        };
 
-        socket
-            .send_to(data, src)
-            .map_err(|_| Error::UDPSendFailed)?;
+        let entry = QueryLogEntry {
+            timestamp: received.0,
+            client,
+            qname: question.name.clone(),
+            qtype: question.question_type,
+            outcome,
+            rcode: response.header.response_code,
+            upstream,
+            cname,
+            latency: received.1.elapsed(),
+        };
+        policy.query_log.log(&entry);
+        policy.query_db.insert(&entry);
+        self.stats.record(&entry);
+        self.metrics.record(&entry);
+        self.recent_queries.push(entry);
+    }
 
-        Ok(())
+    /// Answers `qname` from the cache when possible, otherwise resolves it recursively and caches
+    /// the response. If that fails, a stale response is served instead when the cache still holds
+    /// one, and refreshed in the background. Popular entries about to expire are also refreshed
+    /// in the background.
+    pub fn resolve(&self, policy: &Policy, qname: &str, qtype: RecordType) -> Result<Resolution> {
+        if let Some(hit) = self.cache.get(qname, qtype) {
+            // Refresh popular entries before they expire, so that clients never wait for them
+            if hit.prefetch {
+                self.refresh_in_background(qname, qtype);
+            }
+            return Ok(Resolution::local(hit.response, Outcome::Cached));
+        }
+
+        // Upstream servers were unreachable very recently, don't make the client wait for them
+        // to time out again and serve the stale response right away.
+        if self
+            .cache
+            .refresh_failed_within(qname, qtype, STALE_REFRESH_INTERVAL)
+        {
+            if let Some(response) = self.cache.get_stale(qname, qtype) {
+                return Ok(Resolution::local(response, Outcome::Stale));
+            }
+        }
+
+        match self.upstream_lookup(policy, qname, qtype) {
+            Ok(resolution) => {
+                self.cache.insert(qname, qtype, &resolution.response);
+                Ok(resolution)
+            }
+            Err(e) => match self.cache.get_stale(qname, qtype) {
+                Some(response) => {
+                    log::warning!("Failed resolving {}, serving stale response: {}", qname, e);
+                    self.cache.set_refresh_failed(qname, qtype);
+                    self.refresh_in_background(qname, qtype);
+                    Ok(Resolution::local(response, Outcome::Stale))
+                }
+                None => Err(e),
+            },
+        }
+    }
+
+    /// Starts the thread refreshing cache entries in the background, see `refresh_in_background`.
+    pub fn start_refresher(self: &Arc<Self>) {
+        let (sender, receiver) = mpsc::channel();
+        if self.refresher.set(sender).is_err() {
+            return;
+        }
+
+        let server = Arc::clone(self);
+        thread::spawn(move || server.run_refresher(receiver));
+    }
+
+    /// Asks the refresher thread to resolve `qname` again and update its cache entry, be it stale
+    /// or about to expire. Nothing happens if the refresher isn't running.
+    fn refresh_in_background(&self, qname: &str, qtype: RecordType) {
+        if let Some(sender) = self.refresher.get() {
+            let _ = sender.send((qname.to_lowercase(), qtype));
+        }
+    }
+
+    fn run_refresher(&self, receiver: Receiver<(String, RecordType)>) {
+        // The names to refresh, along with when to try next
+        let mut pending: HashMap<(String, RecordType), Instant> = HashMap::new();
+
+        loop {
+            // Sleep until a new name is to be refreshed or a pending one is due
+            let now = Instant::now();
+            let timeout = pending
+                .values()
+                .min()
+                .map(|next| next.saturating_duration_since(now))
+                .unwrap_or(STALE_REFRESH_INTERVAL);
+            match receiver.recv_timeout(timeout) {
+                Ok(key) => {
+                    pending.entry(key).or_insert(now);
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+
+            let now = Instant::now();
+            let due: Vec<(String, RecordType)> = pending
+                .iter()
+                .filter(|(_, next)| **next <= now)
+                .map(|(key, _)| key.clone())
+                .collect();
+
+            for (qname, qtype) in due {
+                match self.upstream_lookup(&self.policy(), &qname, qtype) {
+                    Ok(resolution) => {
+                        self.cache.insert(&qname, qtype, &resolution.response);
+                        self.cache.end_prefetch(&qname, qtype);
+                        pending.remove(&(qname, qtype));
+                    }
+                    // Upstream is still unreachable, try again later as long as there is a stale
+                    // response to refresh.
+                    Err(_) => {
+                        self.cache.set_refresh_failed(&qname, qtype);
+                        self.cache.end_prefetch(&qname, qtype);
+                        if self.cache.get_stale(&qname, qtype).is_some() {
+                            pending.insert((qname, qtype), now + STALE_REFRESH_INTERVAL);
+                        } else {
+                            pending.remove(&(qname, qtype));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Looks `qname` up from upstream servers: queries falling in a forward zone of `policy` are
+    /// forwarded to the upstreams of the most specific one, others are resolved recursively.
+    pub fn upstream_lookup(
+        &self,
+        policy: &Policy,
+        qname: &str,
+        qtype: RecordType,
+    ) -> Result<Resolution> {
+        let zone = policy
+            .forward_zones
+            .iter()
+            .filter(|zone| is_subdomain(qname, &zone.zone))
+            .max_by_key(|zone| zone.zone.len());
+
+        match zone {
+            Some(zone) => self.forward_lookup(zone, qname, qtype),
+            None => self.recursive_lookup(qname, qtype),
+        }
+    }
+
+    /// Forwards the query for `qname` to the best upstream of `zone`, moving on to the next best
+    /// one whenever an upstream fails to answer.
+    fn forward_lookup(
+        &self,
+        zone: &ForwardZone,
+        qname: &str,
+        qtype: RecordType,
+    ) -> Result<Resolution> {
+        let query = query_packet(qname, qtype)?;
+
+        let mut candidates: Vec<usize> = (0..zone.upstreams.len()).collect();
+        let mut last_error = Error::NoNameserver(qname.to_owned());
+
+        while let Some(i) = zone.selector.select(&candidates) {
+            let upstream = &zone.upstreams[i];
+            log::debug!("forwarding {:?} {} to {}", qtype, qname, upstream);
+
+            let start = Instant::now();
+            match upstream.query(&query) {
+                Ok(response) => {
+                    let rtt = start.elapsed();
+                    let name = upstream.to_string();
+                    zone.selector.record_success(i, rtt);
+                    self.metrics.record_upstream_success(&name, rtt);
+                    return Ok(Resolution {
+                        response,
+                        outcome: Outcome::Forwarded,
+                        upstream: Some(name),
+                        cname: None,
+                    });
+                }
+                Err(e) => {
+                    log::warning!("Upstream {} failed: {}", upstream, e);
+                    zone.selector.record_failure(i, UPSTREAM_TIMEOUT);
+                    self.metrics.record_upstream_failure(&upstream.to_string());
+                    candidates.retain(|c| *c != i);
+                    last_error = e;
+                }
+            }
+        }
+
+        Err(last_error)
     }
 
-    pub fn recursive_lookup(&self, qname: &str, qtype: RecordType) -> Result<Packet> {
-        // For now we're always starting with *a.root-servers.net*.
-        let mut ns = "198.41.0.4".parse::<Ipv4Addr>().unwrap();
+    pub fn recursive_lookup(&self, qname: &str, qtype: RecordType) -> Result<Resolution> {
+        let mut budget = LookupBudget::default();
+        let response = self.recursive_lookup_with(qname, qtype, &mut budget, 0)?;
+
+        Ok(Resolution {
+            response,
+            outcome: Outcome::Recursed,
+            upstream: budget.last_server.map(|ns| ns.to_string()),
+            cname: None,
+        })
+    }
 
-        // Since it might take an arbitrary number of steps, we enter an unbounded loop.
+    /// Performs the actual iterative lookup of `qname`. The `budget` is shared with every nested
+    /// lookup started to resolve glueless name servers, `depth` being the current nesting level.
+    fn recursive_lookup_with(
+        &self,
+        qname: &str,
+        qtype: RecordType,
+        budget: &mut LookupBudget,
+        depth: usize,
+    ) -> Result<Packet> {
+        // For now we're always starting with the root servers.
+        let mut servers = self.root_hints.addrs(self.ip_families);
+        if servers.is_empty() {
+            return Err(Error::NoRootHints);
+        }
+
+        // Number of referrals followed by this lookup sequence
+        let mut referrals = 0;
+
+        // State of the QNAME minimisation: the labels of `qname`, how many of them were already
+        // revealed, the zone cut we're at and how many minimised queries were sent.
+        let labels: Vec<&str> = qname.split('.').filter(|l| !l.is_empty()).collect();
+        let mut minimising = self.qname_minimisation != QnameMinimisation::Off;
+        let mut revealed = 0;
+        let mut zone = String::new();
+        let mut minimised_queries = 0;
+
+        // Since it might take an arbitrary number of steps, we enter a loop that is only bounded
+        // by the limits on upstream queries and referrals.
         loop {
-            println!("attempting lookup of {:?} {} with ns {}", qtype, qname, ns);
+            // When minimising, the servers of a zone are only told about the next label(s) below
+            // their zone, with the type `A` to avoid tripping over servers mishandling `NS`.
+            let n = if minimising {
+                (revealed + minimisation_step(labels.len() - revealed, minimised_queries))
+                    .min(labels.len())
+            } else {
+                labels.len()
+            };
+            let minimised = n < labels.len();
+            let (query_name, query_type) = if minimised {
+                (labels[labels.len() - n..].join("."), RecordType::A)
+            } else {
+                (qname.to_owned(), qtype)
+            };
+
+            // The next step is to send the query to the best server of the current zone.
+            let response = self.query_servers(&query_name, query_type, &mut servers, budget)?;
 
-            // The next step is to send the query to the active server.
-            let ns_copy = ns;
+            // The response to a minimised query is only used to find the next zone cut.
+            if minimised {
+                minimised_queries += 1;
 
-            let server = (ns_copy, 53);
-            let response = self.lookup(qname, qtype, server)?;
+                // Only a referral to a zone below the current one is a zone cut. A server that
+                // answers is authoritative for `query_name` and can be asked about the rest.
+                let cut = match response.header.response_code {
+                    ResultCode::NoError if response.answers.is_empty() => response
+                        .get_ns_zone(&query_name)
+                        .filter(|cut| *cut != zone && is_subdomain(cut, &zone))
+                        .map(|cut| cut.to_owned()),
+                    ResultCode::NoError => None,
+                    // Broken servers answer errors, or `NXDOMAIN` for empty non-terminals, to
+                    // minimised queries. In strict mode that is the final answer, while the
+                    // relaxed mode retries with the full name as described in
+                    // [RFC9156#3](https://www.rfc-editor.org/rfc/rfc9156#section-3).
+                    _ if self.qname_minimisation == QnameMinimisation::Strict => {
+                        return Ok(response)
+                    }
+                    _ => {
+                        minimising = false;
+                        continue;
+                    }
+                };
+
+                match cut {
+                    // A referral: follow it below, like for any query.
+                    Some(cut) => {
+                        revealed = cut.split('.').filter(|l| !l.is_empty()).count();
+                        zone = cut;
+                    }
+                    // Otherwise there is no zone cut at `query_name`, reveal more labels to the
+                    // same servers.
+                    None => {
+                        revealed = n;
+                        continue;
+                    }
+                }
+            }
 
             // If there are entries in the answer section, and no errors, we are done!
             if !response.answers.is_empty() && response.header.response_code == ResultCode::NoError
@@ -156,11 +1127,19 @@ impl Server {
                 return Ok(response);
             }
 
-            // Otherwise, we'll try to find a new nameserver based on NS and a corresponding A
-            // record in the additional section. If this succeeds, we can switch name server
-            // and retry the loop.
-            if let Some(new_ns) = response.get_resolved_ns(qname) {
-                ns = new_ns;
+            // Anything below is a referral to another name server. A broken or malicious
+            // delegation could send us back and forth between servers, so cap them.
+            referrals += 1;
+            if referrals > MAX_REFERRALS {
+                return Err(Error::MaxReferrals(qname.to_owned()));
+            }
+
+            // Otherwise, we'll try to find new nameservers based on NS and corresponding A or
+            // AAAA records in the additional section. If this succeeds, we can switch to these
+            // name servers and retry the loop.
+            let new_servers = response.get_resolved_ns(qname, self.ip_families);
+            if !new_servers.is_empty() {
+                servers = new_servers;
 
                 continue;
             }
@@ -174,18 +1153,213 @@ impl Server {
 
             // Here we go down the rabbit hole by starting _another_ lookup sequence in the
             // midst of our current one. Hopefully, this will give us the IP of an appropriate
-            // name server.
-            let recursive_response = self.recursive_lookup(&new_ns_name, RecordType::A)?;
+            // name server. Glueless delegations pointing to each other would otherwise recurse
+            // until the stack blows up.
+            if depth >= MAX_NS_DEPTH {
+                return Err(Error::MaxNsDepth(qname.to_owned()));
+            }
+            // IPv4 addresses are looked up first, unless only IPv6 can be used.
+            let mut new_servers = Vec::new();
+            for addr_type in [RecordType::A, RecordType::AAAA] {
+                if addr_type == RecordType::A && self.ip_families == IpFamilies::Ipv6
+                    || addr_type == RecordType::AAAA && self.ip_families == IpFamilies::Ipv4
+                {
+                    continue;
+                }
 
-            // Finally, we pick a random ip from the result, and restart the loop. If no such
-            // record is available, we again return the last result we got.
-            if let Some(new_ns) = recursive_response.get_random_a() {
-                ns = new_ns;
-            } else {
+                let recursive_response =
+                    self.recursive_lookup_with(new_ns_name, addr_type, budget, depth + 1)?;
+                new_servers = recursive_response.get_addrs(self.ip_families);
+                if !new_servers.is_empty() {
+                    break;
+                }
+            }
+
+            // Finally, we restart the loop with the addresses we got. If no such record is
+            // available, we again return the last result we got.
+            if new_servers.is_empty() {
                 return Ok(response);
             }
+            servers = new_servers;
+        }
+    }
+
+    /// Sends the query to the best of the `servers` of a zone, as picked by the name server
+    /// selector, and moves on to the next best one whenever a server fails to answer. Servers
+    /// that failed are removed from `servers`.
+    fn query_servers(
+        &self,
+        qname: &str,
+        qtype: RecordType,
+        servers: &mut Vec<IpAddr>,
+        budget: &mut LookupBudget,
+    ) -> Result<Packet> {
+        let mut last_error = Error::NoNameserver(qname.to_owned());
+
+        while let Some(ns) = self.nameservers.select(servers) {
+            log::debug!("attempting lookup of {:?} {} with ns {}", qtype, qname, ns);
+
+            // Every single query sent upstream counts against the budget of the client request.
+            budget.upstream_queries += 1;
+            if budget.upstream_queries > MAX_UPSTREAM_QUERIES {
+                return Err(Error::MaxUpstreamQueries(qname.to_owned()));
+            }
+
+            let start = Instant::now();
+            match self.lookup(qname, qtype, SocketAddr::new(ns, self.ns_port)) {
+                Ok(response) => {
+                    let rtt = start.elapsed();
+                    self.nameservers.record_success(ns, rtt);
+                    self.metrics.record_upstream_success(&ns.to_string(), rtt);
+                    budget.last_server = Some(ns);
+                    return Ok(response);
+                }
+                Err(e) => {
+                    log::warning!("Name server {} failed: {}", ns, e);
+                    self.nameservers.record_failure(ns, UPSTREAM_TIMEOUT);
+                    self.metrics.record_upstream_failure(&ns.to_string());
+                    servers.retain(|addr| *addr != ns);
+                    last_error = e;
+                }
+            }
+        }
+
+        Err(last_error)
+    }
+}
+
+/// Names `client` in a log line, if there is one.
+fn for_client(client: Option<IpAddr>) -> String {
+    client
+        .map(|client| format!(" for {client}"))
+        .unwrap_or_default()
+}
+
+/// Builds the forward zones of `config`, grouping the upstreams of each zone.
+fn forward_zones(config: &Config) -> Result<Vec<ForwardZone>> {
+    let options = UpstreamOptions {
+        ca_file: config.upstreams.tls_ca.clone(),
+        spki_pins: config.upstreams.tls_pins.clone(),
+        doh_method: config.upstreams.doh_method,
+    };
+
+    let mut forwards: Vec<(String, Vec<Box<dyn Upstream>>)> = Vec::new();
+    for (i, forward) in config.upstreams.forward.iter().enumerate() {
+        let (zone, spec) = forward.split_once('=').unwrap_or(("", forward));
+        // `Example.com.` and `example.com` are the same zone, as are `.` and the default one
+        let zone = zone.trim_end_matches('.').to_lowercase();
+        let upstream = upstream::from_spec(spec, &options)
+            .map_err(|e| Error::InvalidConfig(format!("upstreams.forward[{i}]: {e}")))?;
+        match forwards.iter_mut().find(|(z, _)| *z == zone) {
+            Some((_, upstreams)) => upstreams.push(upstream),
+            None => forwards.push((zone, vec![upstream])),
         }
     }
+
+    Ok(forwards
+        .into_iter()
+        .map(|(zone, upstreams)| ForwardZone::new(&zone, upstreams))
+        .collect())
+}
+
+/// Loads a blocklist out of `lists`, `allowlists` and the `scheduled` ones with `loader`,
+/// answering with `mode`. Errors name the settings under `key` the files come from.
+fn load_blocklist(
+    loader: &mut ListLoader,
+    key: &str,
+    lists: &[String],
+    allowlists: &[String],
+    scheduled: &[ScheduledListConfig],
+    mode: BlockingMode,
+) -> Result<Blocklist> {
+    let mut blocklist = Blocklist::default();
+    blocklist.set_mode(mode);
+
+    for (i, path) in lists.iter().enumerate() {
+        let list = loader
+            .load(path)
+            .map_err(|e| Error::InvalidConfig(format!("{key}.lists[{i}]: {e}")))?;
+        log::info!("Loaded {} domains from {}", list.len(), path);
+        blocklist.add_list(list);
+    }
+    for (i, path) in allowlists.iter().enumerate() {
+        let list = loader
+            .load(path)
+            .map_err(|e| Error::InvalidConfig(format!("{key}.allowlists[{i}]: {e}")))?;
+        log::info!("Allowed {} domains from {}", list.len(), path);
+        blocklist.add_allowlist(list);
+    }
+    for (i, scheduled) in scheduled.iter().enumerate() {
+        let key = format!("{key}.scheduled[{i}]");
+        blocklist.add_scheduled(ScheduledBlocklist {
+            schedule: Schedule {
+                days: scheduled.days.clone(),
+                from: scheduled.from,
+                to: scheduled.to,
+            },
+            blocklist: load_blocklist(loader, &key, &scheduled.lists, &[], &[], mode)?,
+        });
+    }
+
+    Ok(blocklist)
+}
+
+/// An empty truncated copy of `response`, telling the client to retry over TCP.
+fn truncated(response: &Packet) -> Packet {
+    let mut packet: Packet = Default::default();
+    packet.header.id = response.header.id;
+    packet.header.is_response = true;
+    packet.header.is_truncated = true;
+    packet.header.recursion_desired = response.header.recursion_desired;
+    packet.header.recursion_available = response.header.recursion_available;
+    packet.header.response_code = response.header.response_code;
+    packet.questions = response.questions.clone();
+    packet.header.question_count = packet.questions.len() as u16;
+
+    packet
+}
+
+/// An empty `SERVFAIL` copy of `response`, sent instead of it when it cannot be encoded.
+pub fn failed(response: &Packet) -> Packet {
+    let mut packet = truncated(response);
+    packet.header.is_truncated = false;
+    packet.header.response_code = ResultCode::ServFail;
+
+    packet
+}
+
+/// Forges the packet of a query for `qname`, under a random ID.
+fn query_packet(qname: &str, qtype: RecordType) -> Result<Packet> {
+    let mut packet: Packet = Default::default();
+    packet.header.id = rand::random();
+    packet.header.recursion_desired = true;
+    packet.add_question(qname, qtype)?;
+
+    Ok(packet)
+}
+
+/// Number of labels to reveal with the next minimised query, out of `remaining` ones, once
+/// `sent` minimised queries were already sent. As recommended by
+/// [RFC9156#2.3](https://www.rfc-editor.org/rfc/rfc9156#section-2.3), the first queries reveal
+/// one label at a time, then the remaining labels are spread so that no more than
+/// `MAX_MINIMISE_COUNT` queries are sent overall.
+fn minimisation_step(remaining: usize, sent: usize) -> usize {
+    if sent < MINIMISE_ONE_LAB {
+        1
+    } else if sent >= MAX_MINIMISE_COUNT {
+        remaining
+    } else {
+        remaining.div_ceil(MAX_MINIMISE_COUNT - sent)
+    }
+}
+
+/// Keeps track of the work done while resolving a single client request.
+#[derive(Default)]
+struct LookupBudget {
+    /// Number of queries sent to upstream servers so far
+    upstream_queries: usize,
+    /// Name server which sent the last response, i.e. the final answer once the lookup is done
+    last_server: Option<IpAddr>,
 }
 
 impl fmt::Display for Server {
@@ -193,3 +1367,308 @@ impl fmt::Display for Server {
         write!(f, "({}:{})", self.local_addr, self.local_port)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use chrono::{NaiveDate, TimeZone};
+
+    use std::net::Ipv4Addr;
+
+    use crate::hints::RootServer;
+    use crate::record::RecordPreamble;
+    use crate::testing::echo;
+
+    use super::*;
+
+    /// A clock only moving when told to.
+    struct FixedClock(Mutex<DateTime<Utc>>);
+
+    impl FixedClock {
+        /// Sets the clock to `time` on the given day of October 2026, in local time.
+        fn set(&self, day: u32, time: &str) {
+            let local = NaiveDate::from_ymd_opt(2026, 10, day)
+                .unwrap()
+                .and_time(time.parse().unwrap());
+            *self.0.lock().unwrap() = Local
+                .from_local_datetime(&local)
+                .earliest()
+                .unwrap()
+                .with_timezone(&Utc);
+        }
+    }
+
+    impl Clock for FixedClock {
+        fn now(&self) -> DateTime<Utc> {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    #[test]
+    fn blocks_scheduled_lists_while_their_schedule_is_active() {
+        let list = std::env::temp_dir().join(format!("barthez-{}-games.txt", std::process::id()));
+        fs::write(&list, "games.example\n").unwrap();
+        let mut config = Config::default();
+        config.blocking.scheduled.push(ScheduledListConfig {
+            lists: vec![list.to_string_lossy().into_owned()],
+            days: Vec::new(),
+            from: "21:00".parse().unwrap(),
+            to: "07:00".parse().unwrap(),
+        });
+
+        let clock = Arc::new(FixedClock(Mutex::new(Utc::now())));
+        let server = Server::from_config(&config, Arc::clone(&clock) as Arc<dyn Clock>);
+        fs::remove_file(&list).unwrap();
+        let server = server.unwrap();
+        let policy = server.policy();
+        let client = IpAddr::from([192, 168, 1, 10]);
+        let blocked = |qname| server.blocked(policy.blocklist(), client, qname);
+
+        clock.set(19, "20:59");
+        assert_eq!(blocked("games.example"), None);
+        clock.set(19, "21:00");
+        assert_eq!(blocked("games.example").as_deref(), Some("games.example"));
+        assert_eq!(
+            blocked("www.games.example").as_deref(),
+            Some("games.example")
+        );
+        assert_eq!(blocked("news.example"), None);
+        clock.set(20, "06:59");
+        assert_eq!(blocked("games.example").as_deref(), Some("games.example"));
+        clock.set(20, "07:00");
+        assert_eq!(blocked("games.example"), None);
+    }
+
+    #[test]
+    fn groups_the_upstreams_of_a_zone_however_it_is_written() {
+        let mut config = Config::default();
+        config.upstreams.forward = [
+            "udp://192.0.2.1",
+            ".=udp://192.0.2.2",
+            "Example.com.=udp://192.0.2.3",
+            "example.com=udp://192.0.2.4",
+            "example.org=udp://192.0.2.5",
+        ]
+        .map(str::to_owned)
+        .to_vec();
+
+        let zones = forward_zones(&config).unwrap();
+        let zones: Vec<_> = zones
+            .iter()
+            .map(|zone| (zone.zone.as_str(), zone.upstreams.len()))
+            .collect();
+        assert_eq!(zones, [("", 2), ("example.com", 2), ("example.org", 1)]);
+    }
+
+    #[test]
+    fn takes_turns_on_a_fixed_query_port() {
+        let name_server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = name_server.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buffer = [0; 512];
+            while let Ok((len, client)) = name_server.recv_from(&mut buffer) {
+                let _ = name_server.send_to(&echo(&buffer[..len]), client);
+            }
+        });
+        let port = UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+
+        let server = Arc::new(Server::new("127.0.0.1".to_owned(), port));
+        let lookups: Vec<_> = (0..8)
+            .map(|_| {
+                let server = Arc::clone(&server);
+                thread::spawn(move || server.lookup("example.com", RecordType::A, addr))
+            })
+            .collect();
+        for lookup in lookups {
+            assert!(lookup.join().unwrap().is_ok());
+        }
+    }
+
+    /// A server iterating from a single root name server on the loopback, which answers every
+    /// question with `respond`.
+    fn iterating_from(respond: fn(&Question) -> Packet) -> Server {
+        let name_server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = name_server.local_addr().unwrap();
+        thread::spawn(move || loop {
+            let mut buffer = PacketBuffer::new();
+            let Ok((_, client)) = name_server.recv_from(&mut buffer.bytes) else {
+                break;
+            };
+            let query = Packet::try_from(buffer).unwrap();
+            let mut response = respond(&query.questions[0]);
+            response.header.id = query.header.id;
+            response.header.is_response = true;
+            response.header.question_count = 1;
+            response.header.answer_count = response.answers.len() as u16;
+            response.header.authority_count = response.authorities.len() as u16;
+            response.header.additional_count = response.additionals.len() as u16;
+            response.questions = query.questions;
+            let _ = name_server.send_to(&response.to_bytes().unwrap(), client);
+        });
+
+        let mut server = Server::new("127.0.0.1".to_owned(), 0);
+        server.set_qname_minimisation(QnameMinimisation::Off);
+        server.set_root_hints(RootHints {
+            servers: vec![RootServer {
+                name: "a.root.test".to_owned(),
+                addrs: vec![addr.ip()],
+            }],
+        });
+        server.set_ns_port(addr.port());
+        server
+    }
+
+    /// A referral to the name server `ns` of `zone`, along with its address if `glued`.
+    fn referral(zone: &str, ns: &str, glued: bool) -> Packet {
+        let mut response = Packet::default();
+        response.authorities.push(Record::NS {
+            preamble: RecordPreamble::new(zone, RecordType::NS, 3600),
+            host: ns.to_owned(),
+        });
+        if glued {
+            response.additionals.push(Record::A {
+                preamble: RecordPreamble::new(ns, RecordType::A, 3600),
+                addr: Ipv4Addr::LOCALHOST,
+            });
+        }
+        response
+    }
+
+    /// The address of `qname`, which is the loopback.
+    fn address(qname: &str) -> Packet {
+        let mut response = Packet::default();
+        response.answers.push(Record::A {
+            preamble: RecordPreamble::new(qname, RecordType::A, 3600),
+            addr: Ipv4Addr::LOCALHOST,
+        });
+        response
+    }
+
+    #[test]
+    fn gives_up_on_endless_referrals() {
+        let server = iterating_from(|_| referral("test", "ns.test", true));
+
+        let result = server.recursive_lookup("www.example.test", RecordType::A);
+        assert!(matches!(result, Err(Error::MaxReferrals(_))));
+    }
+
+    #[test]
+    fn gives_up_on_endless_glueless_delegations() {
+        // Every name is delegated to a server named after it, whose address is never known
+        let server = iterating_from(|question| {
+            referral(&question.name, &format!("ns.{}", question.name), false)
+        });
+
+        let result = server.recursive_lookup("www.example.test", RecordType::A);
+        assert!(matches!(result, Err(Error::MaxNsDepth(_))));
+    }
+
+    #[test]
+    fn counts_nested_lookups_against_the_queries_of_a_request() {
+        let server = iterating_from(|question| match question.name.as_str() {
+            "ns.test" => address("ns.test"),
+            _ => referral("test", "ns.test", false),
+        });
+
+        // Each referral takes two queries, so the budget runs out before the referrals do
+        let mut budget = LookupBudget {
+            upstream_queries: MAX_UPSTREAM_QUERIES - MAX_REFERRALS,
+            last_server: None,
+        };
+        let result =
+            server.recursive_lookup_with("www.example.test", RecordType::A, &mut budget, 0);
+        assert!(matches!(result, Err(Error::MaxUpstreamQueries(_))));
+        assert_eq!(budget.upstream_queries, MAX_UPSTREAM_QUERIES + 1);
+    }
+
+    /// A name server holding `www.example.test`, which mistakes the names above it for missing
+    /// ones.
+    fn nxdomain_above_the_query_name(question: &Question) -> Packet {
+        match question.name.as_str() {
+            "www.example.test" => address("www.example.test"),
+            _ => {
+                let mut response = Packet::default();
+                response.header.response_code = ResultCode::NXDomain;
+                response
+            }
+        }
+    }
+
+    #[test]
+    fn retries_with_the_full_name_when_minimised_queries_fail() {
+        let mut server = iterating_from(nxdomain_above_the_query_name);
+        server.set_qname_minimisation(QnameMinimisation::Relaxed);
+
+        let resolution = server
+            .recursive_lookup("www.example.test", RecordType::A)
+            .unwrap();
+        assert_eq!(
+            resolution.response.header.response_code,
+            ResultCode::NoError
+        );
+        assert_eq!(
+            resolution.response.get_addrs(IpFamilies::Both),
+            [IpAddr::from(Ipv4Addr::LOCALHOST)]
+        );
+    }
+
+    #[test]
+    fn takes_failures_of_minimised_queries_as_the_answer_when_strict() {
+        let mut server = iterating_from(nxdomain_above_the_query_name);
+        server.set_qname_minimisation(QnameMinimisation::Strict);
+
+        let resolution = server
+            .recursive_lookup("www.example.test", RecordType::A)
+            .unwrap();
+        assert_eq!(
+            resolution.response.header.response_code,
+            ResultCode::NXDomain
+        );
+        assert_eq!(resolution.response.questions[0].name, "test");
+    }
+
+    #[test]
+    fn reloads_blocklists_unless_the_new_configuration_is_invalid() {
+        let list = std::env::temp_dir().join(format!("barthez-{}-reload.txt", std::process::id()));
+        fs::write(&list, "ads.example\n").unwrap();
+        let mut config = Config::default();
+        config.blocking.lists = vec![list.to_string_lossy().into_owned()];
+        config.query_log.file = String::new();
+        let server = Server::from_config(&config, Arc::new(SystemClock)).unwrap();
+        server
+            .cache
+            .insert("cached.test", RecordType::A, &address("cached.test"));
+        let client = IpAddr::from([192, 168, 1, 10]);
+        let blocked = |qname| server.blocked(server.policy().blocklist(), client, qname);
+
+        fs::write(&list, "tracker.example\n").unwrap();
+        server.reload(&config).unwrap();
+        assert_eq!(blocked("ads.example"), None);
+        assert_eq!(
+            blocked("tracker.example").as_deref(),
+            Some("tracker.example")
+        );
+        assert!(server.cache.get("cached.test", RecordType::A).is_some());
+
+        // The configuration is only swapped in once the new one is fully loaded
+        let mut invalid = config.clone();
+        invalid
+            .blocking
+            .lists
+            .push("/nonexistent/list.txt".to_owned());
+        let policy = server.policy();
+        fs::remove_file(&list).unwrap();
+        assert!(server.reload(&invalid).is_err());
+        assert!(Arc::ptr_eq(&policy, &server.policy()));
+        assert_eq!(server.config(), config);
+        assert_eq!(
+            blocked("tracker.example").as_deref(),
+            Some("tracker.example")
+        );
+    }
+}
@@ -0,0 +1,285 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt::{self, Formatter};
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::{Serialize, Serializer};
+use signal_hook::consts::SIGUSR1;
+use signal_hook::iterator::Signals;
+
+use crate::globals::{STATS_MAX_KEYS, STATS_SLOT, STATS_TOP_COUNT, STATS_WINDOW};
+use crate::pause::BlockingStatus;
+use crate::querylog::{Outcome, QueryLogEntry};
+use crate::result::{Error, Result};
+use crate::server::Server;
+
+/// Counts kept since the start.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Totals {
+    pub queries: u64,
+    pub outcomes: BTreeMap<Outcome, u64>,
+    /// Queries forwarded to each upstream
+    pub upstreams: BTreeMap<String, u64>,
+    /// Queries by record type
+    pub qtypes: BTreeMap<String, u64>,
+    /// Responses by response code
+    pub rcodes: BTreeMap<String, u64>,
+}
+
+impl Totals {
+    pub fn count(&self, outcome: Outcome) -> u64 {
+        self.outcomes.get(&outcome).copied().unwrap_or(0)
+    }
+
+    /// Queries answered from the cache, be it with fresh or stale responses.
+    pub fn cache_hits(&self) -> u64 {
+        self.count(Outcome::Cached) + self.count(Outcome::Stale)
+    }
+}
+
+/// Counts of the queries received during a slot of time.
+struct Slot {
+    /// Index of the slot, counted in `STATS_SLOT` since the start
+    index: u64,
+    queries: u64,
+    blocked: u64,
+    domains: HashMap<String, u64>,
+    blocked_domains: HashMap<String, u64>,
+    clients: HashMap<IpAddr, u64>,
+}
+
+impl Slot {
+    fn new(index: u64) -> Self {
+        Self {
+            index,
+            queries: 0,
+            blocked: 0,
+            domains: HashMap::new(),
+            blocked_domains: HashMap::new(),
+            clients: HashMap::new(),
+        }
+    }
+}
+
+/// Increments the count of `key`, unless `counts` already tracks too many keys: past that, keys
+/// seen for the first time during the slot go uncounted.
+fn increment<K: Eq + Hash>(counts: &mut HashMap<K, u64>, key: K) {
+    if counts.len() < STATS_MAX_KEYS {
+        *counts.entry(key).or_insert(0) += 1;
+    } else if let Some(count) = counts.get_mut(&key) {
+        *count += 1;
+    }
+}
+
+/// Sums the counts of the `counts` of every slot, and returns the `n` highest ones.
+fn top<'a, K: Clone + Eq + Hash + Ord + 'a>(
+    counts: impl Iterator<Item = &'a HashMap<K, u64>>,
+    n: usize,
+) -> Vec<(K, u64)> {
+    let mut sums: HashMap<&K, u64> = HashMap::new();
+    for counts in counts {
+        for (key, count) in counts {
+            *sums.entry(key).or_insert(0) += count;
+        }
+    }
+
+    let mut sums: Vec<(K, u64)> = sums.into_iter().map(|(k, c)| (k.clone(), c)).collect();
+    sums.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    sums.truncate(n);
+    sums
+}
+
+/// What the statistics were like at some point.
+#[derive(Clone, Debug, Serialize)]
+pub struct StatsReport {
+    #[serde(serialize_with = "seconds")]
+    pub uptime: Duration,
+    pub totals: Totals,
+    /// How far back the numbers below go
+    #[serde(serialize_with = "seconds")]
+    pub window: Duration,
+    pub queries: u64,
+    pub blocked: u64,
+    pub top_domains: Vec<(String, u64)>,
+    pub top_blocked_domains: Vec<(String, u64)>,
+    pub top_clients: Vec<(IpAddr, u64)>,
+    /// Whether blocking is enabled, which the statistics alone don't know
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub blocking: Option<BlockingStatus>,
+}
+
+fn seconds<S: Serializer>(
+    duration: &Duration,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    serializer.serialize_u64(duration.as_secs())
+}
+
+/// Share of `count` in `total`, as a percentage.
+fn percent(count: u64, total: u64) -> f64 {
+    match total {
+        0 => 0.0,
+        _ => count as f64 * 100.0 / total as f64,
+    }
+}
+
+fn write_counts<K: fmt::Display>(
+    f: &mut Formatter<'_>,
+    title: &str,
+    counts: impl IntoIterator<Item = (K, u64)>,
+) -> fmt::Result {
+    write!(f, "{title}:")?;
+    for (i, (key, count)) in counts.into_iter().enumerate() {
+        let separator = if i == 0 { "" } else { "," };
+        write!(f, "{separator} {key} {count}")?;
+    }
+    writeln!(f)
+}
+
+impl fmt::Display for StatsReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let totals = &self.totals;
+        writeln!(f, "Uptime: {}s", self.uptime.as_secs())?;
+        if let Some(blocking) = &self.blocking {
+            writeln!(f, "Blocking: {blocking}")?;
+        }
+        writeln!(
+            f,
+            "Queries: {}, {} blocked ({:.1}%), {} from the cache ({:.1}%)",
+            totals.queries,
+            totals.count(Outcome::Blocked),
+            percent(totals.count(Outcome::Blocked), totals.queries),
+            totals.cache_hits(),
+            percent(totals.cache_hits(), totals.queries)
+        )?;
+        write_counts(f, "Outcomes", totals.outcomes.clone())?;
+        write_counts(f, "Upstreams", totals.upstreams.clone())?;
+        write_counts(f, "Types", totals.qtypes.clone())?;
+        write_counts(f, "Response codes", totals.rcodes.clone())?;
+
+        writeln!(
+            f,
+            "Last {}s: {} queries, {} blocked ({:.1}%)",
+            self.window.as_secs(),
+            self.queries,
+            self.blocked,
+            percent(self.blocked, self.queries)
+        )?;
+        write_counts(f, "Top domains", self.top_domains.clone())?;
+        write_counts(f, "Top blocked domains", self.top_blocked_domains.clone())?;
+        write_counts(f, "Top clients", self.top_clients.clone())
+    }
+}
+
+/// Live statistics of the queries answered: totals since the start, and top domains and clients
+/// over the last `STATS_WINDOW`. The latter are counted by slots of `STATS_SLOT`, the oldest slot
+/// being dropped as a new one starts.
+pub struct Stats {
+    started: Instant,
+    totals: Mutex<Totals>,
+    slots: Mutex<VecDeque<Slot>>,
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            totals: Mutex::new(Totals::default()),
+            slots: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    fn slot_index(&self, now: Instant) -> u64 {
+        now.duration_since(self.started).as_secs() / STATS_SLOT.as_secs()
+    }
+
+    pub fn record(&self, entry: &QueryLogEntry) {
+        let blocked = entry.outcome == Outcome::Blocked;
+
+        {
+            let mut totals = self.totals.lock().unwrap();
+            totals.queries += 1;
+            *totals.outcomes.entry(entry.outcome).or_insert(0) += 1;
+            if let (Outcome::Forwarded, Some(upstream)) = (entry.outcome, &entry.upstream) {
+                *totals.upstreams.entry(upstream.clone()).or_insert(0) += 1;
+            }
+            *totals.qtypes.entry(entry.qtype.to_string()).or_insert(0) += 1;
+            *totals.rcodes.entry(entry.rcode.to_string()).or_insert(0) += 1;
+        }
+
+        let index = self.slot_index(Instant::now());
+        let mut slots = self.slots.lock().unwrap();
+        if slots.back().is_none_or(|slot| slot.index != index) {
+            slots.push_back(Slot::new(index));
+        }
+        let window = STATS_WINDOW.as_secs() / STATS_SLOT.as_secs();
+        while slots
+            .front()
+            .is_some_and(|slot| slot.index + window <= index)
+        {
+            slots.pop_front();
+        }
+
+        let Some(slot) = slots.back_mut() else {
+            return;
+        };
+        let qname = entry.qname.trim_end_matches('.').to_lowercase();
+        slot.queries += 1;
+        increment(&mut slot.clients, entry.client);
+        if blocked {
+            slot.blocked += 1;
+            increment(&mut slot.blocked_domains, qname);
+        } else {
+            increment(&mut slot.domains, qname);
+        }
+    }
+
+    /// Reports the statistics, with the `n` top domains and clients over the last `window`
+    /// (rounded up to whole slots, and up to `STATS_WINDOW`).
+    pub fn report(&self, window: Duration, n: usize) -> StatsReport {
+        let now = Instant::now();
+        let totals = self.totals.lock().unwrap().clone();
+
+        let index = self.slot_index(now);
+        let count = window.as_secs().div_ceil(STATS_SLOT.as_secs()).max(1);
+        let slots = self.slots.lock().unwrap();
+        let recent: Vec<&Slot> = slots
+            .iter()
+            .filter(|slot| slot.index + count > index)
+            .collect();
+
+        StatsReport {
+            uptime: now.duration_since(self.started),
+            totals,
+            window: (STATS_SLOT * count as u32).min(STATS_WINDOW),
+            queries: recent.iter().map(|slot| slot.queries).sum(),
+            blocked: recent.iter().map(|slot| slot.blocked).sum(),
+            top_domains: top(recent.iter().map(|slot| &slot.domains), n),
+            top_blocked_domains: top(recent.iter().map(|slot| &slot.blocked_domains), n),
+            top_clients: top(recent.iter().map(|slot| &slot.clients), n),
+            blocking: None,
+        }
+    }
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Prints the report of `server` over the whole window on `SIGUSR1`.
+pub fn report_on_signal(server: Arc<Server>) -> Result<()> {
+    let mut signals =
+        Signals::new([SIGUSR1]).map_err(|e| Error::SignalFailed(format!("SIGUSR1: {e}")))?;
+    thread::spawn(move || {
+        for _ in signals.forever() {
+            print!("{}", server.report(STATS_WINDOW, STATS_TOP_COUNT));
+        }
+    });
+
+    Ok(())
+}
@@ -0,0 +1,97 @@
+use std::sync::Arc;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use ring::digest::{digest, SHA256};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::{ClientConfig, RootCertStore, ServerConfig};
+
+use crate::clock::SystemClock;
+use crate::config::Config;
+use crate::packet::Packet;
+use crate::question::Question;
+use crate::record::RecordType;
+use crate::server::Server;
+
+/// Name of the test server certificate, signed by the test CA of `data/test/ca.pem`
+pub const SERVER_NAME: &str = "dns.test";
+
+/// Path of a file of `data/test`.
+pub fn data_file(name: &str) -> String {
+    format!("{}/data/test/{name}", env!("CARGO_MANIFEST_DIR"))
+}
+
+/// The TLS configuration of a test server presenting the certificate of `SERVER_NAME`.
+pub fn server_config(alpn: &[&[u8]]) -> ServerConfig {
+    let certs = CertificateDer::pem_file_iter(data_file("server.pem"))
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    let key = PrivateKeyDer::from_pem_file(data_file("server.key")).unwrap();
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut config = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .unwrap();
+    config.alpn_protocols = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+    config
+}
+
+/// The TLS configuration of a client trusting nothing but the test CA.
+pub fn client_config() -> ClientConfig {
+    let mut roots = RootCertStore::empty();
+    for cert in CertificateDer::pem_file_iter(data_file("ca.pem")).unwrap() {
+        roots.add(cert.unwrap()).unwrap();
+    }
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth()
+}
+
+/// The SPKI pin of the test server certificate.
+pub fn server_pin() -> String {
+    let cert = CertificateDer::from_pem_file(data_file("server.pem")).unwrap();
+    let cert = webpki::EndEntityCert::try_from(&cert).unwrap();
+    BASE64.encode(digest(&SHA256, cert.subject_public_key_info().as_ref()))
+}
+
+/// A recursive query for the `A` records of `qname`.
+pub fn query(id: u16, qname: &str) -> Packet {
+    let mut query: Packet = Default::default();
+    query.header.id = id;
+    query.header.recursion_desired = true;
+    query.questions.push(Question::new(qname, RecordType::A));
+    query.header.question_count = 1;
+    query
+}
+
+/// The response to `query`, as a server knowing nothing about its name would send.
+pub fn echo(query: &[u8]) -> Vec<u8> {
+    let mut response = query.to_vec();
+    response[2] |= 0x80;
+    response
+}
+
+/// A server blocking `blocked.test` with the unspecified address, whose queries are answered
+/// without reaching any upstream.
+pub fn blocking_server() -> Arc<Server> {
+    let mut config = Config::default();
+    config.blocking.lists = vec![data_file("blocklist.txt")];
+    config.query_log.file = String::new();
+    Arc::new(Server::from_config(&config, Arc::new(SystemClock)).unwrap())
+}
+
+/// Prefixes `message` with its length, as sent over TCP and TLS.
+pub fn framed(message: &[u8]) -> Vec<u8> {
+    let mut frame = (message.len() as u16).to_be_bytes().to_vec();
+    frame.extend_from_slice(message);
+    frame
+}
@@ -0,0 +1,393 @@
+use std::fmt::{self, Formatter};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as BASE64URL;
+use base64::Engine;
+use bytes::Bytes;
+use h2::client::SendRequest;
+use http::{header, Method, Request, StatusCode, Uri};
+use rustls::pki_types::ServerName;
+use serde::Deserialize;
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+
+use crate::globals::{MAX_MESSAGE_SIZE, UPSTREAM_TIMEOUT};
+use crate::log;
+use crate::packet::{Packet, PacketBuffer};
+use crate::result::{Error, Result};
+use crate::runtime::runtime;
+use crate::upstream::tls::client_config;
+use crate::upstream::Upstream;
+
+/// The media type of DNS messages carried over HTTP
+const DNS_MESSAGE: &str = "application/dns-message";
+
+/// How queries are sent to a DNS over HTTPS server.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DohMethod {
+    /// The query is the body of a `POST` request
+    #[default]
+    Post,
+    /// The query is base64url-encoded in the `dns` parameter of a `GET` request, which makes the
+    /// responses cacheable by HTTP caches
+    Get,
+}
+
+pub struct HttpsUpstreamConfig {
+    /// URL of the DNS endpoint, e.g. `https://dns.example/dns-query`
+    pub url: String,
+    /// Address to connect to, for when the host of `url` isn't an IP address and shouldn't be
+    /// resolved through the system resolver (which may well be us)
+    pub addr: Option<IpAddr>,
+    pub method: DohMethod,
+    /// PEM file holding the CA certificates to trust instead of the built-in web PKI roots
+    pub ca_file: Option<String>,
+    /// Base64 SHA-256 hashes of the SubjectPublicKeyInfo the server certificate must match
+    pub spki_pins: Vec<String>,
+}
+
+/// A DNS over HTTPS server, as specified by [RFC8484](https://www.rfc-editor.org/rfc/rfc8484).
+///
+/// Queries are sent over a single HTTP/2 connection, kept open and shared by every query, each
+/// of them being a stream of its own.
+pub struct HttpsUpstream {
+    uri: Uri,
+    host: String,
+    port: u16,
+    addr: Option<IpAddr>,
+    method: DohMethod,
+    server_name: ServerName<'static>,
+    connector: TlsConnector,
+    /// Handle on the HTTP/2 connection in use, replaced when it breaks
+    connection: tokio::sync::Mutex<Option<SendRequest<Bytes>>>,
+}
+
+impl HttpsUpstream {
+    pub fn new(config: HttpsUpstreamConfig) -> Result<Self> {
+        let invalid = || Error::InvalidUpstream(config.url.clone());
+
+        let uri: Uri = config.url.parse().map_err(|_| invalid())?;
+        if uri.scheme_str() != Some("https") {
+            return Err(invalid());
+        }
+        let host = uri.host().ok_or_else(invalid)?;
+        let host = host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_owned();
+        let port = uri.port_u16().unwrap_or(443);
+
+        let server_name = ServerName::try_from(host.clone()).map_err(|_| invalid())?;
+        let tls_config = client_config(
+            config.ca_file.as_deref(),
+            &config.spki_pins,
+            vec![b"h2".to_vec()],
+        )?;
+
+        Ok(Self {
+            uri,
+            host,
+            port,
+            addr: config.addr,
+            method: config.method,
+            server_name,
+            connector: TlsConnector::from(Arc::new(tls_config)),
+            connection: tokio::sync::Mutex::new(None),
+        })
+    }
+
+    /// The address of the server: the one configured, the host of the URL if it's an IP address,
+    /// or else whatever the system resolver says.
+    async fn resolve_addr(&self) -> Result<SocketAddr> {
+        if let Some(addr) = self.addr.or_else(|| self.host.parse().ok()) {
+            return Ok(SocketAddr::new(addr, self.port));
+        }
+
+        tokio::net::lookup_host((self.host.as_str(), self.port))
+            .await
+            .ok()
+            .and_then(|mut addrs| addrs.next())
+            .ok_or_else(|| Error::UpstreamConnectFailed(format!("cannot resolve {}", self.host)))
+    }
+
+    /// Returns a handle on the open connection, or opens a new one if there is none.
+    async fn connection(&self) -> Result<SendRequest<Bytes>> {
+        let mut connection = self.connection.lock().await;
+
+        if let Some(c) = connection.as_ref() {
+            return Ok(c.clone());
+        }
+
+        let addr = self.resolve_addr().await?;
+        let stream = TcpStream::connect(addr)
+            .await
+            .map_err(|e| Error::UpstreamConnectFailed(format!("{addr}: {e}")))?;
+        stream
+            .set_nodelay(true)
+            .map_err(|e| Error::UpstreamConnectFailed(format!("{addr}: {e}")))?;
+        let stream = self
+            .connector
+            .connect(self.server_name.clone(), stream)
+            .await
+            .map_err(|e| Error::TlsFailed(format!("{addr}: {e}")))?;
+        if stream.get_ref().1.alpn_protocol() != Some(b"h2") {
+            return Err(Error::TlsFailed(format!("{addr}: HTTP/2 not negotiated")));
+        }
+
+        let (send_request, h2_connection) = h2::client::handshake(stream)
+            .await
+            .map_err(|e| Error::HttpFailed(format!("{addr}: {e}")))?;
+        runtime().spawn(async move {
+            if let Err(e) = h2_connection.await {
+                log::warning!("HTTP/2 connection to {} closed: {}", addr, e);
+            }
+        });
+
+        *connection = Some(send_request.clone());
+
+        Ok(send_request)
+    }
+
+    /// Builds the HTTP request carrying `query`, along with its body if any.
+    fn request(&self, query: &[u8]) -> Result<(Request<()>, Option<Bytes>)> {
+        let (uri, body) = match self.method {
+            DohMethod::Post => (self.uri.clone(), Some(Bytes::copy_from_slice(query))),
+            DohMethod::Get => {
+                let separator = if self.uri.query().is_some() { '&' } else { '?' };
+                let uri = format!("{}{}dns={}", self.uri, separator, BASE64URL.encode(query));
+                (
+                    uri.parse()
+                        .map_err(|_| Error::InvalidUpstream(self.uri.to_string()))?,
+                    None,
+                )
+            }
+        };
+
+        let mut builder = Request::builder()
+            .uri(uri)
+            .header(header::ACCEPT, DNS_MESSAGE);
+        builder = match self.method {
+            DohMethod::Post => builder
+                .method(Method::POST)
+                .header(header::CONTENT_TYPE, DNS_MESSAGE),
+            DohMethod::Get => builder.method(Method::GET),
+        };
+        let request = builder
+            .body(())
+            .map_err(|e| Error::HttpFailed(e.to_string()))?;
+
+        Ok((request, body))
+    }
+
+    /// Sends `query` as a new stream of the connection and reads the response, which must be a
+    /// DNS message of at most `MAX_MESSAGE_SIZE` bytes.
+    async fn send(&self, send_request: SendRequest<Bytes>, query: &[u8]) -> Result<Vec<u8>> {
+        let h2_error = |e: h2::Error| Error::HttpFailed(format!("{}: {e}", self.uri));
+
+        let (request, body) = self.request(query)?;
+        let mut send_request = send_request.ready().await.map_err(h2_error)?;
+        let (response, mut stream) = send_request
+            .send_request(request, body.is_none())
+            .map_err(h2_error)?;
+        if let Some(body) = body {
+            stream.send_data(body, true).map_err(h2_error)?;
+        }
+
+        let response = response.await.map_err(h2_error)?;
+        if response.status() != StatusCode::OK {
+            return Err(Error::HttpFailed(format!(
+                "{}: status {}",
+                self.uri,
+                response.status()
+            )));
+        }
+        let content_type = response.headers().get(header::CONTENT_TYPE);
+        if content_type.is_none_or(|content_type| content_type != DNS_MESSAGE) {
+            return Err(Error::HttpFailed(format!(
+                "{}: unexpected content type {:?}",
+                self.uri, content_type
+            )));
+        }
+
+        let mut body = response.into_body();
+        let mut message = Vec::new();
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk.map_err(h2_error)?;
+            let _ = body.flow_control().release_capacity(chunk.len());
+            if message.len() + chunk.len() > MAX_MESSAGE_SIZE {
+                return Err(Error::HttpFailed(format!(
+                    "{}: response over {MAX_MESSAGE_SIZE} bytes",
+                    self.uri
+                )));
+            }
+            message.extend_from_slice(&chunk);
+        }
+
+        Ok(message)
+    }
+
+    async fn exchange(&self, query: &[u8]) -> Result<Vec<u8>> {
+        // Retried once on a new connection, as in `TlsUpstream::exchange`
+        let mut retried = false;
+
+        loop {
+            let send_request = self.connection().await?;
+            match self.send(send_request, query).await {
+                Ok(response) => return Ok(response),
+                Err(e @ Error::HttpFailed(_)) if !retried => {
+                    log::warning!("Reconnecting to {}: {}", self, e);
+                    *self.connection.lock().await = None;
+                    retried = true;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl Upstream for HttpsUpstream {
+    fn query(&self, query: &Packet) -> Result<Packet> {
+        // Queries are sent with an ID of 0 as recommended by
+        // [RFC8484#4.1](https://www.rfc-editor.org/rfc/rfc8484#section-4.1), so that identical
+        // queries are identical HTTP requests.
+        let mut bytes = query.to_bytes()?;
+        bytes[0] = 0;
+        bytes[1] = 0;
+
+        let response = runtime().block_on(async {
+            tokio::time::timeout(UPSTREAM_TIMEOUT, self.exchange(&bytes))
+                .await
+                .map_err(|_| Error::HttpFailed(format!("{}: timed out", self.uri)))?
+        })?;
+
+        let mut packet = Packet::try_from(PacketBuffer::try_from(response.as_slice())?)?;
+        packet.header.id = query.header.id;
+
+        Ok(packet)
+    }
+}
+
+impl fmt::Display for HttpsUpstream {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.uri)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::engine::general_purpose::STANDARD as BASE64;
+    use tokio::net::TcpListener;
+    use tokio_rustls::TlsAcceptor;
+
+    use crate::testing::{data_file, echo, query, server_config, server_pin, SERVER_NAME};
+
+    use super::*;
+
+    /// Starts a DNS over HTTPS server replying to every query with the content type and body
+    /// `reply` gives for it, and returns its port.
+    fn serve(reply: fn(&[u8]) -> (&'static str, Vec<u8>)) -> u16 {
+        let listener = runtime()
+            .block_on(TcpListener::bind("127.0.0.1:0"))
+            .unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let acceptor = TlsAcceptor::from(Arc::new(server_config(&[b"h2"])));
+
+        runtime().spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                runtime().spawn(async move {
+                    let stream = acceptor.accept(stream).await.unwrap();
+                    let mut connection = h2::server::handshake(stream).await.unwrap();
+                    // Requests are answered on their own, the connection being polled meanwhile
+                    // for their bodies to come in
+                    while let Some(Ok((request, respond))) = connection.accept().await {
+                        runtime().spawn(answer(request, respond, reply));
+                    }
+                });
+            }
+        });
+
+        port
+    }
+
+    /// Replies to a single request of `serve`.
+    async fn answer(
+        request: http::Request<h2::RecvStream>,
+        mut respond: h2::server::SendResponse<Bytes>,
+        reply: fn(&[u8]) -> (&'static str, Vec<u8>),
+    ) {
+        let query = match request.uri().query() {
+            Some(params) => BASE64URL
+                .decode(params.strip_prefix("dns=").unwrap())
+                .unwrap(),
+            None => {
+                let mut body = request.into_body();
+                let mut query = Vec::new();
+                while let Some(Ok(chunk)) = body.data().await {
+                    query.extend_from_slice(&chunk);
+                }
+                query
+            }
+        };
+        let (content_type, body) = reply(&query);
+        let response = http::Response::builder()
+            .header(header::CONTENT_TYPE, content_type)
+            .body(())
+            .unwrap();
+        let mut stream = respond.send_response(response, false).unwrap();
+        let _ = stream.send_data(Bytes::from(body), true);
+    }
+
+    fn upstream(port: u16, method: DohMethod, spki_pins: Vec<String>) -> HttpsUpstream {
+        HttpsUpstream::new(HttpsUpstreamConfig {
+            url: format!("https://{SERVER_NAME}:{port}/dns-query"),
+            addr: Some(IpAddr::from([127, 0, 0, 1])),
+            method,
+            ca_file: Some(data_file("ca.pem")),
+            spki_pins,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn queries_with_either_method() {
+        let port = serve(|query| (DNS_MESSAGE, echo(query)));
+        for method in [DohMethod::Post, DohMethod::Get] {
+            let response = upstream(port, method, Vec::new())
+                .query(&query(42, "example.com"))
+                .unwrap();
+            // Sent with an ID of 0, but answered with that of the query
+            assert_eq!(response.header.id, 42);
+            assert!(response.header.is_response);
+            assert_eq!(response.questions[0].name, "example.com");
+        }
+    }
+
+    #[test]
+    fn rejects_responses_that_are_not_dns_messages() {
+        let port = serve(|query| ("text/html", echo(query)));
+        let result = upstream(port, DohMethod::Post, Vec::new()).query(&query(1, "example.com"));
+        assert!(matches!(result, Err(Error::HttpFailed(_))));
+    }
+
+    #[test]
+    fn rejects_responses_larger_than_a_dns_message() {
+        let port = serve(|_| (DNS_MESSAGE, vec![0; MAX_MESSAGE_SIZE + 1]));
+        let result = upstream(port, DohMethod::Post, Vec::new()).query(&query(1, "example.com"));
+        assert!(matches!(result, Err(Error::HttpFailed(_))));
+    }
+
+    #[test]
+    fn checks_the_pins_of_the_server() {
+        let port = serve(|query| (DNS_MESSAGE, echo(query)));
+
+        let pinned = upstream(port, DohMethod::Post, vec![server_pin()]);
+        assert!(pinned.query(&query(1, "example.com")).is_ok());
+
+        let mispinned = upstream(port, DohMethod::Post, vec![BASE64.encode([0; 32])]);
+        let result = mispinned.query(&query(1, "example.com"));
+        assert!(matches!(result, Err(Error::TlsFailed(_))));
+    }
+}
@@ -0,0 +1,85 @@
+mod https;
+mod tls;
+mod udp;
+
+pub use https::{DohMethod, HttpsUpstream, HttpsUpstreamConfig};
+pub use tls::{TlsUpstream, TlsUpstreamConfig};
+pub use udp::{exchange, UdpUpstream};
+
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+
+use crate::packet::Packet;
+use crate::result::{Error, Result};
+
+/// A server queries are forwarded to, whatever the transport used to reach it.
+pub trait Upstream: fmt::Display + Send + Sync {
+    /// Sends `query` to the server and waits for its response.
+    fn query(&self, query: &Packet) -> Result<Packet>;
+}
+
+/// Options applying to the upstreams reached over TLS or HTTPS.
+#[derive(Clone, Default)]
+pub struct UpstreamOptions {
+    /// PEM file holding the CA certificates to trust instead of the built-in web PKI roots
+    pub ca_file: Option<String>,
+    /// Base64 SHA-256 hashes of the SubjectPublicKeyInfo the server certificate must match
+    pub spki_pins: Vec<String>,
+    /// How queries are sent to DNS over HTTPS servers
+    pub doh_method: DohMethod,
+}
+
+/// Builds an upstream out of its specification:
+/// - `udp://<ip>[:<port>]` for plain DNS, on port 53 by default
+/// - `tls://<ip>[:<port>]#<name>` for DNS over TLS, on port 853 by default, the certificate of
+///   the server being checked against `<name>`
+/// - `https://<host>[:<port>][/<path>][#<ip>]` for DNS over HTTPS, on port 443 by default, the
+///   server being reached at `<ip>` if given rather than by resolving `<host>`
+///
+/// IPv6 addresses with a port must be written between brackets, e.g. `[2606:4700::1111]:853`.
+pub fn from_spec(spec: &str, options: &UpstreamOptions) -> Result<Box<dyn Upstream>> {
+    let invalid = || Error::InvalidUpstream(spec.to_owned());
+    let (scheme, rest) = spec.split_once("://").ok_or_else(invalid)?;
+
+    match scheme {
+        "udp" => Ok(Box::new(UdpUpstream::new(
+            parse_addr(rest, 53).ok_or_else(invalid)?,
+        ))),
+        "tls" => {
+            let (addr, name) = rest.split_once('#').ok_or_else(invalid)?;
+            let config = TlsUpstreamConfig {
+                addr: parse_addr(addr, 853).ok_or_else(invalid)?,
+                server_name: name.to_owned(),
+                ca_file: options.ca_file.clone(),
+                spki_pins: options.spki_pins.clone(),
+            };
+            Ok(Box::new(TlsUpstream::new(config)?))
+        }
+        "https" => {
+            let (url, addr) = match spec.split_once('#') {
+                Some((url, addr)) => (url, Some(addr.parse().map_err(|_| invalid())?)),
+                None => (spec, None),
+            };
+            let config = HttpsUpstreamConfig {
+                url: url.to_owned(),
+                addr,
+                method: options.doh_method,
+                ca_file: options.ca_file.clone(),
+                spki_pins: options.spki_pins.clone(),
+            };
+            Ok(Box::new(HttpsUpstream::new(config)?))
+        }
+        _ => Err(invalid()),
+    }
+}
+
+/// Parses `<ip>[:<port>]`, using `default_port` when there is none.
+pub fn parse_addr(addr: &str, default_port: u16) -> Option<SocketAddr> {
+    addr.parse::<SocketAddr>().ok().or_else(|| {
+        addr.trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<IpAddr>()
+            .ok()
+            .map(|ip| SocketAddr::new(ip, default_port))
+    })
+}
@@ -0,0 +1,514 @@
+use std::collections::HashMap;
+use std::fmt::{self, Formatter};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use ring::digest::{digest, SHA256};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot, Notify};
+use tokio_rustls::TlsConnector;
+
+use crate::globals::UPSTREAM_TIMEOUT;
+use crate::packet::{Packet, PacketBuffer};
+use crate::result::{Error, Result};
+use crate::runtime::runtime;
+use crate::upstream::Upstream;
+
+pub struct TlsUpstreamConfig {
+    pub addr: SocketAddr,
+    /// Name the certificate of the server is checked against, also sent as SNI
+    pub server_name: String,
+    /// PEM file holding the CA certificates to trust instead of the built-in web PKI roots
+    pub ca_file: Option<String>,
+    /// Base64 SHA-256 hashes of the SubjectPublicKeyInfo the server certificate must match, as
+    /// in the out-of-band key-pinned profile of
+    /// [RFC7858#4.2](https://www.rfc-editor.org/rfc/rfc7858#section-4.2)
+    pub spki_pins: Vec<String>,
+}
+
+/// A DNS over TLS server, as specified by [RFC7858](https://www.rfc-editor.org/rfc/rfc7858).
+///
+/// A single connection is kept open and reused by every query. Queries are pipelined on it: each
+/// of them is written as soon as it's issued and responses are matched back by their ID, in
+/// whatever order they arrive.
+pub struct TlsUpstream {
+    addr: SocketAddr,
+    server_name: ServerName<'static>,
+    connector: TlsConnector,
+    /// The connection in use, replaced when it gets closed
+    connection: tokio::sync::Mutex<Option<Arc<Connection>>>,
+}
+
+impl TlsUpstream {
+    pub fn new(config: TlsUpstreamConfig) -> Result<Self> {
+        let server_name = ServerName::try_from(config.server_name.clone())
+            .map_err(|_| Error::InvalidUpstream(config.server_name.clone()))?;
+
+        let tls_config = client_config(config.ca_file.as_deref(), &config.spki_pins, Vec::new())?;
+
+        Ok(Self {
+            addr: config.addr,
+            server_name,
+            connector: TlsConnector::from(Arc::new(tls_config)),
+            connection: tokio::sync::Mutex::new(None),
+        })
+    }
+
+    /// Returns the open connection, or opens a new one if there is none.
+    async fn connection(&self) -> Result<Arc<Connection>> {
+        let mut connection = self.connection.lock().await;
+
+        if let Some(c) = connection.as_ref().filter(|c| !c.is_closed()) {
+            return Ok(Arc::clone(c));
+        }
+
+        let stream = TcpStream::connect(self.addr)
+            .await
+            .map_err(|e| Error::UpstreamConnectFailed(format!("{}: {e}", self.addr)))?;
+        stream
+            .set_nodelay(true)
+            .map_err(|e| Error::UpstreamConnectFailed(format!("{}: {e}", self.addr)))?;
+        let stream = self
+            .connector
+            .connect(self.server_name.clone(), stream)
+            .await
+            .map_err(|e| Error::TlsFailed(format!("{}: {e}", self.addr)))?;
+
+        let c = Arc::new(Connection::spawn(stream));
+        *connection = Some(Arc::clone(&c));
+
+        Ok(c)
+    }
+
+    async fn exchange(&self, query: &[u8]) -> Result<Vec<u8>> {
+        // The server may have closed a connection we still believe to be open, in which case the
+        // query is retried once on a new connection.
+        let mut retried = false;
+
+        loop {
+            let connection = self.connection().await?;
+            match connection.send(query).await {
+                Ok(response) => return Ok(response),
+                Err(e) if retried => return Err(e),
+                Err(_) => retried = true,
+            }
+        }
+    }
+}
+
+impl Upstream for TlsUpstream {
+    fn query(&self, query: &Packet) -> Result<Packet> {
+        let bytes = query.to_bytes()?;
+
+        let response = runtime().block_on(async {
+            tokio::time::timeout(UPSTREAM_TIMEOUT, self.exchange(&bytes))
+                .await
+                .map_err(|_| Error::UpstreamTimeout(self.addr))?
+        })?;
+
+        let mut packet = Packet::try_from(PacketBuffer::try_from(response.as_slice())?)?;
+        packet.header.id = query.header.id;
+
+        Ok(packet)
+    }
+}
+
+impl fmt::Display for TlsUpstream {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "tls://{}#{}", self.addr, self.server_name.to_str())
+    }
+}
+
+/// A TLS connection to the upstream server. A task writes the queries it is handed, while another
+/// reads the responses and hands each of them to the query with the same ID.
+struct Connection {
+    /// Queries to write, already framed
+    writer: mpsc::UnboundedSender<Vec<u8>>,
+    /// Queries waiting for their response, by ID
+    pending: Arc<Mutex<HashMap<u16, oneshot::Sender<Vec<u8>>>>>,
+    /// Set once the connection can't be used anymore
+    closed: Arc<AtomicBool>,
+    /// Stops the reader, for the connection to be closed right away
+    shutdown: Arc<Notify>,
+}
+
+impl Connection {
+    fn spawn<S>(stream: S) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (mut reader, mut writer) = tokio::io::split(stream);
+        let (sender, mut receiver) = mpsc::unbounded_channel::<Vec<u8>>();
+        let pending: Arc<Mutex<HashMap<u16, oneshot::Sender<Vec<u8>>>>> = Default::default();
+        let closed = Arc::new(AtomicBool::new(false));
+        let shutdown = Arc::new(Notify::new());
+
+        let writer_closed = Arc::clone(&closed);
+        runtime().spawn(async move {
+            while let Some(frame) = receiver.recv().await {
+                if writer.write_all(&frame).await.is_err() || writer.flush().await.is_err() {
+                    break;
+                }
+            }
+            writer_closed.store(true, Ordering::Relaxed);
+        });
+
+        let reader_pending = Arc::clone(&pending);
+        let reader_closed = Arc::clone(&closed);
+        let reader_shutdown = Arc::clone(&shutdown);
+        runtime().spawn(async move {
+            let read = async {
+                // Every message is prefixed with its length on 2 bytes
+                while let Ok(len) = reader.read_u16().await {
+                    let len = len as usize;
+                    let mut message = vec![0; len];
+                    if len < 2 || reader.read_exact(&mut message).await.is_err() {
+                        break;
+                    }
+
+                    let id = u16::from_be_bytes([message[0], message[1]]);
+                    if let Some(waiter) = reader_pending.lock().unwrap().remove(&id) {
+                        let _ = waiter.send(message);
+                    }
+                }
+            };
+            tokio::select! {
+                _ = read => {}
+                _ = reader_shutdown.notified() => {}
+            }
+
+            // Dropping the waiters lets the queries still in flight fail right away
+            reader_closed.store(true, Ordering::Relaxed);
+            reader_pending.lock().unwrap().clear();
+        });
+
+        Self {
+            writer: sender,
+            pending,
+            closed,
+            shutdown,
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
+    }
+
+    /// Stops using the connection, failing the queries still in flight. The socket is closed
+    /// once the writer is dropped along with the connection.
+    fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        self.shutdown.notify_one();
+    }
+
+    /// Writes `query` on the connection under an ID that isn't in flight already, and waits for
+    /// its response. Should the wait be given up on, the connection is closed, see `InFlight`.
+    async fn send(&self, query: &[u8]) -> Result<Vec<u8>> {
+        let (sender, receiver) = oneshot::channel();
+
+        let id = {
+            let mut pending = self.pending.lock().unwrap();
+            let mut id: u16 = rand::random();
+            while pending.contains_key(&id) {
+                id = rand::random();
+            }
+            pending.insert(id, sender);
+            id
+        };
+        let mut in_flight = InFlight {
+            connection: self,
+            id,
+            answered: false,
+        };
+
+        let mut frame = Vec::with_capacity(query.len() + 2);
+        frame.extend_from_slice(&(query.len() as u16).to_be_bytes());
+        frame.extend_from_slice(&id.to_be_bytes());
+        frame.extend_from_slice(&query[2..]);
+
+        if self.writer.send(frame).is_err() {
+            return Err(Error::UpstreamClosed);
+        }
+
+        let response = receiver.await.map_err(|_| Error::UpstreamClosed)?;
+        in_flight.answered = true;
+        Ok(response)
+    }
+}
+
+/// A query written on a connection, withdrawn from the pending ones once dropped. A query dropped
+/// before its response arrived timed out: the server stopped answering without closing the
+/// connection, which is closed for the next queries to be sent on a new one.
+struct InFlight<'a> {
+    connection: &'a Connection,
+    id: u16,
+    answered: bool,
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.connection.pending.lock().unwrap().remove(&self.id);
+        if !self.answered {
+            self.connection.close();
+        }
+    }
+}
+
+/// Builds the TLS configuration used to connect to upstream servers, trusting the CAs of
+/// `ca_file` (or the web PKI roots) and enforcing `spki_pins` if there are any. `alpn` lists the
+/// application protocols to negotiate.
+pub(super) fn client_config(
+    ca_file: Option<&str>,
+    spki_pins: &[String],
+    alpn: Vec<Vec<u8>>,
+) -> Result<ClientConfig> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let verifier = PinnedVerifier::new(ca_file, spki_pins, provider.clone())?;
+
+    let mut config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|e| Error::TlsFailed(e.to_string()))?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+    config.alpn_protocols = alpn;
+
+    Ok(config)
+}
+
+/// Checks the certificate of the server against the trusted CAs, then against the SPKI pins if
+/// there are any.
+#[derive(Debug)]
+struct PinnedVerifier {
+    inner: Arc<WebPkiServerVerifier>,
+    /// SHA-256 hashes of the accepted SubjectPublicKeyInfo
+    pins: Vec<Vec<u8>>,
+}
+
+impl PinnedVerifier {
+    fn new(
+        ca_file: Option<&str>,
+        spki_pins: &[String],
+        provider: Arc<CryptoProvider>,
+    ) -> Result<Self> {
+        let mut roots = RootCertStore::empty();
+        match ca_file {
+            Some(path) => {
+                let certs = CertificateDer::pem_file_iter(path)
+                    .map_err(|e| Error::TlsFailed(format!("{path}: {e}")))?;
+                for cert in certs {
+                    let cert = cert.map_err(|e| Error::TlsFailed(format!("{path}: {e}")))?;
+                    roots
+                        .add(cert)
+                        .map_err(|e| Error::TlsFailed(format!("{path}: {e}")))?;
+                }
+            }
+            None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+        }
+
+        let inner = WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider)
+            .build()
+            .map_err(|e| Error::TlsFailed(e.to_string()))?;
+
+        let pins = spki_pins
+            .iter()
+            .map(|pin| {
+                BASE64
+                    .decode(pin)
+                    .ok()
+                    .filter(|hash| hash.len() == SHA256.output_len())
+                    .ok_or_else(|| Error::TlsFailed(format!("invalid SPKI pin {pin}")))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self { inner, pins })
+    }
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        let verified = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        )?;
+
+        if self.pins.is_empty() {
+            return Ok(verified);
+        }
+
+        let cert = webpki::EndEntityCert::try_from(end_entity)
+            .map_err(|_| rustls::Error::General("invalid server certificate".to_owned()))?;
+        let hash = digest(&SHA256, cert.subject_public_key_info().as_ref());
+        if self.pins.iter().any(|pin| pin.as_slice() == hash.as_ref()) {
+            Ok(verified)
+        } else {
+            Err(rustls::Error::General(
+                "server certificate matches no SPKI pin".to_owned(),
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use tokio::net::TcpListener;
+    use tokio_rustls::TlsAcceptor;
+
+    use crate::testing::{data_file, echo, query, server_config, server_pin, SERVER_NAME};
+
+    use super::*;
+
+    /// Starts a DNS over TLS server answering the first `answered` queries of each connection.
+    /// The next ones are ignored, the connection being left open unless `close` is set. Returns
+    /// its address, and the number of connections it accepted.
+    fn serve(answered: usize, close: bool) -> (SocketAddr, Arc<AtomicUsize>) {
+        let listener = runtime()
+            .block_on(TcpListener::bind("127.0.0.1:0"))
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(server_config(&[])));
+        let connections = Arc::new(AtomicUsize::new(0));
+
+        let accepted = Arc::clone(&connections);
+        runtime().spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                accepted.fetch_add(1, Ordering::Relaxed);
+                let acceptor = acceptor.clone();
+                runtime().spawn(async move {
+                    let Ok(mut stream) = acceptor.accept(stream).await else {
+                        return;
+                    };
+                    let mut count = 0;
+                    while let Ok(len) = stream.read_u16().await {
+                        let mut message = vec![0; len as usize];
+                        if stream.read_exact(&mut message).await.is_err() {
+                            return;
+                        }
+                        count += 1;
+                        if count > answered {
+                            if close {
+                                return;
+                            }
+                            continue;
+                        }
+                        let response = echo(&message);
+                        let _ = stream.write_u16(response.len() as u16).await;
+                        let _ = stream.write_all(&response).await;
+                    }
+                });
+            }
+        });
+
+        (addr, connections)
+    }
+
+    fn upstream(addr: SocketAddr, spki_pins: Vec<String>) -> TlsUpstream {
+        TlsUpstream::new(TlsUpstreamConfig {
+            addr,
+            server_name: SERVER_NAME.to_owned(),
+            ca_file: Some(data_file("ca.pem")),
+            spki_pins,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn queries_a_server_trusted_by_its_ca() {
+        let (addr, _) = serve(usize::MAX, false);
+        let upstream = upstream(addr, Vec::new());
+
+        for id in [1, 2] {
+            let response = upstream.query(&query(id, "example.com")).unwrap();
+            assert_eq!(response.header.id, id);
+            assert!(response.header.is_response);
+            assert_eq!(response.questions[0].name, "example.com");
+        }
+    }
+
+    #[test]
+    fn checks_the_pins_of_the_server() {
+        let (addr, _) = serve(usize::MAX, false);
+
+        let pinned = upstream(addr, vec![server_pin()]);
+        assert!(pinned.query(&query(1, "example.com")).is_ok());
+
+        let mispinned = upstream(addr, vec![BASE64.encode([0; 32])]);
+        let result = mispinned.query(&query(1, "example.com"));
+        assert!(matches!(result, Err(Error::TlsFailed(_))));
+    }
+
+    #[test]
+    fn reconnects_once_the_server_stops_answering() {
+        let (addr, connections) = serve(1, false);
+        let upstream = upstream(addr, Vec::new());
+
+        assert!(upstream.query(&query(1, "example.com")).is_ok());
+        let result = upstream.query(&query(2, "example.com"));
+        assert!(matches!(result, Err(Error::UpstreamTimeout(_))));
+
+        // The query that timed out is forgotten, along with the connection
+        let connection = runtime()
+            .block_on(upstream.connection.lock())
+            .clone()
+            .unwrap();
+        assert!(connection.is_closed());
+        assert!(connection.pending.lock().unwrap().is_empty());
+
+        assert!(upstream.query(&query(3, "example.com")).is_ok());
+        assert_eq!(connections.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn reconnects_once_the_server_closes_the_connection() {
+        let (addr, connections) = serve(1, true);
+        let upstream = upstream(addr, Vec::new());
+
+        assert!(upstream.query(&query(1, "example.com")).is_ok());
+        assert!(upstream.query(&query(2, "example.com")).is_ok());
+        assert_eq!(connections.load(Ordering::Relaxed), 2);
+    }
+}
@@ -0,0 +1,106 @@
+use std::fmt::{self, Formatter};
+use std::io::ErrorKind;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::Instant;
+
+use crate::globals::UPSTREAM_TIMEOUT;
+use crate::log;
+use crate::packet::{Packet, PacketBuffer};
+use crate::result::{Error, Result};
+use crate::upstream::Upstream;
+
+/// Sends `query` to `server` over UDP from `local` and waits for the matching response.
+pub fn exchange(query: &Packet, server: SocketAddr, local: (&str, u16)) -> Result<Packet> {
+    // Write that packet to a buffer to send
+    let mut send_buffer = PacketBuffer::new();
+    query.write(&mut send_buffer)?;
+
+    let socket = UdpSocket::bind(local).map_err(|_| Error::UDPBindFailed)?;
+    socket
+        .send_to(&send_buffer.bytes[0..send_buffer.pos()], server)
+        .map_err(|e| {
+            log::warning!("{e}");
+            Error::UDPSendFailed
+        })?;
+
+    // Don't wait forever on a server that doesn't answer, and drop any packet that isn't the
+    // response to our query. Those packets don't buy the server any more time.
+    let deadline = Instant::now() + UPSTREAM_TIMEOUT;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(Error::UpstreamTimeout(server));
+        }
+        socket
+            .set_read_timeout(Some(remaining))
+            .map_err(|_| Error::UDPRecvFailed)?;
+
+        let mut recv_buffer = PacketBuffer::new();
+        let (_, src) = socket
+            .recv_from(&mut recv_buffer.bytes)
+            .map_err(|e| match e.kind() {
+                ErrorKind::WouldBlock | ErrorKind::TimedOut => Error::UpstreamTimeout(server),
+                _ => Error::UDPRecvFailed,
+            })?;
+
+        let recv_packet = Packet::try_from(recv_buffer)?;
+        if src == server && recv_packet.header.id == query.header.id {
+            return Ok(recv_packet);
+        }
+    }
+}
+
+/// A plain DNS server, reached over UDP.
+pub struct UdpUpstream {
+    addr: SocketAddr,
+}
+
+impl UdpUpstream {
+    pub fn new(addr: SocketAddr) -> Self {
+        Self { addr }
+    }
+}
+
+impl Upstream for UdpUpstream {
+    fn query(&self, query: &Packet) -> Result<Packet> {
+        let local = if self.addr.is_ipv4() { "0.0.0.0" } else { "::" };
+        exchange(query, self.addr, (local, 0))
+    }
+}
+
+impl fmt::Display for UdpUpstream {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "udp://{}", self.addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn times_out_despite_stray_packets() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        // Answers the query over and over, always with the wrong ID
+        thread::spawn(move || {
+            let mut buffer = [0; 512];
+            let (len, client) = server.recv_from(&mut buffer).unwrap();
+            buffer[0] ^= 0xff;
+            while server.send_to(&buffer[..len], client).is_ok() {
+                thread::sleep(Duration::from_millis(100));
+            }
+        });
+
+        let mut query: Packet = Default::default();
+        query.header.id = 4242;
+        let started = Instant::now();
+        let result = exchange(&query, addr, ("127.0.0.1", 0));
+
+        assert!(matches!(result, Err(Error::UpstreamTimeout(_))));
+        assert!(started.elapsed() < UPSTREAM_TIMEOUT + Duration::from_millis(500));
+    }
+}